@@ -0,0 +1,474 @@
+//! ターミナルからアプリと同じ設定・履歴でAIに質問するCLI
+//!
+//! talkwithrust-cli ask --provider claude --tier high "質問"
+//! git diff | talkwithrust-cli ask --json "この差分をレビューして"
+//! talkwithrust-cli repl --provider gemini --persona 2
+//! talkwithrust-cli mock --port 9000 --rate-limit-every 3
+
+use base64::Engine;
+use std::io::{BufRead, Read, Write};
+use std::path::PathBuf;
+
+use talkwithrustv2_lib::manage::{
+    message::Shelf,
+    persona::{self, Persona},
+    provider::{self, Provider, Target},
+    rag,
+    transport::{self, MockConfig},
+    utils, variables,
+};
+
+const USAGE: &str = "usage:
+  talkwithrust-cli ask [options] [PROMPT...]
+  talkwithrust-cli repl [options]
+  talkwithrust-cli mock [mock options]
+
+options:
+  -p, --provider <claude|chatgpt|gemini>  送信先 (default: claude)
+  -t, --tier <high|low>                   モデルの種類 (default: low)
+  -m, --model <MODEL>                     モデル名を直接指定
+  -s, --persona <0-4|NAME>                ペルソナ (番号は組み込み、名前は personas フォルダ)
+  -f, --file <PATH>                       添付ファイル (複数可、画像は1つまで)
+  -r, --rag <NAME>                        rag_index_folder で作った索引を使う
+      --stdin                             標準入力を読み込んで PROMPT の後に足す
+      --json                              回答をJSONで出力
+      --memo                              終了時に履歴をメモする (repl は常にメモ)
+
+PROMPT が - の場合か --stdin を付けた場合だけ標準入力を読み込みます。
+
+mock options: (HTTP_MODE=mock HTTP_MOCK_URL=http://127.0.0.1:<port> で接続)
+      --port <PORT>                       待ち受けポート (default: 9000)
+      --cassette <PATH>                   記録済みの応答を返すカセット
+      --delay-ms <MS>                     応答を遅らせる
+      --fail-status <STATUS>              全リクエストをこのステータスで失敗させる
+      --rate-limit-every <N>              N 回に1回 429 を返す
+
+repl commands:
+  /persona <0-4|NAME>  /provider <name>  /tier <high|low>  /model <MODEL>
+  /reset  /memo  /help  /quit";
+
+#[derive(Debug, Clone, PartialEq)]
+enum Command {
+    Ask,
+    Repl,
+    Mock,
+    Help,
+}
+
+#[derive(Debug, Clone)]
+struct Options {
+    command: Command,
+    target: Target,
+    persona: String,
+    files: Vec<PathBuf>,
+    rag: Option<String>,
+    json: bool,
+    memo: bool,
+    stdin: bool,
+    prompt: String,
+    port: u16,
+    mock: MockConfig,
+}
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let command = match args.first().map(|s| s.as_str()) {
+        Some("ask") => Command::Ask,
+        Some("repl") => Command::Repl,
+        Some("mock") => Command::Mock,
+        Some("help") | Some("-h") | Some("--help") | None => Command::Help,
+        Some(other) => return Err(format!("unknown command: {}", other)),
+    };
+
+    let mut options = Options {
+        command,
+        target: Target {
+            provider: Provider::Claude,
+            b: 0,
+            model: None,
+        },
+        persona: "0".to_string(),
+        files: Vec::new(),
+        rag: None,
+        json: false,
+        memo: false,
+        stdin: false,
+        prompt: String::new(),
+        port: 9000,
+        mock: MockConfig::default(),
+    };
+
+    let mut words = Vec::new();
+    let mut iter = args.iter().skip(1);
+    while let Some(arg) = iter.next() {
+        let mut value = |name: &str| {
+            iter.next()
+                .cloned()
+                .ok_or(format!("missing value for {}", name))
+        };
+        match arg.as_str() {
+            "-p" | "--provider" => options.target.provider = parse_provider(&value(arg)?)?,
+            "-t" | "--tier" => options.target.b = parse_tier(&value(arg)?)?,
+            "-m" | "--model" => options.target.model = Some(value(arg)?),
+            "-s" | "--persona" => options.persona = parse_persona(&value(arg)?)?,
+            "-f" | "--file" => options.files.push(PathBuf::from(value(arg)?)),
+            "-r" | "--rag" => options.rag = Some(value(arg)?),
+            "--json" => options.json = true,
+            "--memo" => options.memo = true,
+            "--stdin" => options.stdin = true,
+            "--port" => options.port = parse_number(arg, &value(arg)?)?,
+            "--cassette" => options.mock.cassette = Some(PathBuf::from(value(arg)?)),
+            "--delay-ms" => options.mock.delay_ms = parse_number(arg, &value(arg)?)?,
+            "--fail-status" => options.mock.fail_status = Some(parse_number(arg, &value(arg)?)?),
+            "--rate-limit-every" => {
+                options.mock.rate_limit_every = Some(parse_number(arg, &value(arg)?)?)
+            }
+            "-h" | "--help" => options.command = Command::Help,
+            "--" => {
+                words.extend(iter.by_ref().cloned());
+            }
+            flag if flag.starts_with('-') && flag != "-" => {
+                return Err(format!("unknown option: {}", flag))
+            }
+            word => words.push(word.to_string()),
+        }
+    }
+    options.prompt = words.join(" ");
+
+    Ok(options)
+}
+
+fn parse_provider(name: &str) -> Result<Provider, String> {
+    Provider::from_name(name).ok_or(format!("unknown provider: {}", name))
+}
+
+fn parse_number<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value for {}: {}", name, value))
+}
+
+fn parse_tier(tier: &str) -> Result<u8, String> {
+    match tier {
+        "high" | "1" => Ok(1),
+        "low" | "0" => Ok(0),
+        _ => Err(format!("unknown tier: {}", tier)),
+    }
+}
+
+/// 番号は 0〜4 のみ。名前は set_persona で探す
+fn parse_persona(persona: &str) -> Result<String, String> {
+    match persona.parse::<u8>() {
+        Ok(n) if n <= 4 => Ok(persona.to_string()),
+        Ok(_) => Err(format!("unknown persona: {}", persona)),
+        Err(_) => Ok(persona.to_string()),
+    }
+}
+
+/// 0 (none) は送らない
+fn set_persona(shelf: &mut Shelf, persona: &str) -> Result<Option<Persona>, String> {
+    let persona = match persona.parse::<usize>() {
+        Ok(0) => None,
+        Ok(n) => persona::builtins().into_iter().nth(n - 1),
+        Err(_) => Some(persona::Library::open()?.get(persona)?),
+    };
+    shelf.set_persona(persona.clone());
+    Ok(persona)
+}
+
+/// 添付ファイルを (本文に足すテキスト, 画像の data URL) に分ける
+fn attachments(files: &[PathBuf]) -> Result<(String, Option<String>), String> {
+    let mut texts = Vec::new();
+    let mut image = None;
+    for file in files {
+        if !file.is_file() {
+            return Err(format!("file not found: {}", file.display()));
+        }
+        match utils::get_file_type_by_extension(&file.to_string_lossy()) {
+            Some(mime) if mime.starts_with("image/") => {
+                if image.is_some() {
+                    return Err("only one image can be attached".to_string());
+                }
+                let bytes =
+                    std::fs::read(file).map_err(|e| format!("failed to read file: {}", e))?;
+                let data = base64::engine::general_purpose::STANDARD.encode(bytes);
+                image = Some(format!("data:{};base64,{}", mime, data));
+            }
+            _ => texts.push(file.clone()),
+        }
+    }
+
+    let text = if texts.is_empty() {
+        String::new()
+    } else {
+        talkwithrustv2_lib::files_to_string(texts)
+    };
+    Ok((text, image))
+}
+
+/// 履歴に質問を足して送信し、回答を履歴に残す
+async fn ask(
+    shelf: &mut Shelf,
+    target: &Target,
+    msg: String,
+    src: Option<String>,
+) -> Result<provider::Reply, String> {
+    // GUI と同じくシステムプロンプトの {user} や {date} などの変数を展開する
+    let (provider, model, max_tokens) = target.resolve();
+    let system_prompt = variables::render_system(&shelf.system_prompt(), provider, &model)?;
+    shelf.add_to_messages("user".to_string(), msg, src);
+
+    let mut messages = shelf.context_messages();
+    // GUI と同じく索引があれば資料を添える
+    if let Some(name) = &shelf.rag {
+        if let Err(e) = rag::augment(name, &mut messages).await {
+            shelf.drop_unanswered();
+            return Err(e);
+        }
+    }
+
+    let config = shelf.generation_config(None);
+    match provider::ask(
+        provider,
+        &model,
+        max_tokens,
+        &messages,
+        &system_prompt,
+        &config,
+    )
+    .await
+    {
+        Ok(reply) => {
+            shelf.add_answer(reply.text.clone(), reply.meta());
+            Ok(reply)
+        }
+        Err(e) => {
+            shelf.add_to_messages("assistant".to_string(), format!("Error: {}", e), None);
+            Err(e)
+        }
+    }
+}
+
+fn print_reply(reply: &provider::Reply, json: bool) -> Result<(), String> {
+    if json {
+        let text =
+            serde_json::to_string(reply).map_err(|e| format!("failed to serialize: {}", e))?;
+        println!("{}", text);
+    } else {
+        println!("{}", reply.text);
+        for warning in &reply.warnings {
+            eprintln!("warning: {}", warning);
+        }
+    }
+    Ok(())
+}
+
+async fn run_ask(options: Options) -> Result<(), String> {
+    let mut prompt = options.prompt.clone();
+    // 端末でなくても勝手には読まない (CI やエディタから呼ばれたとき固まらないように)
+    if prompt == "-" || options.stdin {
+        let mut input = String::new();
+        std::io::stdin()
+            .lock()
+            .read_to_string(&mut input)
+            .map_err(|e| format!("failed to read stdin: {}", e))?;
+        prompt = match prompt.as_str() {
+            "-" => input,
+            _ => format!("{}\n\n{}", prompt, input),
+        };
+    }
+
+    let (text, src) = attachments(&options.files)?;
+    if !text.is_empty() {
+        prompt = format!("{}\n\n{}", prompt, text);
+    }
+    if prompt.trim().is_empty() {
+        return Err("empty prompt".to_string());
+    }
+
+    let mut shelf = Shelf::new();
+    shelf.rag = options.rag.clone();
+    set_persona(&mut shelf, &options.persona)?;
+
+    let result = ask(&mut shelf, &options.target, prompt, src).await;
+    if options.memo {
+        shelf.memo()?;
+    }
+    print_reply(&result?, options.json)
+}
+
+async fn run_repl(options: Options) -> Result<(), String> {
+    let mut shelf = Shelf::new();
+    let mut target = options.target.clone();
+    shelf.rag = options.rag.clone();
+    set_persona(&mut shelf, &options.persona)?;
+
+    // 最初の質問にだけ添付する
+    let (mut pending_text, mut pending_src) = attachments(&options.files)?;
+
+    eprintln!("{} (/help でコマンド一覧)", target.label());
+    let stdin = std::io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        eprint!("> ");
+        let _ = std::io::stderr().flush();
+        let Some(line) = lines.next() else {
+            break;
+        };
+        let line = line.map_err(|e| format!("failed to read stdin: {}", e))?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(command) = line.strip_prefix('/') {
+            let (name, arg) = command.split_once(' ').unwrap_or((command, ""));
+            let arg = arg.trim();
+            let result = match name {
+                "quit" | "exit" => break,
+                "help" => Ok(USAGE.to_string()),
+                // ペルソナに既定のAIやモデルの種類があれば切り替える
+                "persona" => parse_persona(arg)
+                    .and_then(|name| set_persona(&mut shelf, &name))
+                    .map(|persona| match persona {
+                        Some(persona) => {
+                            if let Some(provider) = persona.provider {
+                                target.provider = provider;
+                                target.model = None;
+                            }
+                            if let Some(tier) = persona.tier {
+                                target.b = tier.b();
+                            }
+                            format!("persona: {} ({})", persona.name, target.label())
+                        }
+                        None => "persona: none".to_string(),
+                    }),
+                "provider" => parse_provider(arg).map(|p| {
+                    target.provider = p;
+                    target.model = None;
+                    target.label()
+                }),
+                "tier" => parse_tier(arg).map(|b| {
+                    target.b = b;
+                    target.label()
+                }),
+                "model" => {
+                    target.model = Some(arg.to_string()).filter(|m| !m.is_empty());
+                    Ok(target.label())
+                }
+                "reset" => shelf.reset().map(|_| "reset".to_string()),
+                "memo" => shelf.memo().map(|_| "memo is success".to_string()),
+                _ => Err(format!("unknown command: /{}", name)),
+            };
+            match result {
+                Ok(text) => eprintln!("{}", text),
+                Err(e) => eprintln!("error: {}", e),
+            }
+            continue;
+        }
+
+        let mut msg = line.to_string();
+        if !pending_text.is_empty() {
+            msg = format!("{}\n\n{}", msg, std::mem::take(&mut pending_text));
+        }
+        match ask(&mut shelf, &target, msg, pending_src.take()).await {
+            Ok(reply) => print_reply(&reply, options.json)?,
+            Err(e) => eprintln!("error: {}", e),
+        }
+    }
+
+    // アプリのウィンドウを閉じたときと同じく履歴をメモする
+    if !shelf.get_messages().is_empty() {
+        shelf.memo()?;
+    }
+    Ok(())
+}
+
+/// 各APIを模したサーバーを Ctrl+C まで動かす
+async fn run_mock(options: Options) -> Result<(), String> {
+    let (port, shutdown) = transport::mock_server(options.port, options.mock).await?;
+    eprintln!("mock server: http://127.0.0.1:{}", port);
+    eprintln!("HTTP_MODE=mock HTTP_MOCK_URL=http://127.0.0.1:{}", port);
+
+    tokio::signal::ctrl_c()
+        .await
+        .map_err(|e| format!("failed to wait for ctrl-c: {}", e))?;
+    let _ = shutdown.send(());
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    dotenv::dotenv().ok();
+    env_logger::init();
+
+    let args = std::env::args().skip(1).collect::<Vec<String>>();
+    let result = match parse_args(&args) {
+        Ok(options) => match options.command {
+            Command::Ask => run_ask(options).await,
+            Command::Repl => run_repl(options).await,
+            Command::Mock => run_mock(options).await,
+            Command::Help => {
+                println!("{}", USAGE);
+                Ok(())
+            }
+        },
+        Err(e) => Err(format!("{}\n\n{}", e, USAGE)),
+    };
+
+    if let Err(e) = result {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_args_ask() {
+        let options = parse_args(&args(
+            "ask --provider openai -t high --json -f a.rs -f b.png what is this",
+        ))
+        .unwrap();
+        assert_eq!(options.command, Command::Ask);
+        assert_eq!(options.target.provider, Provider::ChatGPT);
+        assert_eq!(options.target.b, 1);
+        assert!(options.json);
+        assert_eq!(options.files.len(), 2);
+        assert_eq!(options.prompt, "what is this");
+    }
+
+    #[test]
+    fn test_parse_args_stdin() {
+        assert!(!parse_args(&args("ask hello")).unwrap().stdin);
+        assert!(parse_args(&args("ask --stdin summarize")).unwrap().stdin);
+        assert_eq!(parse_args(&args("ask -")).unwrap().prompt, "-");
+    }
+
+    #[test]
+    fn test_parse_args_mock() {
+        let options = parse_args(&args(
+            "mock --port 0 --rate-limit-every 3 --fail-status 500",
+        ))
+        .unwrap();
+        assert_eq!(options.command, Command::Mock);
+        assert_eq!(options.port, 0);
+        assert_eq!(options.mock.rate_limit_every, Some(3));
+        assert_eq!(options.mock.fail_status, Some(500));
+    }
+
+    #[test]
+    fn test_parse_args_errors() {
+        assert!(parse_args(&args("ask --provider mistral")).is_err());
+        assert!(parse_args(&args("ask --tier")).is_err());
+        assert!(parse_args(&args("ask --persona 9")).is_err());
+        assert!(parse_args(&args("ask --verbose")).is_err());
+        assert!(parse_args(&args("mock --port abc")).is_err());
+        assert_eq!(parse_args(&[]).unwrap().command, Command::Help);
+    }
+}
@@ -8,8 +8,8 @@ use tauri::State;
 
 use crate::manage::utils::convert_markdown_to_html;
 
-mod manage;
-mod sub;
+pub mod manage;
+pub mod sub;
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
@@ -29,10 +29,67 @@ pub fn run() {
             manage::claude::claude_request,
             manage::chatgpt::chatgpt_request,
             manage::chatgpt::chatgpt_request_to_dell3,
+            manage::chatgpt::chatgpt_request_to_image,
+            manage::chatgpt::chatgpt_request_image_edit,
+            manage::chatgpt::chatgpt_request_image_variation,
+            manage::gallery::list_images,
+            manage::gallery::search_images,
+            manage::gallery::open_image,
             manage::gemini::gemini_request,
+            manage::gemini::gemini_request_to_image,
+            manage::compare::compare_request,
+            manage::compare::choose_answer,
+            manage::debate::debate_request,
+            manage::rag::rag_index_folder,
+            manage::rag::rag_list,
+            manage::rag::rag_delete,
+            manage::rag::rag_use,
+            manage::rag::rag_search,
+            manage::search::search_memos,
+            manage::search::open_memo,
+            manage::gateway::gateway_start,
+            manage::gateway::gateway_stop,
+            manage::gateway::gateway_status,
+            manage::persona::list_personas,
+            manage::persona::create_persona,
+            manage::persona::update_persona,
+            manage::persona::duplicate_persona,
+            manage::persona::delete_persona,
+            manage::persona::use_persona,
+            manage::generation::get_generation_defaults,
+            manage::generation::set_generation_defaults,
+            manage::generation::set_session_generation,
+            manage::layers::list_system_layers,
+            manage::layers::preview_system_prompt,
+            manage::layers::toggle_system_layer,
+            manage::layers::set_base_prompt,
+            manage::layers::add_context_file,
+            manage::layers::remove_context_file,
+            manage::layers::set_session_notes,
+            manage::secrets::set_api_key,
+            manage::secrets::rotate_api_key,
+            manage::secrets::test_api_key,
+            manage::secrets::delete_api_key,
+            manage::provider::provider_status,
+            manage::models::list_models,
+            manage::settings::get_settings,
+            manage::settings::update_settings,
+            manage::settings::settings_schema,
+            manage::variables::list_variables,
+            manage::variables::set_variable,
+            manage::variables::delete_variable,
+            manage::variables::preview_template,
             memo,
             all_messages,
+            export_messages,
+            delete_message,
+            edit_message,
+            exclude_message,
+            pin_message,
+            star_message,
             files_to_string,
+            highlight_css,
+            highlight_themes,
         ])
         .on_window_event(move |window, event| {
             if let tauri::WindowEvent::Destroyed = event {
@@ -49,10 +106,10 @@ pub fn run() {
 
 #[tauri::command]
 fn is_there_env() -> bool {
-    let chatgpt = std::env::var("CHATGPTTOKEN");
-    let anth = std::env::var("ANTHROPIC_API_KEY");
-    // 上記環境変数一方が存在すればTrue, どちらもなければFalse
-    chatgpt.is_ok() || anth.is_ok()
+    // いずれかのAIのキーがあればTrue
+    manage::provider::Provider::ALL
+        .iter()
+        .any(|provider| provider.configured())
 }
 
 #[tauri::command]
@@ -98,19 +155,24 @@ fn reset(state: State<'_, Arc<Mutex<manage::message::Shelf>>>) -> String {
     }
 }
 
+/// 組み込みのペルソナを番号で選ぶ。0 はシステムプロンプトなし
 #[tauri::command]
 fn request_system(
     num: u8,
     state: State<'_, Arc<Mutex<manage::message::Shelf>>>,
 ) -> Result<String, String> {
-    let prompt: String = sub::prompts::choose(num);
+    let persona = match num {
+        0 => None,
+        n => Some(
+            manage::persona::builtins()
+                .into_iter()
+                .nth(n as usize - 1)
+                .ok_or(format!("unknown persona: {}", n))?,
+        ),
+    };
 
     let mut shelf = state.lock().unwrap();
-
-    shelf.system_messages.reset();
-    shelf
-        .system_messages
-        .add("system".to_string(), prompt, None);
+    shelf.set_persona(persona);
 
     Ok("success".to_string())
 }
@@ -131,11 +193,12 @@ async fn all_messages(
         .iter()
         .map(|message| {
             if message.role == "user" {
-                format!("{}: {}\n\n", message.role, message.content)
+                format!("{}: {}\n\n", message.speaker(), message.content)
             } else {
                 format!(
                     "{}: {}\n--------------------\n\n",
-                    message.role, message.content
+                    message.speaker(),
+                    message.content
                 )
             }
         })
@@ -153,8 +216,78 @@ async fn all_messages(
     }
 }
 
+/// 履歴を JSON で書き出す
+/// ID、日時、回答したモデル、トークン数、印なども含む
 #[tauri::command]
-fn files_to_string(filepaths: Vec<PathBuf>) -> String {
+async fn export_messages(
+    state: State<'_, Arc<Mutex<manage::message::Shelf>>>,
+) -> Result<String, String> {
+    let shelf = state.lock().unwrap();
+    serde_json::to_string_pretty(&shelf.get_messages())
+        .map(|json| manage::secrets::redact(&json))
+        .map_err(|e| format!("failed to export messages: {}", e))
+}
+
+/// 発言を、質問と回答のやりとりごと削除する
+#[tauri::command]
+async fn delete_message(
+    id: &str,
+    state: State<'_, Arc<Mutex<manage::message::Shelf>>>,
+) -> Result<String, String> {
+    let mut shelf = state.lock().unwrap();
+    shelf.delete_message(id)?;
+    Ok("success".to_string())
+}
+
+#[tauri::command]
+async fn edit_message(
+    id: &str,
+    content: &str,
+    state: State<'_, Arc<Mutex<manage::message::Shelf>>>,
+) -> Result<String, String> {
+    let mut shelf = state.lock().unwrap();
+    shelf.edit_message(id, content)?;
+    Ok("success".to_string())
+}
+
+/// AIへ送らない発言にする。ログの貼り付けなど長いやりとりに使う
+#[tauri::command]
+async fn exclude_message(
+    id: &str,
+    excluded: bool,
+    state: State<'_, Arc<Mutex<manage::message::Shelf>>>,
+) -> Result<String, String> {
+    let mut shelf = state.lock().unwrap();
+    shelf.exclude_message(id, excluded)?;
+    Ok("success".to_string())
+}
+
+/// CONTEXT_TOKEN_BUDGET を超えても削らない発言にする
+#[tauri::command]
+async fn pin_message(
+    id: &str,
+    pinned: bool,
+    state: State<'_, Arc<Mutex<manage::message::Shelf>>>,
+) -> Result<String, String> {
+    let mut shelf = state.lock().unwrap();
+    shelf.pin_message(id, pinned)?;
+    Ok("success".to_string())
+}
+
+#[tauri::command]
+async fn star_message(
+    id: &str,
+    starred: bool,
+    state: State<'_, Arc<Mutex<manage::message::Shelf>>>,
+) -> Result<String, String> {
+    let mut shelf = state.lock().unwrap();
+    shelf.star_message(id, starred)?;
+    Ok("success".to_string())
+}
+
+/// ファイルの内容をコードブロックにまとめる
+#[tauri::command]
+pub fn files_to_string(filepaths: Vec<PathBuf>) -> String {
     let mut all_messages_string = String::new();
     for filepath in filepaths {
         if filepath.is_dir() || !filepath.exists() {
@@ -179,3 +312,14 @@ fn files_to_string(filepaths: Vec<PathBuf>) -> String {
 
     all_messages_string
 }
+
+/// コードブロックの色付け用CSS (ライト・ダーク)
+#[tauri::command]
+fn highlight_css() -> Result<String, String> {
+    sub::highlight::css(&Default::default())
+}
+
+#[tauri::command]
+fn highlight_themes() -> Vec<String> {
+    sub::highlight::themes()
+}
@@ -0,0 +1,552 @@
+use serde::Serialize;
+use serde_json::Value;
+use std::result::Result;
+
+use crate::manage::{
+    chatgpt,
+    message::{self, Message},
+    provider::Provider,
+};
+
+/// モデルの性能
+/// 送信前に確かめ、合わないものは外すかエラーにする
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Capabilities {
+    // 画像入力
+    pub vision: bool,
+    // PDF 入力
+    pub pdf: bool,
+    // ツール呼び出し
+    pub tools: bool,
+    // 途中経過を返せるか (stream: true)
+    pub streaming: bool,
+    // JSON だけを返すモード
+    pub json_mode: bool,
+    pub context_window: u32,
+    pub max_output: u32,
+    // システムプロンプトを受け付けるか。無いモデルは最初の質問に添える
+    pub system_prompt: bool,
+    // OpenAI の推論モデル。max_completion_tokens を使い、temperature などは受け付けない
+    pub reasoning: bool,
+    // reasoning_effort を受け付けるか
+    pub reasoning_effort: bool,
+}
+
+const fn caps(context_window: u32, max_output: u32) -> Capabilities {
+    Capabilities {
+        vision: true,
+        pdf: true,
+        tools: true,
+        streaming: true,
+        json_mode: true,
+        context_window,
+        max_output,
+        system_prompt: true,
+        reasoning: false,
+        reasoning_effort: false,
+    }
+}
+
+// o1, o3, o4, gpt-5
+const fn reasoning(context_window: u32, max_output: u32) -> Capabilities {
+    Capabilities {
+        reasoning: true,
+        reasoning_effort: true,
+        ..caps(context_window, max_output)
+    }
+}
+
+// 画像も PDF も読めない
+const fn text_only(context_window: u32, max_output: u32) -> Capabilities {
+    Capabilities {
+        vision: false,
+        pdf: false,
+        ..caps(context_window, max_output)
+    }
+}
+
+// o1-mini, o1-preview は system も developer も reasoning_effort も受け付けない
+const fn early_reasoning(context_window: u32, max_output: u32) -> Capabilities {
+    Capabilities {
+        vision: false,
+        pdf: false,
+        tools: false,
+        streaming: false,
+        json_mode: false,
+        system_prompt: false,
+        reasoning_effort: false,
+        ..reasoning(context_window, max_output)
+    }
+}
+
+// Claude は JSON モードが無い
+const fn claude(max_output: u32) -> Capabilities {
+    Capabilities {
+        json_mode: false,
+        ..caps(200_000, max_output)
+    }
+}
+
+/// 1M トークンあたりの単価(USD) (入力, 出力)
+pub type Price = (f64, f64);
+
+/// モデル名の前方一致ごとの性能と単価。モデルを足すときはここだけ直す
+/// 複数一致した場合は最も長く一致したものを使う
+const REGISTRY: [(&str, Capabilities, Option<Price>); 44] = [
+    (
+        "claude-2",
+        Capabilities {
+            tools: false,
+            json_mode: false,
+            ..text_only(100_000, 4096)
+        },
+        None,
+    ),
+    (
+        "claude-3",
+        Capabilities {
+            pdf: false,
+            ..claude(4096)
+        },
+        None,
+    ),
+    (
+        "claude-3-haiku",
+        Capabilities {
+            pdf: false,
+            ..claude(4096)
+        },
+        Some((0.25, 1.25)),
+    ),
+    (
+        "claude-3-opus",
+        Capabilities {
+            pdf: false,
+            ..claude(4096)
+        },
+        Some((15.0, 75.0)),
+    ),
+    ("claude-3-5", claude(8192), Some((3.0, 15.0))),
+    ("claude-3-5-haiku", claude(8192), Some((0.8, 4.0))),
+    ("claude-3-7", claude(64000), Some((3.0, 15.0))),
+    ("claude-sonnet-4", claude(64000), Some((3.0, 15.0))),
+    ("claude-opus-4", claude(32000), Some((15.0, 75.0))),
+    ("claude-haiku-4", claude(64000), Some((1.0, 5.0))),
+    ("gpt-3.5", text_only(16_385, 4096), None),
+    (
+        "gpt-4",
+        Capabilities {
+            json_mode: false,
+            ..text_only(8_192, 8192)
+        },
+        None,
+    ),
+    ("gpt-4-1106", text_only(128_000, 4096), None),
+    ("gpt-4-0125", text_only(128_000, 4096), None),
+    (
+        "gpt-4-turbo",
+        Capabilities {
+            pdf: false,
+            ..caps(128_000, 4096)
+        },
+        None,
+    ),
+    ("gpt-4o", caps(128_000, 16384), Some((2.5, 10.0))),
+    ("gpt-4o-mini", caps(128_000, 16384), Some((0.15, 0.6))),
+    (
+        "chatgpt-4o",
+        Capabilities {
+            tools: false,
+            ..caps(128_000, 16384)
+        },
+        Some((5.0, 15.0)),
+    ),
+    ("gpt-4.1", caps(1_047_576, 32768), Some((2.0, 8.0))),
+    ("gpt-4.1-mini", caps(1_047_576, 32768), Some((0.4, 1.6))),
+    ("gpt-4.1-nano", caps(1_047_576, 32768), Some((0.1, 0.4))),
+    ("gpt-5", reasoning(400_000, 128000), None),
+    // ChatGPT で使われている推論なしの gpt-5
+    ("gpt-5-chat", caps(128_000, 16384), None),
+    ("o1", reasoning(200_000, 100000), Some((15.0, 60.0))),
+    ("o1-mini", early_reasoning(128_000, 65536), Some((1.1, 4.4))),
+    (
+        "o1-preview",
+        early_reasoning(128_000, 32768),
+        Some((15.0, 60.0)),
+    ),
+    ("o3", reasoning(200_000, 100000), Some((2.0, 8.0))),
+    // o3-pro は途中経過を返さない
+    (
+        "o3-pro",
+        Capabilities {
+            streaming: false,
+            ..reasoning(200_000, 100000)
+        },
+        Some((20.0, 80.0)),
+    ),
+    (
+        "o3-mini",
+        Capabilities {
+            vision: false,
+            pdf: false,
+            ..reasoning(200_000, 100000)
+        },
+        Some((1.1, 4.4)),
+    ),
+    ("o4", reasoning(200_000, 100000), None),
+    ("o4-mini", reasoning(200_000, 100000), Some((1.1, 4.4))),
+    ("gemini-1.5", caps(1_048_576, 8192), None),
+    (
+        "gemini-1.5-flash",
+        caps(1_048_576, 8192),
+        Some((0.075, 0.3)),
+    ),
+    ("gemini-1.5-pro", caps(2_097_152, 8192), Some((1.25, 5.0))),
+    ("gemini-2.0", caps(1_048_576, 8192), None),
+    ("gemini-2.0-flash", caps(1_048_576, 8192), Some((0.1, 0.4))),
+    (
+        "gemini-2.0-flash-lite",
+        caps(1_048_576, 8192),
+        Some((0.075, 0.3)),
+    ),
+    // 画像生成モデルはツールと JSON モードを使えない
+    (
+        "gemini-2.0-flash-preview-image",
+        Capabilities {
+            tools: false,
+            json_mode: false,
+            ..caps(32_768, 8192)
+        },
+        None,
+    ),
+    ("gemini-2.5", caps(1_048_576, 65536), None),
+    ("gemini-2.5-flash", caps(1_048_576, 65536), Some((0.3, 2.5))),
+    (
+        "gemini-2.5-flash-image",
+        Capabilities {
+            tools: false,
+            json_mode: false,
+            ..caps(32_768, 32768)
+        },
+        None,
+    ),
+    ("gemini-2.5-pro", caps(1_048_576, 65536), Some((1.25, 10.0))),
+    ("gemini-3", caps(1_048_576, 65536), None),
+    ("gemini-3-pro-image", caps(65_536, 32768), None),
+];
+
+fn entry(model: &str) -> Option<&'static (&'static str, Capabilities, Option<Price>)> {
+    let model = model.strip_prefix("ft:").unwrap_or(model);
+    REGISTRY
+        .iter()
+        .filter(|(prefix, _, _)| model.starts_with(prefix))
+        .max_by_key(|(prefix, _, _)| prefix.len())
+}
+
+/// モデルの性能。不明なモデル (互換サーバーなど) は None で、確かめずに送る
+pub fn lookup(model: &str) -> Option<Capabilities> {
+    entry(model).map(|(_, caps, _)| *caps)
+}
+
+/// モデルの単価。分からなければ None
+pub fn price(model: &str) -> Option<Price> {
+    entry(model).and_then(|(_, _, price)| *price)
+}
+
+/// stream: true で送れるか。送れないモデルはエラーにする
+pub fn check_stream(model: &str) -> Result<(), String> {
+    match lookup(model) {
+        Some(caps) if !caps.streaming => {
+            Err(format!("{} does not stream: send stream: false", model))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// 添付の種類
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Attachment {
+    Image,
+    Pdf,
+}
+
+impl Attachment {
+    pub fn of(src: &str) -> Self {
+        if src.starts_with("data:application/pdf") {
+            Attachment::Pdf
+        } else {
+            Attachment::Image
+        }
+    }
+
+    fn supported(&self, caps: &Capabilities) -> bool {
+        match self {
+            Attachment::Image => caps.vision,
+            Attachment::Pdf => caps.pdf,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Attachment::Image => "images",
+            Attachment::Pdf => "PDFs",
+        }
+    }
+}
+
+/// 添付を読めないモデルならエラーにする
+/// 設定した高性能/低価格モデルで読めるものがあれば、エラーに添える
+pub fn check_attachment(provider: Provider, model: &str, src: Option<&str>) -> Result<(), String> {
+    let (Some(src), Some(caps)) = (src.filter(|s| !s.is_empty()), lookup(model)) else {
+        return Ok(());
+    };
+    let attachment = Attachment::of(src);
+    if attachment.supported(&caps) {
+        return Ok(());
+    }
+
+    let suggestion = [provider.model(1).0, provider.model(0).0]
+        .into_iter()
+        .find(|m| lookup(m).is_some_and(|c| attachment.supported(&c)))
+        .map(|m| format!(" such as {}", m))
+        .unwrap_or_default();
+    Err(format!(
+        "{} cannot read {}: choose a model that can{} or send without the attachment",
+        model,
+        attachment.name(),
+        suggestion
+    ))
+}
+
+/// 送信する履歴をモデルに合わせる
+/// 読めない過去の添付は外し、コンテキスト長を超える分は古いやりとりから削る
+/// 今回の質問の添付を読めなければエラー
+pub fn adapt_messages(
+    provider: Provider,
+    model: &str,
+    max_tokens: u32,
+    messages: Vec<Message>,
+    system_prompt: &str,
+) -> Result<(Vec<Message>, Vec<String>), String> {
+    let Some(caps) = lookup(model) else {
+        return Ok((messages, Vec::new()));
+    };
+    let mut warnings = Vec::new();
+
+    let last = messages.iter().rposition(|m| m.role == "user");
+    if let Some(last) = last {
+        check_attachment(provider, model, messages[last].src.as_deref())?;
+    }
+    let mut messages = messages;
+    let mut dropped = 0;
+    for message in messages.iter_mut() {
+        if let Some(src) = &message.src {
+            if !Attachment::of(src).supported(&caps) {
+                message.src = None;
+                dropped += 1;
+            }
+        }
+    }
+    if dropped > 0 {
+        warnings.push(format!(
+            "{} earlier attachment(s) were not sent: {} cannot read them",
+            dropped, model
+        ));
+    }
+
+    let len = messages.len();
+    let system_tokens = system_prompt.chars().count() / 2;
+    let budget = (caps.context_window as usize)
+        .saturating_sub(max_tokens.min(caps.max_output) as usize)
+        .saturating_sub(system_tokens);
+    let messages = message::trim_to_budget(messages, Some(budget));
+    if messages.len() < len {
+        warnings.push(format!(
+            "{} older message(s) were not sent to fit the {} context window",
+            len - messages.len(),
+            model
+        ));
+    }
+    Ok((messages, warnings))
+}
+
+/// リクエストボディをモデルに合わせる
+/// 受け付けないパラメータは外し、最大トークン数は上限に収める
+/// ツールのように外すと意味が変わるものはエラー
+/// Gemini はボディに model が無いため、model を別に渡す
+pub fn adapt_body(
+    provider: Provider,
+    model: &str,
+    body: &mut Value,
+) -> Result<Vec<String>, String> {
+    let mut warnings = Vec::new();
+
+    // OpenAI の推論モデルは temperature などを受け付けない
+    if provider == Provider::ChatGPT {
+        for param in chatgpt::family(model).unsupported {
+            if let Some(map) = body.as_object_mut() {
+                if map.remove(*param).is_some() {
+                    warnings.push(format!(
+                        "{} was not sent: {} does not support it",
+                        param, model
+                    ));
+                }
+            }
+        }
+    }
+
+    // Claude の API には JSON モードが無いため、不明なモデルでも外す
+    let caps = lookup(model);
+    if !caps.map_or(provider != Provider::Claude, |caps| caps.json_mode) {
+        let removed = match provider {
+            Provider::Gemini => body
+                .get_mut("generationConfig")
+                .and_then(|config| config.as_object_mut())
+                .and_then(|config| config.remove("responseMimeType")),
+            _ => body
+                .as_object_mut()
+                .and_then(|map| map.remove("response_format")),
+        };
+        if removed.is_some() {
+            warnings.push(format!(
+                "{} has no JSON mode: ask for JSON in the prompt",
+                model
+            ));
+        }
+    }
+
+    let Some(caps) = caps else {
+        return Ok(warnings);
+    };
+
+    if body["stream"] == true {
+        check_stream(model)?;
+    }
+    if body.get("tools").is_some() && !caps.tools {
+        return Err(format!(
+            "{} does not support tools: choose another model or send without tools",
+            model
+        ));
+    }
+
+    let key = match provider {
+        Provider::Claude => "max_tokens",
+        Provider::ChatGPT => chatgpt::family(model).max_tokens,
+        Provider::Gemini => "maxOutputTokens",
+    };
+    let slot = match provider {
+        Provider::Gemini => body
+            .get_mut("generationConfig")
+            .and_then(|config| config.get_mut(key)),
+        _ => body.get_mut(key),
+    };
+    if let Some(slot) = slot {
+        match slot.as_u64() {
+            Some(max_tokens) if max_tokens > caps.max_output as u64 => {
+                *slot = Value::from(caps.max_output);
+                warnings.push(format!(
+                    "max_tokens was lowered from {} to the {} limit of {}",
+                    max_tokens, model, caps.max_output
+                ));
+            }
+            _ => {}
+        }
+    }
+    Ok(warnings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn message(role: &str, content: &str, src: Option<&str>) -> Message {
+        Message {
+            role: role.to_string(),
+            content: content.to_string(),
+            src: src.map(|s| s.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_lookup_uses_longest_prefix() {
+        assert!(!lookup("o1-mini-2024-09-12").unwrap().system_prompt);
+        assert!(lookup("o1-2024-12-17").unwrap().system_prompt);
+        assert_eq!(lookup("ft:gpt-4o-mini:org::abc").unwrap().max_output, 16384);
+        assert!(!lookup("claude-3-haiku-20240307").unwrap().pdf);
+        assert!(lookup("claude-3-5-sonnet-latest").unwrap().pdf);
+        assert!(lookup("llama3").is_none());
+        assert_eq!(
+            lookup("gpt-4-1106-preview").unwrap().context_window,
+            128_000
+        );
+        assert!(lookup("gpt-4-0125-preview").unwrap().json_mode);
+        assert!(!lookup("gpt-4-0613").unwrap().json_mode);
+    }
+
+    #[test]
+    fn test_price_comes_from_the_same_entry() {
+        assert_eq!(price("gpt-4o-mini-2024-07-18"), Some((0.15, 0.6)));
+        assert_eq!(price("claude-3-5-sonnet-latest"), Some((3.0, 15.0)));
+        assert_eq!(price("gemini-2.5-pro"), Some((1.25, 10.0)));
+        assert!(price("gpt-4-1106-preview").is_none());
+        assert!(price("llama3").is_none());
+    }
+
+    #[test]
+    fn test_attachment_is_checked_and_old_ones_dropped() {
+        let image = "data:image/png;base64,AAAA";
+        let err = check_attachment(Provider::ChatGPT, "o3-mini", Some(image)).unwrap_err();
+        assert!(err.starts_with("o3-mini cannot read images"));
+        assert!(check_attachment(Provider::ChatGPT, "gpt-4o", Some(image)).is_ok());
+        assert!(check_attachment(Provider::ChatGPT, "local-model", Some(image)).is_ok());
+        assert!(check_attachment(
+            Provider::Claude,
+            "claude-3-haiku",
+            Some("data:application/pdf;base64,AAAA")
+        )
+        .is_err());
+
+        let messages = vec![
+            message("user", "what is this", Some(image)),
+            message("assistant", "a cat", None),
+            message("user", "and now?", None),
+        ];
+        let (messages, warnings) =
+            adapt_messages(Provider::ChatGPT, "o3-mini", 1000, messages, "").unwrap();
+        assert!(messages[0].src.is_none());
+        assert_eq!(warnings.len(), 1);
+
+        let messages = vec![message("user", "what is this", Some(image))];
+        assert!(adapt_messages(Provider::ChatGPT, "o3-mini", 1000, messages, "").is_err());
+    }
+
+    #[test]
+    fn test_adapt_body_drops_params_and_clamps_max_tokens() {
+        let mut body = json!({"model": "o3", "temperature": 0.2, "max_completion_tokens": 200000});
+        let warnings = adapt_body(Provider::ChatGPT, "o3", &mut body).unwrap();
+        assert!(body.get("temperature").is_none());
+        assert_eq!(body["max_completion_tokens"], 100000);
+        assert_eq!(warnings.len(), 2);
+        assert!(chatgpt::check(&body).is_ok());
+
+        let mut body = json!({"model": "claude-sonnet-4-5", "max_tokens": 1024, "response_format": {"type": "json_object"}});
+        let warnings = adapt_body(Provider::Claude, "claude-sonnet-4-5", &mut body).unwrap();
+        assert!(body.get("response_format").is_none());
+        assert_eq!(warnings.len(), 1);
+
+        let mut body = json!({"generationConfig": {"maxOutputTokens": 100000}});
+        adapt_body(Provider::Gemini, "gemini-2.5-flash", &mut body).unwrap();
+        assert_eq!(body["generationConfig"]["maxOutputTokens"], 65536);
+
+        let mut body = json!({"model": "o1-mini", "tools": []});
+        assert!(adapt_body(Provider::ChatGPT, "o1-mini", &mut body).is_err());
+
+        let mut body = json!({"model": "o3-pro", "stream": true});
+        let err = adapt_body(Provider::ChatGPT, "o3-pro", &mut body).unwrap_err();
+        assert_eq!(err, "o3-pro does not stream: send stream: false");
+        assert!(check_stream("o3").is_ok());
+        assert!(check_stream("local-model").is_ok());
+    }
+}
@@ -1,7 +1,16 @@
-use crate::manage::{self, message::Message, utils};
-
-use super::utils::get_env;
+use crate::manage::{
+    self, capabilities,
+    gallery::{self, Gallery, GeneratedImage},
+    generation::{self, GenerationConfig},
+    message::Message,
+    provider::{self, Provider, Target},
+    settings, transport, utils, variables,
+};
+
+use base64::Engine;
+use reqwest::multipart::{Form, Part};
 use reqwest::Client;
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use std::sync::Mutex;
 use std::{result::Result, sync::Arc};
@@ -10,191 +19,730 @@ use tauri::State;
 #[tauri::command]
 pub async fn chatgpt_request(
     b: u8,
+    model: Option<String>,
     msg: &str,
     src: &str,
+    config: Option<GenerationConfig>,
     state: State<'_, Arc<Mutex<manage::message::Shelf>>>,
-) -> Result<String, String> {
+) -> Result<utils::ChatResponse, String> {
     let start_time = chrono::Local::now();
 
-    let (high, low) = model();
-    let (set_model, _max_tokens) = if b == 1 { (high, 4096) } else { (low, 16384) };
+    // キーが無ければ、履歴に追加する前にエラーにする
+    transport::key(Provider::ChatGPT)?;
+
+    // model があれば b より優先する
+    let (_, set_model, max_tokens) = Target {
+        provider: Provider::ChatGPT,
+        b,
+        model,
+    }
+    .resolve();
 
     // add new request message, and get message history
-    let messages = {
+    let (mut messages, system_prompt, rag, config) = {
         let set_src = if src.is_empty() {
             None
         } else {
             Some(src.to_string())
         };
         let mut mut_shelf = state.lock().unwrap();
+        // 生成パラメータが上限を超えていれば、履歴に追加する前にエラーにする
+        let config = mut_shelf.generation_config(config.as_ref());
+        config.validate(Provider::ChatGPT, &set_model)?;
+        // 添付を読めないモデルなら、履歴に追加する前にエラーにする
+        capabilities::check_attachment(Provider::ChatGPT, &set_model, set_src.as_deref())?;
+        // システムプロンプトの {user} や {date} などの変数を展開する
+        // 値のない変数があれば、履歴に追加する前にエラーにする
+        // 質問はコードなどの { } をそのまま送るため展開しない
+        let system_prompt =
+            variables::render_system(&mut_shelf.system_prompt(), Provider::ChatGPT, &set_model)?;
         mut_shelf.add_to_messages("user".to_string(), msg.to_string(), set_src);
 
-        let guard_shelf = mut_shelf.clone();
-        let mut messages = guard_shelf.get_messages();
-
-        let system_prompt = mut_shelf.system_messages.get();
-        if let Some(prompt) = system_prompt.last() {
-            messages.push(prompt.clone());
-        }
-
-        messages
+        (
+            mut_shelf.context_messages(),
+            system_prompt,
+            mut_shelf.rag.clone(),
+            config,
+        )
     };
 
-    // request
-    let body = json!({
-        "model": set_model,
-        // "max_tokens": max_tokens, 4o-previewではサポートされていない
-        "messages": messages.iter().map(|m| {
-            json!({
-                "role": m.role,
-                "content": to_content(m.clone())
-            })
-        }).collect::<Vec<_>>(),
-    });
-    let res = match inner(body).await {
-        Ok(res) => res,
-        Err(e) => return Err(format!("Request error: {}", e)),
+    // 送れなかった質問は履歴から除く (次の質問で user が続かないように)
+    let sent = async {
+        // 索引が設定されていれば、質問に資料を添える
+        let citations = match &rag {
+            Some(name) => manage::rag::augment(name, &mut messages).await?,
+            None => Vec::new(),
+        };
+
+        // request
+        let max_tokens = config.max_tokens.unwrap_or(max_tokens);
+        // モデルの性能に合わせる。読めない添付などは送信前にエラー
+        let (body, adapted) = provider::prepare(
+            Provider::ChatGPT,
+            &set_model,
+            max_tokens,
+            messages,
+            &system_prompt,
+            &config,
+        )?;
+        let res = inner(body)
+            .await
+            .map_err(|e| format!("Request error: {}", e))?;
+        Ok::<_, String>((citations, adapted, res))
+    }
+    .await;
+    let (citations, adapted, res) = match sent {
+        Ok(sent) => sent,
+        Err(e) => {
+            state.lock().unwrap().drop_unanswered();
+            return Err(e);
+        }
     };
 
-    // get response message and token count
-    let (text, token_count) = match manage::utils::get_content_for_chatgpt(&res) {
-        Ok(v) => v,
-        Err(e) => (format!("Error: {}", e), 0),
+    // get response message, token count and finish reason
+    let mut meta = provider::meta(Provider::ChatGPT, &set_model, &res, start_time);
+    meta.warnings.extend(adapted);
+    let text = match manage::utils::get_content_for_chatgpt(&res) {
+        Ok((text, _)) => text,
+        Err(e) => {
+            meta.warnings.push(e.clone());
+            format!("Error: {}", e)
+        }
     };
 
     // メッセージを履歴に追加
-    {
+    let voice = {
         let mut mut_shelf = state.lock().unwrap();
-        mut_shelf.add_to_messages("assistant".to_string(), text.clone(), None);
-    }
+        let texts = generation::candidate_texts(Provider::ChatGPT, &res);
+        if texts.len() > 1 {
+            if let Err(e) = mut_shelf.add_candidates(texts, meta.clone()) {
+                mut_shelf.drop_unanswered();
+                return Err(e);
+            }
+        } else {
+            mut_shelf.add_answer(text.clone(), meta.clone());
+        }
+        mut_shelf.voice()
+    };
 
-    manage::utils::say(text.to_string());
+    manage::utils::say_as(text.to_string(), voice);
 
     let markdown_content = manage::utils::convert_markdown_to_html(text.as_str())?;
+    let markdown_content = format!(
+        "{}{}",
+        markdown_content,
+        manage::rag::citations_html(&citations)
+    );
 
     Ok(manage::utils::create_response(
-        markdown_content.as_str(),
-        set_model.as_str(),
-        token_count,
-        start_time,
+        &markdown_content,
+        &text,
+        meta,
     ))
 }
 
 #[tauri::command]
-pub async fn chatgpt_request_to_dell3(size: u8, msg: &str) -> Result<String, String> {
+pub async fn chatgpt_request_to_dell3(
+    size: u8,
+    msg: &str,
+    state: State<'_, Arc<Mutex<manage::message::Shelf>>>,
+) -> Result<String, String> {
+    let options = ImageOptions {
+        model: "dall-e-3".to_string(),
+        size: dell3_size(size).to_string(),
+        ..Default::default()
+    };
+    chatgpt_request_to_image(msg, options, state).await
+}
+
+/// 画像を生成する
+/// モデルや品質などは options で指定し、省略時は IMAGE_MODEL の既定値を使う
+#[tauri::command]
+pub async fn chatgpt_request_to_image(
+    msg: &str,
+    options: ImageOptions,
+    state: State<'_, Arc<Mutex<manage::message::Shelf>>>,
+) -> Result<String, String> {
+    options.validate(ImageAction::Generate)?;
+
     // request
-    let res = match manage::chatgpt::request_to_dell3(size, msg).await {
+    let res = match request_to_image(&options, msg).await {
         Ok(res) => res,
         Err(e) => return Err(format!("Request error: {}", e)),
     };
 
-    // get response message
-    let (prompt, url) = match manage::utils::get_content_for_chatgpt_dell3(&res) {
-        Ok(text) => text,
-        Err(e) => return Err(format!("Error: {}", e).to_string()),
+    store_images(&res, msg, &options, state).await
+}
+
+/// 画像を編集する
+/// image / mask は data URL で受け取る。貼り付けたスクリーンショットをそのまま渡せる
+#[tauri::command]
+pub async fn chatgpt_request_image_edit(
+    msg: &str,
+    image: &str,
+    mask: Option<String>,
+    options: ImageOptions,
+    state: State<'_, Arc<Mutex<manage::message::Shelf>>>,
+) -> Result<String, String> {
+    options.validate(ImageAction::Edit)?;
+
+    let res = match request_image_edit(&options, msg, image, mask.as_deref()).await {
+        Ok(res) => res,
+        Err(e) => return Err(format!("Request error: {}", e)),
     };
 
-    let text = json!({
-        "prompt": prompt,
-        "url": url,
-    });
+    store_images(&res, msg, &options, state).await
+}
+
+/// 画像のバリエーションを生成する (dall-e-2 のみ対応)
+/// source はギャラリーの id など元画像を示す名前。履歴の質問として残す
+#[tauri::command]
+pub async fn chatgpt_request_image_variation(
+    image: &str,
+    source: Option<String>,
+    options: ImageOptions,
+    state: State<'_, Arc<Mutex<manage::message::Shelf>>>,
+) -> Result<String, String> {
+    let options = ImageOptions {
+        model: "dall-e-2".to_string(),
+        ..options
+    };
+    options.validate(ImageAction::Variation)?;
+
+    let res = match request_image_variation(&options, image).await {
+        Ok(res) => res,
+        Err(e) => return Err(format!("Request error: {}", e)),
+    };
 
-    Ok(text.to_string())
+    let msg = variation_message(source.as_deref(), image)?;
+    store_images(&res, &msg, &options, state).await
 }
 
-pub fn model() -> (String, String) {
-    let (mut high_model, mut low_model) = utils::model_high_and_low("CHATGPT_MODELS");
-    high_model = if high_model.is_empty() {
-        String::from("chatgpt-4o")
-    } else {
-        high_model
+/// バリエーションの元画像を示す質問文
+/// source が無ければ形式と大きさで示す
+fn variation_message(source: Option<&str>, image: &str) -> Result<String, String> {
+    match source.filter(|s| !s.is_empty()) {
+        Some(source) => Ok(format!("variation of {}", source)),
+        None => {
+            let (mime, bytes) = utils::decode_data_url(image)?;
+            Ok(format!(
+                "variation of an attached image ({}, {} KB)",
+                mime,
+                bytes.len().div_ceil(1024)
+            ))
+        }
+    }
+}
+
+/// 画像APIのレスポンスをギャラリーに保存し、履歴に追加する
+async fn store_images(
+    res: &Value,
+    msg: &str,
+    options: &ImageOptions,
+    state: State<'_, Arc<Mutex<manage::message::Shelf>>>,
+) -> Result<String, String> {
+    // get response images
+    // b64_json が無い場合は一時URLからダウンロードする
+    let images = match manage::utils::get_b64_images_for_chatgpt(res) {
+        Ok(images) => {
+            let mut decoded = Vec::new();
+            for (prompt, b64) in images {
+                let bytes = base64::engine::general_purpose::STANDARD
+                    .decode(b64)
+                    .map_err(|e| format!("base64 decode error: {}", e))?;
+                decoded.push((prompt, bytes));
+            }
+            decoded
+        }
+        Err(_) => {
+            let (prompt, url) = match manage::utils::get_content_for_chatgpt_dell3(res) {
+                Ok(text) => text,
+                Err(e) => return Err(format!("Error: {}", e).to_string()),
+            };
+            vec![(prompt, download(&url).await?)]
+        }
     };
-    low_model = if low_model.is_empty() {
-        String::from("gpt-4o-mini")
-    } else {
-        low_model
+
+    // 生成画像をローカルに保存する
+    let gallery = Gallery::open()?;
+    let template = GeneratedImage {
+        provider: "openai".to_string(),
+        model: options.model.clone(),
+        prompt: msg.to_string(),
+        size: options.size.clone(),
+        ..Default::default()
     };
+    let saved = gallery.save_all(
+        &template,
+        images
+            .into_iter()
+            .map(|(prompt, bytes)| (prompt, bytes, options.extension().to_string()))
+            .collect(),
+    )?;
+
+    // 履歴とメモに残るよう、保存先は本文とは別に追加する
+    {
+        let mut mut_shelf = state.lock().unwrap();
+        mut_shelf.add_to_messages("user".to_string(), msg.to_string(), None);
+        mut_shelf.add_images(gallery::revised_text(&saved), gallery.paths(&saved), None);
+    }
+
+    Ok(gallery::to_response(&saved))
+}
+
+/// 高性能と低価格のモデル
+/// 設定 (CHATGPT_MODELS で上書き) が無ければ既定のモデル
+pub fn model() -> (String, String) {
+    let (high, low) = settings::current().models(Provider::ChatGPT);
+    (
+        high.unwrap_or(String::from("gpt-4.1")),
+        low.unwrap_or(String::from("gpt-4.1-mini")),
+    )
+}
+
+/// OpenAI のモデル系統ごとのリクエストの作り方
+/// capabilities の登録から決める
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Family {
+    // システムプロンプトの role。None なら最初の質問に添える
+    pub system_role: Option<&'static str>,
+    // 最大トークン数のパラメータ名
+    pub max_tokens: &'static str,
+    // reasoning_effort を受け付けるか
+    pub reasoning: bool,
+    // 受け付けないパラメータ
+    pub unsupported: &'static [&'static str],
+    // 既定の最大トークン数の下限。推論のトークンも含むため、推論モデルは大きめにする
+    pub min_max_tokens: u32,
+}
+
+// 推論モデルの既定の最大トークン数 (OpenAI は推論用に 25,000 以上を空けるよう勧めている)
+const REASONING_MAX_TOKENS: u32 = 25_000;
+
+// 推論モデルが受け付けないパラメータ
+const REASONING_UNSUPPORTED: &[&str] = &[
+    "temperature",
+    "top_p",
+    "presence_penalty",
+    "frequency_penalty",
+    "logprobs",
+    "top_logprobs",
+    "logit_bias",
+];
+
+// o1-mini, o1-preview は reasoning_effort も受け付けない
+const EARLY_REASONING_UNSUPPORTED: &[&str] = &[
+    "temperature",
+    "top_p",
+    "presence_penalty",
+    "frequency_penalty",
+    "logprobs",
+    "top_logprobs",
+    "logit_bias",
+    "reasoning_effort",
+];
+
+/// モデル名に合う系統
+/// 不明なモデル (互換サーバーなど) は従来の chat completions として扱う
+pub fn family(model: &str) -> Family {
+    let caps = capabilities::lookup(model);
+    let system_prompt = caps.is_none_or(|caps| caps.system_prompt);
+    let reasoning = caps.is_some_and(|caps| caps.reasoning);
+    let effort = caps.is_some_and(|caps| caps.reasoning_effort);
+    Family {
+        system_role: match (system_prompt, reasoning) {
+            (false, _) => None,
+            (true, true) => Some("developer"),
+            (true, false) => Some("system"),
+        },
+        max_tokens: if reasoning {
+            "max_completion_tokens"
+        } else {
+            "max_tokens"
+        },
+        reasoning: effort,
+        unsupported: match (reasoning, effort) {
+            (false, _) => &["reasoning_effort"],
+            (true, true) => REASONING_UNSUPPORTED,
+            (true, false) => EARLY_REASONING_UNSUPPORTED,
+        },
+        min_max_tokens: if reasoning { REASONING_MAX_TOKENS } else { 0 },
+    }
+}
+
+/// 既定の最大トークン数
+/// 推論モデルでは推論だけで使い切らないよう min_max_tokens まで増やす
+pub fn default_max_tokens(model: &str, max_tokens: u32) -> u32 {
+    max_tokens.max(family(model).min_max_tokens)
+}
+
+/// 推論の強さ (minimal, low, medium, high)。設定か REASONING_EFFORT で指定する
+pub fn reasoning_effort() -> Option<String> {
+    settings::current().reasoning_effort
+}
+
+/// リクエストボディを作成する
+/// システムプロンプトは先頭の system (推論モデルは developer) メッセージにする
+pub fn to_body(model: &str, max_tokens: u32, messages: &[Message], system_prompt: &str) -> Value {
+    build_body(
+        model,
+        max_tokens,
+        messages,
+        system_prompt,
+        reasoning_effort().as_deref(),
+    )
+}
 
-    (high_model, low_model)
+fn build_body(
+    model: &str,
+    max_tokens: u32,
+    messages: &[Message],
+    system_prompt: &str,
+    effort: Option<&str>,
+) -> Value {
+    let family = family(model);
+    let mut messages = messages.to_vec();
+    if !system_prompt.is_empty() {
+        match (family.system_role, messages.first_mut()) {
+            (Some(role), _) => messages.insert(
+                0,
+                Message {
+                    role: role.to_string(),
+                    content: system_prompt.to_string(),
+                    ..Default::default()
+                },
+            ),
+            // system を受け付けないモデルは最初の質問に添える
+            (None, Some(first)) => {
+                first.content = format!("{}\n\n{}", system_prompt, first.content);
+            }
+            (None, None) => {}
+        }
+    }
+
+    let mut body = json!({
+        "model": model,
+        "messages": messages.iter().map(|m| {
+            json!({
+                "role": m.role,
+                "content": to_content(m.clone())
+            })
+        }).collect::<Vec<_>>(),
+    });
+    body[family.max_tokens] = json!(max_tokens);
+    // 推論モデル以外には付けない
+    if let (true, Some(effort)) = (family.reasoning, effort) {
+        body["reasoning_effort"] = json!(effort);
+    }
+    body
+}
+
+/// 系統が受け付けないパラメータがあればエラーにする
+pub fn check(body: &Value) -> Result<(), String> {
+    let model = body["model"].as_str().unwrap_or_default();
+    let family = family(model);
+    let rejected: Vec<&str> = family
+        .unsupported
+        .iter()
+        .copied()
+        .filter(|key| !body[*key].is_null())
+        .collect();
+    if !rejected.is_empty() {
+        return Err(format!(
+            "{} does not support: {}",
+            model,
+            rejected.join(", ")
+        ));
+    }
+    if let Some(effort) = body["reasoning_effort"].as_str() {
+        if !["minimal", "low", "medium", "high"].contains(&effort) {
+            return Err(format!("invalid reasoning_effort: {}", effort));
+        }
+    }
+    Ok(())
 }
 
 pub fn to_content(message: Message) -> Value {
-    if message.src.is_none() {
-        json!([{"type": "text", "text": message.content }])
-    } else {
-        json!([
+    match message.src {
+        None => json!([{"type": "text", "text": message.content }]),
+        Some(src) => json!([
             {
                 "type": "image_url",
                 "image_url": {
-                    "url": message.src.unwrap(),
+                    "url": src,
                 },
             },
             {"type": "text", "text": message.content},
-        ])
+        ]),
     }
 }
 
 pub async fn inner(body: Value) -> Result<Value, String> {
-    let keys = get_env().await.unwrap();
+    check(&body)?;
+    let key = transport::key(Provider::ChatGPT)?;
 
     // リクエストを送信
-    let client = Client::new();
-    let res = match client
-        .post("https://api.openai.com/v1/chat/completions")
-        .header("Authorization", format!("Bearer {}", keys.openai_token))
-        .header("content-type", "application/json")
-        .json(&body)
-        .send()
-        .await
-    {
-        Ok(response) => response,
-        Err(err) => {
-            return Err(format!("Request error: {}", err));
-        }
-    };
-
-    match res.json().await {
-        Ok(json) => Ok(json),
-        Err(err) => Err(format!("JSON parse error: {}", err)),
-    }
+    transport::post_json(
+        "https://api.openai.com/v1/chat/completions",
+        &[("Authorization", format!("Bearer {}", key))],
+        &body,
+    )
+    .await
 }
 
-pub async fn request_to_dell3(size_type: u8, prompt: &str) -> Result<Value, String> {
-    let keys = get_env().await.unwrap();
-
-    let size = match size_type {
+pub fn dell3_size(size_type: u8) -> &'static str {
+    match size_type {
         1 => "1024x1024",
         2 => "1792x1024",
         3 => "1024x1792",
         _ => "1024x1024",
-    };
+    }
+}
 
-    let body = json!({
-      "model": "dall-e-3",
-      "prompt": prompt,
-      "n": 1,
-      "size": size,
-    });
+/// 画像APIの種類。モデルごとに使えるものが違う
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageAction {
+    Generate,
+    // dall-e-2, gpt-image-1
+    Edit,
+    // dall-e-2 のみ
+    Variation,
+}
+
+/// 画像生成/編集のオプション
+/// 各モデルが受け付けないパラメータは validate でエラーにする
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ImageOptions {
+    // dall-e-2, dall-e-3, gpt-image-1
+    pub model: String,
+    // 1024x1024, 1792x1024, 1536x1024, auto, ...
+    pub size: String,
+    // dall-e-3: standard, hd / gpt-image-1: low, medium, high, auto
+    pub quality: Option<String>,
+    // dall-e-3: vivid, natural
+    pub style: Option<String>,
+    // gpt-image-1: transparent, opaque, auto
+    pub background: Option<String>,
+    // gpt-image-1: png, jpeg, webp
+    pub output_format: Option<String>,
+    pub n: u8,
+}
+
+impl Default for ImageOptions {
+    fn default() -> Self {
+        let model = settings::current()
+            .image_model
+            .unwrap_or(String::from("dall-e-3"));
+        Self {
+            model,
+            size: "1024x1024".to_string(),
+            quality: None,
+            style: None,
+            background: None,
+            output_format: None,
+            n: 1,
+        }
+    }
+}
+
+impl ImageOptions {
+    pub fn is_gpt_image(&self) -> bool {
+        self.model.starts_with("gpt-image")
+    }
+
+    pub fn validate(&self, action: ImageAction) -> Result<(), String> {
+        match action {
+            ImageAction::Edit if self.model == "dall-e-3" => {
+                return Err(
+                    "dall-e-3 does not support edits: use dall-e-2 or gpt-image-1".to_string(),
+                );
+            }
+            ImageAction::Variation if self.model != "dall-e-2" => {
+                return Err(format!(
+                    "variations are only supported by dall-e-2, not {}",
+                    self.model
+                ));
+            }
+            _ => {}
+        }
+        if let Some(sizes) = self.sizes() {
+            if !sizes.contains(&self.size.as_str()) {
+                return Err(format!(
+                    "size {} is not supported by {}: use one of {}",
+                    self.size,
+                    self.model,
+                    sizes.join(", ")
+                ));
+            }
+        }
+        if self.n == 0 || self.n > 10 {
+            return Err(format!("n must be between 1 and 10: {}", self.n));
+        }
+        if self.model == "dall-e-3" && self.n != 1 {
+            return Err("dall-e-3 supports only n = 1".to_string());
+        }
+        if self.style.is_some() && self.model != "dall-e-3" {
+            return Err(format!("style is not supported by {}", self.model));
+        }
+        if !self.is_gpt_image() && (self.background.is_some() || self.output_format.is_some()) {
+            return Err(format!(
+                "background and output_format are not supported by {}",
+                self.model
+            ));
+        }
+        if self.background.as_deref() == Some("transparent")
+            && self.output_format.as_deref() == Some("jpeg")
+        {
+            return Err("transparent background requires png or webp".to_string());
+        }
+        Ok(())
+    }
+
+    /// モデルが受け付けるサイズ。不明なモデルは None で確認しない
+    pub fn sizes(&self) -> Option<&'static [&'static str]> {
+        match self.model.as_str() {
+            "dall-e-2" => Some(&["256x256", "512x512", "1024x1024"]),
+            "dall-e-3" => Some(&["1024x1024", "1792x1024", "1024x1792"]),
+            _ if self.is_gpt_image() => Some(&["1024x1024", "1536x1024", "1024x1536", "auto"]),
+            _ => None,
+        }
+    }
+
+    /// 保存時の拡張子
+    pub fn extension(&self) -> &str {
+        match self.output_format.as_deref() {
+            Some("jpeg") => "jpg",
+            Some("webp") => "webp",
+            _ => "png",
+        }
+    }
+
+    /// 画像生成APIのリクエストボディ
+    pub fn to_body(&self, prompt: &str) -> Value {
+        let mut body = json!({
+            "model": self.model,
+            "prompt": prompt,
+            "n": self.n,
+            "size": self.size,
+        });
+        self.apply(&mut body);
+        body
+    }
+
+    fn apply(&self, body: &mut Value) {
+        // gpt-image-1 は常に b64_json を返し、response_format を受け付けない
+        // dall-e は URL が数時間で失効するため、画像データを直接受け取る
+        if !self.is_gpt_image() {
+            body["response_format"] = json!("b64_json");
+        }
+        if let Some(quality) = &self.quality {
+            body["quality"] = json!(quality);
+        }
+        if let Some(style) = &self.style {
+            body["style"] = json!(style);
+        }
+        if let Some(background) = &self.background {
+            body["background"] = json!(background);
+        }
+        if let Some(output_format) = &self.output_format {
+            body["output_format"] = json!(output_format);
+        }
+    }
+
+    /// multipart 用のテキストフィールド
+    fn to_fields(&self) -> Vec<(String, String)> {
+        let mut body = json!({
+            "model": self.model,
+            "n": self.n,
+            "size": self.size,
+        });
+        self.apply(&mut body);
+        body.as_object()
+            .map(|fields| {
+                fields
+                    .iter()
+                    .map(|(k, v)| {
+                        let v = match v {
+                            Value::String(s) => s.clone(),
+                            v => v.to_string(),
+                        };
+                        (k.clone(), v)
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+pub async fn request_to_image(options: &ImageOptions, prompt: &str) -> Result<Value, String> {
+    let key = transport::key(Provider::ChatGPT)?;
+
+    // リクエストを送信
+    transport::post_json(
+        "https://api.openai.com/v1/images/generations",
+        &[("Authorization", format!("Bearer {}", key))],
+        &options.to_body(prompt),
+    )
+    .await
+}
+
+pub async fn request_image_edit(
+    options: &ImageOptions,
+    prompt: &str,
+    image: &str,
+    mask: Option<&str>,
+) -> Result<Value, String> {
+    let mut form = to_form(options, "image", image)?.text("prompt", prompt.to_string());
+    if let Some(mask) = mask {
+        form = form.part("mask", to_part("mask", mask)?);
+    }
+
+    request_multipart("https://api.openai.com/v1/images/edits", form).await
+}
+
+pub async fn request_image_variation(options: &ImageOptions, image: &str) -> Result<Value, String> {
+    let form = to_form(options, "image", image)?;
+
+    request_multipart("https://api.openai.com/v1/images/variations", form).await
+}
+
+fn to_part(name: &str, data_url: &str) -> Result<Part, String> {
+    let (mime, bytes) = utils::decode_data_url(data_url)?;
+    let ext = mime.trim_start_matches("image/");
+    Part::bytes(bytes)
+        .file_name(format!("{}.{}", name, ext))
+        .mime_str(&mime)
+        .map_err(|e| format!("invalid mime type: {}", e))
+}
+
+fn to_form(options: &ImageOptions, name: &str, data_url: &str) -> Result<Form, String> {
+    let mut form = Form::new().part(name.to_string(), to_part(name, data_url)?);
+    for (k, v) in options.to_fields() {
+        form = form.text(k, v);
+    }
+    Ok(form)
+}
+
+async fn request_multipart(url: &str, form: Form) -> Result<Value, String> {
+    let key = transport::key(Provider::ChatGPT)?;
 
     // リクエストを送信
-    let client = Client::new();
-    let res = match client
-        .post("https://api.openai.com/v1/images/generations")
-        .header("Authorization", format!("Bearer {}", keys.openai_token))
-        .header("content-type", "application/json")
-        .json(&body)
+    transport::post_form(url, &[("Authorization", format!("Bearer {}", key))], form).await
+}
+
+/// 生成画像の一時URLから画像データを取得する
+/// 署名付きの一時URLのため、transport を通さない
+pub async fn download(url: &str) -> Result<Vec<u8>, String> {
+    let res = Client::new()
+        .get(url)
         .send()
         .await
-    {
-        Ok(response) => response,
-        Err(err) => {
-            return Err(format!("Request error: {}", err));
-        }
-    };
+        .map_err(|e| format!("Request error: {}", e))?;
 
-    match res.json().await {
-        Ok(json) => Ok(json),
-        Err(err) => Err(format!("JSON parse error: {}", err)),
+    match res.bytes().await {
+        Ok(bytes) => Ok(bytes.to_vec()),
+        Err(err) => Err(format!("Download error: {}", err)),
     }
 }
 
@@ -205,6 +753,7 @@ mod tests {
     use super::*;
     use serde_json::json;
 
+    // tests/cassettes/chatgpt.json を再生する
     #[tokio::test]
     async fn test_request() {
         let body = json!({
@@ -221,7 +770,8 @@ mod tests {
             ]
         });
 
-        let res = inner(body).await;
+        let mode = transport::cassette_mode("chatgpt");
+        let res = transport::scope(mode, inner(body)).await;
         match res {
             Ok(value) => {
                 let (content, tokens) = match get_content_for_chatgpt(&value) {
@@ -229,17 +779,172 @@ mod tests {
                     Err(e) => (format!("Failed to get content: {}", e), 0),
                 };
                 println!("response: {}, tokens: {}", content, tokens);
+                assert!(content.contains("meaning of life"));
+                assert_eq!(tokens, 49);
             }
             Err(e) => panic!("Failed to request: {}", e),
         }
     }
 
+    #[test]
+    fn test_to_body_puts_system_prompt_first() {
+        let messages = vec![Message {
+            role: "user".to_string(),
+            content: "hello".to_string(),
+            ..Default::default()
+        }];
+        let body = build_body("gpt-4o", 1024, &messages, "be brief", Some("high"));
+        assert_eq!(body["messages"][0]["role"], "system");
+        assert_eq!(body["messages"][0]["content"][0]["text"], "be brief");
+        assert_eq!(body["messages"][1]["role"], "user");
+        assert_eq!(body["max_tokens"], 1024);
+        assert!(body["reasoning_effort"].is_null());
+        assert!(check(&body).is_ok());
+
+        let body = build_body(
+            "o3-mini-2025-01-31",
+            1024,
+            &messages,
+            "be brief",
+            Some("high"),
+        );
+        assert_eq!(body["messages"][0]["role"], "developer");
+        assert_eq!(body["max_completion_tokens"], 1024);
+        assert!(body["max_tokens"].is_null());
+        assert_eq!(body["reasoning_effort"], "high");
+        assert!(check(&body).is_ok());
+
+        let body = build_body("o1-mini", 1024, &messages, "be brief", Some("high"));
+        assert_eq!(body["messages"].as_array().unwrap().len(), 1);
+        assert_eq!(
+            body["messages"][0]["content"][0]["text"],
+            "be brief\n\nhello"
+        );
+        assert!(body["reasoning_effort"].is_null());
+    }
+
+    #[test]
+    fn test_family_and_check() {
+        assert_eq!(family("gpt-4o-mini").system_role, Some("system"));
+        assert_eq!(family("o4-mini").system_role, Some("developer"));
+        assert_eq!(family("o4-mini").max_tokens, "max_completion_tokens");
+        assert_eq!(family("o1-preview-2024-09-12").system_role, None);
+        assert!(!family("o1-preview-2024-09-12").reasoning);
+        assert_eq!(family("ft:gpt-4.1:org::id").max_tokens, "max_tokens");
+        assert_eq!(family("llama3").system_role, Some("system"));
+        assert_eq!(family("llama3").unsupported, &["reasoning_effort"]);
+        assert_eq!(family("gpt-5-chat-latest").system_role, Some("system"));
+        assert!(!family("gpt-5-chat-latest").reasoning);
+        assert_eq!(default_max_tokens("gpt-5-mini", 4096), 25_000);
+        assert_eq!(default_max_tokens("gpt-5-chat-latest", 4096), 4096);
+        assert_eq!(default_max_tokens("gpt-4.1", 16384), 16384);
+
+        let body = json!({"model": "o3", "temperature": 0.2, "top_p": 1});
+        assert_eq!(
+            check(&body).unwrap_err(),
+            "o3 does not support: temperature, top_p"
+        );
+        let body = json!({"model": "gpt-4o", "reasoning_effort": "high"});
+        assert!(check(&body).is_err());
+        let body = json!({"model": "o3", "reasoning_effort": "extreme"});
+        assert!(check(&body).is_err());
+    }
+
+    #[test]
+    fn test_image_options_body_per_model() {
+        let dalle = ImageOptions {
+            model: "dall-e-3".to_string(),
+            style: Some("natural".to_string()),
+            ..Default::default()
+        };
+        assert!(dalle.validate(ImageAction::Generate).is_ok());
+        let body = dalle.to_body("a cat");
+        assert_eq!(body["response_format"], "b64_json");
+        assert_eq!(body["style"], "natural");
+
+        let gpt_image = ImageOptions {
+            model: "gpt-image-1".to_string(),
+            background: Some("transparent".to_string()),
+            output_format: Some("webp".to_string()),
+            n: 3,
+            ..Default::default()
+        };
+        assert!(gpt_image.validate(ImageAction::Edit).is_ok());
+        let body = gpt_image.to_body("a cat");
+        assert!(body.get("response_format").is_none());
+        assert_eq!(body["n"], 3);
+        assert_eq!(gpt_image.extension(), "webp");
+    }
+
+    #[test]
+    fn test_image_options_rejects_unsupported_params() {
+        let dalle = ImageOptions {
+            model: "dall-e-3".to_string(),
+            n: 2,
+            ..Default::default()
+        };
+        assert!(dalle.validate(ImageAction::Generate).is_err());
+
+        let dalle = ImageOptions {
+            model: "dall-e-2".to_string(),
+            background: Some("transparent".to_string()),
+            ..Default::default()
+        };
+        assert!(dalle.validate(ImageAction::Generate).is_err());
+
+        let gpt_image = ImageOptions {
+            model: "gpt-image-1".to_string(),
+            background: Some("transparent".to_string()),
+            output_format: Some("jpeg".to_string()),
+            ..Default::default()
+        };
+        assert!(gpt_image.validate(ImageAction::Generate).is_err());
+
+        let dalle3 = ImageOptions {
+            model: "dall-e-3".to_string(),
+            ..Default::default()
+        };
+        assert!(dalle3.validate(ImageAction::Edit).is_err());
+        assert!(dalle3.validate(ImageAction::Variation).is_err());
+        let wide = ImageOptions {
+            size: "1536x1024".to_string(),
+            ..dalle3
+        };
+        assert!(wide.validate(ImageAction::Generate).is_err());
+        let small = ImageOptions {
+            model: "dall-e-2".to_string(),
+            size: "256x256".to_string(),
+            ..Default::default()
+        };
+        assert!(small.validate(ImageAction::Variation).is_ok());
+    }
+
+    #[test]
+    fn test_variation_message_names_source() {
+        assert_eq!(
+            variation_message(Some("20250101-0"), "").unwrap(),
+            "variation of 20250101-0"
+        );
+        assert_eq!(
+            variation_message(None, "data:image/png;base64,Y2F0").unwrap(),
+            "variation of an attached image (image/png, 1 KB)"
+        );
+    }
+
     #[tokio::test]
     async fn test_request_to_dell3() {
-        let res = request_to_dell3(1, "a cute cat").await;
+        let options = ImageOptions {
+            model: "dall-e-3".to_string(),
+            size: dell3_size(1).to_string(),
+            ..Default::default()
+        };
+        let mode = transport::cassette_mode("chatgpt");
+        let res = transport::scope(mode, request_to_image(&options, "a cute cat")).await;
         match res {
             Ok(value) => {
                 println!("response: {}", value);
+                let images = utils::get_b64_images_for_chatgpt(&value).unwrap();
+                assert_eq!(images.len(), 1);
             }
             Err(e) => panic!("Failed to request: {}", e),
         }
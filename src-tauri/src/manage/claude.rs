@@ -1,7 +1,11 @@
-use crate::manage::{self, message::Message, utils};
+use crate::manage::{
+    self, capabilities,
+    generation::GenerationConfig,
+    message::Message,
+    provider::{self, Provider, Target},
+    settings, transport, utils, variables,
+};
 
-use super::utils::get_env;
-use reqwest::Client;
 use serde_json::{json, Value};
 use std::{
     result::Result,
@@ -12,116 +16,152 @@ use tauri::State;
 #[tauri::command]
 pub async fn claude_request(
     b: u8,
+    model: Option<String>,
     msg: &str,
     src: &str,
+    config: Option<GenerationConfig>,
     state: State<'_, Arc<Mutex<manage::message::Shelf>>>,
-) -> Result<String, String> {
+) -> Result<utils::ChatResponse, String> {
     let start_time = chrono::Local::now();
 
-    let (high, low) = model();
-    let (set_model, max_tokens) = if b == 1 { (high, 8192) } else { (low, 4096) };
+    // キーが無ければ、履歴に追加する前にエラーにする
+    transport::key(Provider::Claude)?;
+
+    // model があれば b より優先する
+    let (_, set_model, max_tokens) = Target {
+        provider: Provider::Claude,
+        b,
+        model,
+    }
+    .resolve();
 
     // add new request message, and get message history
-    let (messages, system_prompt) = {
+    let (mut messages, system_prompt, rag, config) = {
         let set_src = if src.is_empty() {
             None
         } else {
             Some(src.to_string())
         };
         let mut mut_shelf = state.lock().unwrap();
+        // 生成パラメータが上限を超えていれば、履歴に追加する前にエラーにする
+        let config = mut_shelf.generation_config(config.as_ref());
+        config.validate(Provider::Claude, &set_model)?;
+        // 添付を読めないモデルなら、履歴に追加する前にエラーにする
+        capabilities::check_attachment(Provider::Claude, &set_model, set_src.as_deref())?;
+        // システムプロンプトの {user} や {date} などの変数を展開する
+        // 値のない変数があれば、履歴に追加する前にエラーにする
+        // 質問はコードなどの { } をそのまま送るため展開しない
+        let system_prompt =
+            variables::render_system(&mut_shelf.system_prompt(), Provider::Claude, &set_model)?;
         mut_shelf.add_to_messages("user".to_string(), msg.to_string(), set_src);
 
-        let guard_shelf = mut_shelf.clone();
+        (
+            mut_shelf.context_messages(),
+            system_prompt,
+            mut_shelf.rag.clone(),
+            config,
+        )
+    };
 
-        let system_prompts = mut_shelf.system_messages.get();
-        let system_prompt = if !system_prompts.is_empty() {
-            // 最期の配列をStringで出力
-            let prompt = system_prompts.last().unwrap();
-            prompt.content.to_string()
-        } else {
-            "".to_string()
+    // 送れなかった質問は履歴から除く (次の質問で user が続かないように)
+    let sent = async {
+        // 索引が設定されていれば、質問に資料を添える
+        let citations = match &rag {
+            Some(name) => manage::rag::augment(name, &mut messages).await?,
+            None => Vec::new(),
         };
 
-        (guard_shelf.get_messages(), system_prompt)
-    };
-
-    // request
-    let body = if system_prompt.is_empty() {
-        json!({
-            "model": set_model,
-            "max_tokens": max_tokens,
-            "messages":
-                messages.iter().map(|m| {
-                    json!({
-                        "role": m.role,
-                        "content": to_content(m.clone())
-                    })
-                }).collect::<Vec<_>>(),
-        })
-    } else {
-        json!({
-            "model": set_model,
-            "max_tokens": max_tokens,
-            "system": system_prompt,
-            "messages":
-            messages.iter().map(|m| {
-                json!({
-                    "role": m.role,
-                    "content": to_content(m.clone())
-                })
-            }).collect::<Vec<_>>(),
-        })
-    };
-    let res = match inner(body).await {
-        Ok(res) => res,
-        Err(e) => return Err(format!("Request error: {}", e)),
+        // request
+        let max_tokens = config.max_tokens.unwrap_or(max_tokens);
+        // モデルの性能に合わせる。読めない添付などは送信前にエラー
+        let (body, adapted) = provider::prepare(
+            Provider::Claude,
+            &set_model,
+            max_tokens,
+            messages,
+            &system_prompt,
+            &config,
+        )?;
+        let res = inner(body)
+            .await
+            .map_err(|e| format!("Request error: {}", e))?;
+        Ok::<_, String>((citations, adapted, res))
+    }
+    .await;
+    let (citations, adapted, res) = match sent {
+        Ok(sent) => sent,
+        Err(e) => {
+            state.lock().unwrap().drop_unanswered();
+            return Err(e);
+        }
     };
 
-    // get response message and token count
-    let (text, token_count) = match manage::utils::get_content_for_claude(&res) {
-        Ok(v) => v,
-        Err(e) => (format!("Error: {}", e), 0),
+    // get response message, token count and finish reason
+    let mut meta = provider::meta(Provider::Claude, &set_model, &res, start_time);
+    meta.warnings.extend(adapted);
+    let text = match manage::utils::get_content_for_claude(&res) {
+        Ok((text, _)) => text,
+        Err(e) => {
+            meta.warnings.push(e.clone());
+            format!("Error: {}", e)
+        }
     };
 
     // メッセージを履歴に追加
-    {
+    let voice = {
         let mut mut_shelf = state.lock().unwrap();
-        mut_shelf.add_to_messages("assistant".to_string(), text.clone(), None);
-    }
+        mut_shelf.add_answer(text.clone(), meta.clone());
+        mut_shelf.voice()
+    };
 
-    manage::utils::say(text.to_string());
+    manage::utils::say_as(text.to_string(), voice);
 
     let markdown_content = manage::utils::convert_markdown_to_html(text.as_str())?;
+    let markdown_content = format!(
+        "{}{}",
+        markdown_content,
+        manage::rag::citations_html(&citations)
+    );
 
     Ok(manage::utils::create_response(
-        markdown_content.as_str(),
-        set_model.as_str(),
-        token_count,
-        start_time,
+        &markdown_content,
+        &text,
+        meta,
     ))
 }
 
+/// 高性能と低価格のモデル
+/// 設定 (CLAUDE_MODELS で上書き) が無ければ既定のモデル
 pub fn model() -> (String, String) {
-    let (mut high_model, mut low_model) = utils::model_high_and_low("CLAUDE_MODELS");
-    high_model = if high_model.is_empty() {
-        String::from("claude-3-7-sonnet-latest")
-    } else {
-        high_model
-    };
-    low_model = if low_model.is_empty() {
-        String::from("claude-3-5-haiku")
-    } else {
-        low_model
-    };
+    let (high, low) = settings::current().models(Provider::Claude);
+    (
+        high.unwrap_or(String::from("claude-sonnet-4-5")),
+        low.unwrap_or(String::from("claude-haiku-4-5")),
+    )
+}
 
-    (high_model, low_model)
+/// リクエストボディを作成する
+/// システムプロンプトは system に設定する
+pub fn to_body(model: &str, max_tokens: u32, messages: &[Message], system_prompt: &str) -> Value {
+    let mut body = json!({
+        "model": model,
+        "max_tokens": max_tokens,
+        "messages":
+            messages.iter().map(|m| {
+                json!({
+                    "role": m.role,
+                    "content": to_content(m.clone())
+                })
+            }).collect::<Vec<_>>(),
+    });
+    if !system_prompt.is_empty() {
+        body["system"] = json!(system_prompt);
+    }
+    body
 }
 
 pub fn to_content(message: Message) -> Value {
-    if message.src.is_none() {
-        json!([{"type": "text", "text": message.content}])
-    } else {
-        let src = message.src.unwrap();
+    if let Some(src) = message.src {
         let media_type = if src.contains("data:image/png") {
             "image/png"
         } else {
@@ -142,33 +182,24 @@ pub fn to_content(message: Message) -> Value {
             },
             {"type": "text", "text": message.content}
         ])
+    } else {
+        json!([{"type": "text", "text": message.content}])
     }
 }
 
 pub async fn inner(body: Value) -> Result<Value, String> {
-    let keys = get_env().await.unwrap();
+    let key = transport::key(Provider::Claude)?;
 
     // リクエストを送信
-    let client = Client::new();
-    let res = match client
-        .post("https://api.anthropic.com/v1/messages")
-        .header("x-api-key", keys.anthropic_key)
-        .header("anthropic-version", "2023-06-01")
-        .header("content-type", "application/json")
-        .json(&body)
-        .send()
-        .await
-    {
-        Ok(response) => response,
-        Err(err) => {
-            return Err(format!("Request error: {}", err));
-        }
-    };
-
-    match res.json().await {
-        Ok(json) => Ok(json),
-        Err(err) => Err(format!("JSON parse error: {}", err)),
-    }
+    transport::post_json(
+        "https://api.anthropic.com/v1/messages",
+        &[
+            ("x-api-key", key),
+            ("anthropic-version", "2023-06-01".to_string()),
+        ],
+        &body,
+    )
+    .await
 }
 
 #[cfg(test)]
@@ -185,6 +216,8 @@ mod tests {
     use super::*;
     use serde_json::json;
 
+    // tests/cassettes/claude.json を再生する
+    // HTTP_MODE=record で本物の API から記録し直せる
     #[tokio::test]
     async fn test_request() {
         let body = json!({
@@ -197,7 +230,8 @@ mod tests {
             }
         ]});
 
-        let res = inner(body).await;
+        let mode = transport::cassette_mode("claude");
+        let res = transport::scope(mode, inner(body)).await;
         match res {
             Ok(value) => {
                 let (text, tokens) = match get_content_for_claude(&value) {
@@ -205,6 +239,8 @@ mod tests {
                     Err(e) => (format!("Failed to get content: {}", e), 0),
                 };
                 println!("response: {}, tokens: {}", text, tokens);
+                assert!(text.contains("meaning of life"));
+                assert_eq!(tokens, 45);
             }
             Err(e) => panic!("Failed to request: {}", e),
         };
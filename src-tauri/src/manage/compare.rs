@@ -0,0 +1,183 @@
+use serde::Serialize;
+use std::{
+    result::Result,
+    sync::{Arc, Mutex},
+};
+use tauri::State;
+
+use crate::manage::{
+    self,
+    message::Message,
+    provider::{self, Reply, Target},
+    variables,
+};
+
+/// 比較モードの回答1件
+/// index は choose_answer に渡す候補番号。失敗した回答は None
+#[derive(Debug, Clone, Serialize)]
+pub struct Answer {
+    pub index: Option<usize>,
+    #[serde(flatten)]
+    pub reply: Reply,
+    pub html: String,
+    pub error: Option<String>,
+}
+
+/// 同じ履歴と質問を複数のAIへ同時に送り、回答を並べて返す
+/// 最初に成功した回答を履歴に採用し、残りは候補として保持する
+#[tauri::command]
+pub async fn compare_request(
+    targets: Vec<Target>,
+    msg: &str,
+    src: &str,
+    state: State<'_, Arc<Mutex<manage::message::Shelf>>>,
+) -> Result<Vec<Answer>, String> {
+    if targets.is_empty() {
+        return Err("no targets to compare".to_string());
+    }
+
+    // add new request message, and get message history
+    let (mut messages, system_prompts, rag, config) = {
+        let set_src = if src.is_empty() {
+            None
+        } else {
+            Some(src.to_string())
+        };
+        let mut mut_shelf = state.lock().unwrap();
+        // システムプロンプトを相手ごとの provider, model で展開する
+        // 値のない変数があれば、履歴に追加する前にエラーにする
+        let system_prompt = mut_shelf.system_prompt();
+        let system_prompts = targets
+            .iter()
+            .map(|target| {
+                let (provider, model, _) = target.resolve();
+                variables::render_system(&system_prompt, provider, &model)
+            })
+            .collect::<Result<Vec<String>, String>>()?;
+        mut_shelf.add_to_messages("user".to_string(), msg.to_string(), set_src);
+
+        (
+            mut_shelf.context_messages(),
+            system_prompts,
+            mut_shelf.rag.clone(),
+            mut_shelf.generation_config(None),
+        )
+    };
+
+    // 索引が設定されていれば、質問に資料を添える
+    // 資料を読めなければ、送れなかった質問を履歴から除く
+    let citations = match &rag {
+        Some(name) => match manage::rag::augment(name, &mut messages).await {
+            Ok(citations) => citations,
+            Err(e) => {
+                state.lock().unwrap().drop_unanswered();
+                return Err(e);
+            }
+        },
+        None => Vec::new(),
+    };
+
+    // 各AIへ並行してリクエストする
+    let handles = targets
+        .iter()
+        .zip(system_prompts)
+        .map(|(target, system_prompt)| {
+            let (provider, model, max_tokens) = target.resolve();
+            let messages = messages.clone();
+            let config = config.clone();
+            let set_model = model.clone();
+            let handle = tokio::spawn(async move {
+                provider::ask(
+                    provider,
+                    &set_model,
+                    max_tokens,
+                    &messages,
+                    &system_prompt,
+                    &config,
+                )
+                .await
+            });
+            (provider, model, handle)
+        })
+        .collect::<Vec<_>>();
+
+    let mut answers = Vec::new();
+    let mut candidates = Vec::new();
+    for (provider, model, handle) in handles {
+        let result = match handle.await {
+            Ok(result) => result,
+            Err(e) => Err(format!("task error: {}", e)),
+        };
+
+        // HTML に変換できない回答も失敗として記録し、残りの回答を捨てない
+        let result = result.and_then(|reply| {
+            let html = manage::utils::convert_markdown_to_html(reply.text.as_str())?;
+            Ok((reply, html))
+        });
+        match result {
+            Ok((reply, html)) => {
+                let html = format!("{}{}", html, manage::rag::citations_html(&citations));
+                candidates.push(Message {
+                    role: "assistant".to_string(),
+                    content: reply.text.clone(),
+                    label: Some(format!("{}:{}", reply.provider.name(), reply.model)),
+                    meta: Some(reply.meta()),
+                    ..Default::default()
+                });
+                answers.push(Answer {
+                    index: Some(candidates.len() - 1),
+                    reply,
+                    html,
+                    error: None,
+                });
+            }
+            Err(e) => answers.push(Answer {
+                index: None,
+                reply: Reply {
+                    provider,
+                    model,
+                    text: String::new(),
+                    input_tokens: 0,
+                    output_tokens: 0,
+                    latency_ms: 0,
+                    cost: None,
+                    finish_reason: None,
+                    request_id: None,
+                    warnings: Vec::new(),
+                },
+                html: String::new(),
+                error: Some(e),
+            }),
+        }
+    }
+
+    // メッセージを履歴に追加
+    // すべて失敗したら、次の質問に送らないよう質問も取り消す
+    {
+        let mut mut_shelf = state.lock().unwrap();
+        if candidates.is_empty() {
+            let errors = answers
+                .iter()
+                .filter_map(|a| a.error.clone())
+                .collect::<Vec<String>>()
+                .join("\n");
+            mut_shelf.drop_unanswered();
+            return Err(errors);
+        }
+        mut_shelf.add_comparison(candidates)?;
+    }
+
+    Ok(answers)
+}
+
+/// 比較モードの回答から、履歴に採用するものを選ぶ
+#[tauri::command]
+pub fn choose_answer(
+    index: usize,
+    state: State<'_, Arc<Mutex<manage::message::Shelf>>>,
+) -> Result<String, String> {
+    let mut shelf = state.lock().unwrap();
+    let message = shelf.choose(index)?;
+
+    manage::utils::convert_markdown_to_html(message.content.as_str())
+}
@@ -0,0 +1,212 @@
+use serde::Serialize;
+use std::{
+    result::Result,
+    sync::{Arc, Mutex},
+};
+use tauri::State;
+
+use crate::manage::{
+    self,
+    message::Message,
+    provider::{self, Target},
+    variables,
+};
+use crate::sub;
+
+const MAX_ROUNDS: u8 = 5;
+
+/// 討論モードの結果
+/// turns は回答・批評・改訂の全発言、html は最終回答
+#[derive(Debug, Clone, Serialize)]
+pub struct Debate {
+    pub turns: Vec<Message>,
+    pub html: String,
+}
+
+/// 討論モード
+/// answerer が回答し、critic が批判的な立場(prompts::choose(4))で批評し、answerer が改訂する
+/// 批評と改訂を rounds 回繰り返し、最後の改訂を回答として返す
+/// 途中の発言は履歴に残すが、以降のリクエストには最終回答のみを送る
+#[tauri::command]
+pub async fn debate_request(
+    answerer: Target,
+    critic: Target,
+    rounds: u8,
+    msg: &str,
+    src: &str,
+    state: State<'_, Arc<Mutex<manage::message::Shelf>>>,
+) -> Result<Debate, String> {
+    let rounds = rounds.clamp(1, MAX_ROUNDS);
+    let (answer_provider, answer_model, answer_max_tokens) = answerer.resolve();
+    let (critic_provider, critic_model, critic_max_tokens) = critic.resolve();
+
+    // add new request message, and get message history
+    let (messages, system_prompt, critic_prompt, config) = {
+        let set_src = if src.is_empty() {
+            None
+        } else {
+            Some(src.to_string())
+        };
+        let mut mut_shelf = state.lock().unwrap();
+        // システムプロンプトだけを展開し、値のない変数があれば履歴に追加する前にエラーにする
+        let system_prompt =
+            variables::render_system(&mut_shelf.system_prompt(), answer_provider, &answer_model)?;
+        let critic_prompt =
+            variables::render_system(&sub::prompts::choose(4), critic_provider, &critic_model)?;
+        mut_shelf.add_to_messages("user".to_string(), msg.to_string(), set_src);
+
+        (
+            mut_shelf.context_messages(),
+            system_prompt,
+            critic_prompt,
+            mut_shelf.generation_config(None),
+        )
+    };
+
+    // 途中で失敗したら質問を履歴から除く (次の質問で user が続かないように)
+    let debated = async {
+        let mut turns = Vec::new();
+
+        // 最初の回答
+        let reply = provider::ask(
+            answer_provider,
+            &answer_model,
+            answer_max_tokens,
+            &messages,
+            &system_prompt,
+            &config,
+        )
+        .await?;
+        let mut answer = reply.text.clone();
+        turns.push(Message {
+            meta: Some(reply.meta()),
+            ..turn(&format!("answer {}", answerer.label()), &answer)
+        });
+
+        for round in 1..=rounds {
+            // 批評: 質問と現在の回答だけを渡す
+            let request = vec![Message {
+                role: "user".to_string(),
+                content: critique_request(msg, &answer),
+                ..Default::default()
+            }];
+            let reply = provider::ask(
+                critic_provider,
+                &critic_model,
+                critic_max_tokens,
+                &request,
+                &critic_prompt,
+                &config,
+            )
+            .await?;
+            let critique = reply.text.clone();
+            turns.push(Message {
+                meta: Some(reply.meta()),
+                ..turn(&format!("critique {} {}", round, critic.label()), &critique)
+            });
+
+            // 改訂: 元の履歴に回答と批評を続けて渡す
+            let mut request = messages.clone();
+            request.push(Message {
+                role: "assistant".to_string(),
+                content: answer.clone(),
+                ..Default::default()
+            });
+            request.push(Message {
+                role: "user".to_string(),
+                content: revision_request(&critique),
+                ..Default::default()
+            });
+            let reply = provider::ask(
+                answer_provider,
+                &answer_model,
+                answer_max_tokens,
+                &request,
+                &system_prompt,
+                &config,
+            )
+            .await?;
+            answer = reply.text.clone();
+            turns.push(Message {
+                meta: Some(reply.meta()),
+                ..turn(&format!("revision {} {}", round, answerer.label()), &answer)
+            });
+        }
+        Ok::<_, String>((turns, answer))
+    }
+    .await;
+    let (mut turns, answer) = match debated {
+        Ok(debated) => debated,
+        Err(e) => {
+            state.lock().unwrap().drop_unanswered();
+            return Err(e);
+        }
+    };
+
+    // 最終回答のみを文脈に残す
+    if let Some(last) = turns.last_mut() {
+        last.excluded = false;
+    }
+
+    // メッセージを履歴に追加
+    let voice = {
+        let mut mut_shelf = state.lock().unwrap();
+        mut_shelf.messages.messages.extend(turns.clone());
+        mut_shelf.voice()
+    };
+
+    manage::utils::say_as(answer.to_string(), voice);
+
+    let html = manage::utils::convert_markdown_to_html(answer.as_str())?;
+
+    Ok(Debate { turns, html })
+}
+
+fn turn(label: &str, content: &str) -> Message {
+    Message {
+        role: "assistant".to_string(),
+        content: content.to_string(),
+        label: Some(label.to_string()),
+        excluded: true,
+        ..Default::default()
+    }
+}
+
+fn critique_request(question: &str, answer: &str) -> String {
+    format!(
+        "次の質問と回答を批判的に検討し、誤り・不足・改善点を具体的に挙げてください。\n\n# 質問\n{}\n\n# 回答\n{}",
+        question, answer
+    )
+}
+
+fn revision_request(critique: &str) -> String {
+    format!(
+        "次の批評を踏まえて、先ほどの回答を改訂してください。改訂後の回答のみを出力してください。\n\n# 批評\n{}",
+        critique
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_turns_are_excluded_from_context() {
+        let message = turn("critique 1 gemini:gemini-2.0-flash", "too vague");
+        assert!(message.excluded);
+        assert_eq!(
+            message.speaker(),
+            "assistant [critique 1 gemini:gemini-2.0-flash]"
+        );
+    }
+
+    #[test]
+    fn test_requests_embed_question_answer_and_critique() {
+        let request = critique_request("what is rust?", "a language");
+        assert!(request.contains("what is rust?"));
+        assert!(request.contains("a language"));
+
+        let request = revision_request("add examples");
+        assert!(request.contains("add examples"));
+    }
+}
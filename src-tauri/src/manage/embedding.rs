@@ -0,0 +1,186 @@
+use candle_core::{Device, Tensor};
+use candle_nn::VarBuilder;
+use candle_transformers::models::bert::{BertModel, Config, DTYPE};
+use log::info;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::result::Result;
+use std::sync::{Arc, Mutex};
+use tokenizers::{PaddingParams, PaddingStrategy, Tokenizer, TruncationParams};
+
+use crate::manage::utils;
+
+// CPU で動く多言語の文埋め込みモデル (日本語と英語のどちらも意味で検索できる)
+// 初回に Hugging Face から取得し、Documents/.appdata/Talk with RustGPT/models に置く
+const REPO: &str = "sentence-transformers/paraphrase-multilingual-MiniLM-L12-v2";
+const FILES: [&str; 3] = ["config.json", "tokenizer.json", "model.safetensors"];
+// 埋め込みの次元数
+pub const DIMS: usize = 384;
+// 1チャンクのトークン数の上限。超えた分は埋め込みに含めない
+const MAX_TOKENS: usize = 256;
+// 1回の推論にまとめるテキスト数
+const BATCH_SIZE: usize = 16;
+
+/// 読み込んだモデル
+struct Model {
+    bert: BertModel,
+    tokenizer: Tokenizer,
+}
+
+// 読み込みは重いため、アプリ全体で1回だけにする
+static MODEL: Mutex<Option<Arc<Model>>> = Mutex::new(None);
+
+fn dir() -> Result<PathBuf, String> {
+    let name = REPO.rsplit('/').next().unwrap_or(REPO);
+    Ok(utils::app_data_dir()?.join("models").join(name))
+}
+
+/// モデルのファイルが無ければ取得する
+async fn download(dir: &Path) -> Result<(), String> {
+    std::fs::create_dir_all(dir).map_err(|e| format!("failed to create model dir: {}", e))?;
+    for file in FILES {
+        let path = dir.join(file);
+        if path.exists() {
+            continue;
+        }
+        let url = format!("https://huggingface.co/{}/resolve/main/{}", REPO, file);
+        info!("download embedding model file {}", url);
+        let mut res = reqwest::get(&url)
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(|e| format!("failed to download {}: {}", file, e.without_url()))?;
+
+        // 途中で止まっても壊れたファイルを使わないよう、書き終えてから名前を変える
+        let part = dir.join(format!("{}.part", file));
+        let mut out =
+            std::fs::File::create(&part).map_err(|e| format!("failed to write {}: {}", file, e))?;
+        while let Some(bytes) = res
+            .chunk()
+            .await
+            .map_err(|e| format!("failed to download {}: {}", file, e.without_url()))?
+        {
+            out.write_all(&bytes)
+                .map_err(|e| format!("failed to write {}: {}", file, e))?;
+        }
+        std::fs::rename(&part, &path).map_err(|e| format!("failed to write {}: {}", file, e))?;
+    }
+    Ok(())
+}
+
+fn load(dir: &Path) -> Result<Model, String> {
+    let config = std::fs::read_to_string(dir.join("config.json"))
+        .map_err(|e| format!("failed to read model config: {}", e))?;
+    let config: Config = serde_json::from_str(&config)
+        .map_err(|e| format!("failed to parse model config: {}", e))?;
+
+    let mut tokenizer = Tokenizer::from_file(dir.join("tokenizer.json"))
+        .map_err(|e| format!("failed to load tokenizer: {}", e))?;
+    // パディングは tokenizer.json の指定 (パディング用のトークン) を使い、バッチ内の最長にそろえる
+    let padding = tokenizer.get_padding().cloned().unwrap_or_default();
+    tokenizer.with_padding(Some(PaddingParams {
+        strategy: PaddingStrategy::BatchLongest,
+        ..padding
+    }));
+    tokenizer
+        .with_truncation(Some(TruncationParams {
+            max_length: MAX_TOKENS,
+            ..Default::default()
+        }))
+        .map_err(|e| format!("failed to load tokenizer: {}", e))?;
+
+    // SAFETY: 取得したファイルは読み込み中に書き換えない
+    let vb = unsafe {
+        VarBuilder::from_mmaped_safetensors(&[dir.join("model.safetensors")], DTYPE, &Device::Cpu)
+    }
+    .map_err(|e| format!("failed to load model: {}", e))?;
+    let bert = BertModel::load(vb, &config).map_err(|e| format!("failed to load model: {}", e))?;
+    Ok(Model { bert, tokenizer })
+}
+
+async fn model() -> Result<Arc<Model>, String> {
+    if let Some(model) = MODEL.lock().unwrap().clone() {
+        return Ok(model);
+    }
+    let dir = dir()?;
+    download(&dir).await?;
+    let model = tokio::task::spawn_blocking(move || load(&dir))
+        .await
+        .map_err(|e| format!("failed to load model: {}", e))??;
+    let model = Arc::new(model);
+    *MODEL.lock().unwrap() = Some(model.clone());
+    Ok(model)
+}
+
+impl Model {
+    fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>, String> {
+        let err = |e: candle_core::Error| format!("failed to embed: {}", e);
+        let encodings = self
+            .tokenizer
+            .encode_batch(texts.to_vec(), true)
+            .map_err(|e| format!("failed to tokenize: {}", e))?;
+        let ids = encodings
+            .iter()
+            .map(|e| Tensor::new(e.get_ids(), &Device::Cpu))
+            .collect::<candle_core::Result<Vec<Tensor>>>()
+            .and_then(|ids| Tensor::stack(&ids, 0))
+            .map_err(err)?;
+        let mask = encodings
+            .iter()
+            .map(|e| Tensor::new(e.get_attention_mask(), &Device::Cpu))
+            .collect::<candle_core::Result<Vec<Tensor>>>()
+            .and_then(|mask| Tensor::stack(&mask, 0))
+            .map_err(err)?;
+        let token_types = ids.zeros_like().map_err(err)?;
+
+        let hidden = self
+            .bert
+            .forward(&ids, &token_types, Some(&mask))
+            .map_err(err)?;
+        mean_pool(&hidden, &mask).map_err(err)
+    }
+}
+
+/// 文中のトークンの平均を、長さ 1 にそろえた文の埋め込みにする (パディングは除く)
+fn mean_pool(hidden: &Tensor, mask: &Tensor) -> candle_core::Result<Vec<Vec<f32>>> {
+    let mask = mask.to_dtype(DTYPE)?.unsqueeze(2)?;
+    let sum = hidden.broadcast_mul(&mask)?.sum(1)?;
+    let pooled = sum.broadcast_div(&mask.sum(1)?)?;
+    let norm = pooled.sqr()?.sum_keepdim(1)?.sqrt()?;
+    pooled.broadcast_div(&norm)?.to_vec2::<f32>()
+}
+
+/// テキストをまとめて埋め込む。初回はモデルの取得と読み込みに時間がかかる
+pub async fn embed(texts: &[String]) -> Result<Vec<Vec<f32>>, String> {
+    let model = model().await?;
+    let texts = texts.to_vec();
+    tokio::task::spawn_blocking(move || {
+        let mut vectors = Vec::new();
+        for batch in texts.chunks(BATCH_SIZE) {
+            vectors.extend(model.embed(batch)?);
+        }
+        Ok(vectors)
+    })
+    .await
+    .map_err(|e| format!("failed to embed: {}", e))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mean_pool_skips_padding_and_normalizes() {
+        // 2文 x 3トークン x 2次元。2文目の最後のトークンはパディング
+        let hidden = Tensor::new(
+            &[
+                [[1.0f32, 0.0], [3.0, 0.0], [2.0, 0.0]],
+                [[0.0, 2.0], [0.0, 4.0], [100.0, 0.0]],
+            ],
+            &Device::Cpu,
+        )
+        .unwrap();
+        let mask = Tensor::new(&[[1u32, 1, 1], [1, 1, 0]], &Device::Cpu).unwrap();
+        let vectors = mean_pool(&hidden, &mask).unwrap();
+        assert_eq!(vectors, vec![vec![1.0, 0.0], vec![0.0, 1.0]]);
+    }
+}
@@ -0,0 +1,389 @@
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fs::{create_dir_all, File};
+use std::io::Write;
+use std::path::PathBuf;
+use std::result::Result;
+use std::sync::Mutex;
+
+use log::info;
+
+use crate::manage::utils;
+
+const INDEX_FILE: &str = "index.json";
+
+static INDEX_LOCK: Mutex<()> = Mutex::new(());
+
+/// 生成画像1枚分の記録
+/// 画像本体は同じディレクトリの filename に保存される
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct GeneratedImage {
+    pub id: String,
+    pub created_at: String,
+    pub provider: String,
+    pub model: String,
+    // ユーザーが入力したプロンプト
+    pub prompt: String,
+    // API側で書き換えられたプロンプト
+    pub revised_prompt: String,
+    pub size: String,
+    pub filename: String,
+}
+
+/// 保存済みの画像と、その data URL
+#[derive(Debug, Clone)]
+pub struct SavedImage {
+    pub image: GeneratedImage,
+    pub url: String,
+}
+
+/// 生成画像の保存先
+/// Documents/.appdata/Talk with RustGPT/images
+pub struct Gallery {
+    dir: PathBuf,
+}
+
+impl Gallery {
+    pub fn open() -> Result<Self, String> {
+        let dir = utils::app_data_dir()?.join("images");
+        Ok(Self::at(dir))
+    }
+
+    pub fn at(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    pub fn path_of(&self, image: &GeneratedImage) -> PathBuf {
+        self.dir.join(&image.filename)
+    }
+
+    /// 新しい順で全件を返す
+    pub fn list(&self) -> Result<Vec<GeneratedImage>, String> {
+        let path = self.dir.join(INDEX_FILE);
+        if !path.exists() {
+            return Ok(Vec::new());
+        }
+
+        let data = std::fs::read_to_string(path)
+            .map_err(|e| format!("failed to read gallery index: {}", e))?;
+        let mut images: Vec<GeneratedImage> = serde_json::from_str(&data)
+            .map_err(|e| format!("failed to parse gallery index: {}", e))?;
+        images.reverse();
+        Ok(images)
+    }
+
+    /// プロンプトと書き換え後プロンプトを大文字小文字を区別せずに検索する
+    pub fn search(&self, query: &str) -> Result<Vec<GeneratedImage>, String> {
+        let query = query.to_lowercase();
+        let images = self.list()?;
+        Ok(images
+            .into_iter()
+            .filter(|image| {
+                image.prompt.to_lowercase().contains(&query)
+                    || image.revised_prompt.to_lowercase().contains(&query)
+            })
+            .collect())
+    }
+
+    pub fn get(&self, id: &str) -> Result<GeneratedImage, String> {
+        self.list()?
+            .into_iter()
+            .find(|image| image.id == id)
+            .ok_or(format!("image not found: {}", id))
+    }
+
+    /// 画像を書き出して索引に追加する
+    /// ext は保存ファイルの拡張子 (png, jpg, webp)
+    pub fn save(
+        &self,
+        mut image: GeneratedImage,
+        bytes: &[u8],
+        ext: &str,
+    ) -> Result<GeneratedImage, String> {
+        create_dir_all(self.dir.as_path())
+            .map_err(|e| format!("failed to create gallery dir: {}", e))?;
+
+        // 同時に保存しても索引の追記が失われないよう、読み込みから書き込みまでを排他にする
+        let _guard = INDEX_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut images = self.list()?;
+        images.reverse();
+
+        let now = chrono::Local::now();
+        image.id = format!("{}-{}", now.format("%Y%m%d%H%M%S%3f"), images.len());
+        image.created_at = now.to_rfc3339();
+        image.filename = format!("{}.{}", image.id, ext);
+
+        let full_path = self.path_of(&image);
+        info!("save image to {:?}", full_path);
+        let mut file =
+            File::create(full_path).map_err(|e| format!("failed to create file: {}", e))?;
+        file.write_all(bytes)
+            .map_err(|e| format!("failed to write file: {}", e))?;
+
+        images.push(image.clone());
+        let data = serde_json::to_string_pretty(&images)
+            .map_err(|e| format!("failed to serialize gallery index: {}", e))?;
+        std::fs::write(self.dir.join(INDEX_FILE), data)
+            .map_err(|e| format!("failed to write gallery index: {}", e))?;
+
+        Ok(image)
+    }
+
+    /// 複数枚をまとめて保存する
+    /// images は (revised_prompt, bytes, 拡張子)。template の provider/model/prompt/size を引き継ぐ
+    /// revised_prompt が空なら template のものを使う
+    pub fn save_all(
+        &self,
+        template: &GeneratedImage,
+        images: Vec<(String, Vec<u8>, String)>,
+    ) -> Result<Vec<SavedImage>, String> {
+        let mut saved = Vec::new();
+        for (revised_prompt, bytes, ext) in images {
+            let revised_prompt = if revised_prompt.is_empty() {
+                template.revised_prompt.clone()
+            } else {
+                revised_prompt
+            };
+            let image = self.save(
+                GeneratedImage {
+                    revised_prompt,
+                    ..template.clone()
+                },
+                &bytes,
+                &ext,
+            )?;
+            let url = self.to_data_url(&image)?;
+            saved.push(SavedImage { image, url });
+        }
+        Ok(saved)
+    }
+
+    /// 履歴に残す保存先のパス
+    /// Message.images に入れ、AIへ送る本文には含めない
+    pub fn paths(&self, saved: &[SavedImage]) -> Vec<String> {
+        saved
+            .iter()
+            .map(|s| self.path_of(&s.image).display().to_string())
+            .collect()
+    }
+
+    /// 保存済み画像を data URL として読み込む
+    /// フロントエンドの表示や、チャットへの添付(src)にそのまま使える
+    pub fn to_data_url(&self, image: &GeneratedImage) -> Result<String, String> {
+        let path = self.path_of(image);
+        let bytes = std::fs::read(&path).map_err(|e| format!("failed to read image: {}", e))?;
+        let mime = utils::get_file_type_by_extension(&image.filename).unwrap_or("image/png");
+        Ok(format!(
+            "data:{};base64,{}",
+            mime,
+            base64::engine::general_purpose::STANDARD.encode(bytes)
+        ))
+    }
+}
+
+/// MIME タイプから保存時の拡張子を決める
+pub fn extension_of(mime: &str) -> &str {
+    match mime {
+        "image/jpeg" => "jpg",
+        "image/webp" => "webp",
+        "image/gif" => "gif",
+        _ => "png",
+    }
+}
+
+/// 生成画像の回答として履歴に残す本文
+/// 書き換え後のプロンプトが無ければ枚数だけを書く
+pub fn revised_text(saved: &[SavedImage]) -> String {
+    let mut prompts = saved
+        .iter()
+        .map(|s| s.image.revised_prompt.as_str())
+        .filter(|p| !p.is_empty())
+        .collect::<Vec<&str>>();
+    // Gemini は全画像に同じ文章が付く
+    prompts.dedup();
+    if prompts.is_empty() {
+        format!("generated {} image(s)", saved.len())
+    } else {
+        prompts.join("\n\n")
+    }
+}
+
+/// invokeへの返り値
+/// 先頭画像の {id, prompt, url} と、全画像の images 配列
+pub fn to_response(saved: &[SavedImage]) -> String {
+    let images = saved
+        .iter()
+        .map(|s| {
+            json!({
+                "id": s.image.id,
+                "prompt": s.image.revised_prompt,
+                "url": s.url,
+            })
+        })
+        .collect::<Vec<Value>>();
+    let mut text = images.first().cloned().unwrap_or_default();
+    text["images"] = json!(images);
+
+    text.to_string()
+}
+
+/// 回答HTMLに画像をインライン表示するための img タグ
+/// data URL は ammonia に除去されるため、サニタイズ後に付け足す
+/// src は保存した画像から組み立てた data URL のみなので安全
+pub fn to_html(saved: &[SavedImage]) -> String {
+    saved
+        .iter()
+        .map(|s| format!("<p><img src=\"{}\" alt=\"{}\"></p>", s.url, s.image.id))
+        .collect::<String>()
+}
+
+#[tauri::command]
+pub fn list_images() -> Result<Vec<GeneratedImage>, String> {
+    Gallery::open()?.list()
+}
+
+#[tauri::command]
+pub fn search_images(query: &str) -> Result<Vec<GeneratedImage>, String> {
+    Gallery::open()?.search(query)
+}
+
+/// 過去の生成画像を開く
+/// 返り値の url は data URL なので、そのまま src としてチャットに添付できる
+#[tauri::command]
+pub fn open_image(id: &str) -> Result<String, String> {
+    let gallery = Gallery::open()?;
+    let image = gallery.get(id)?;
+    let url = gallery.to_data_url(&image)?;
+
+    Ok(json!({
+        "id": image.id,
+        "prompt": image.prompt,
+        "revised_prompt": image.revised_prompt,
+        "url": url,
+    })
+    .to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_gallery(name: &str) -> Gallery {
+        let dir = std::env::temp_dir().join(format!(
+            "talkwithrust-gallery-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        Gallery::at(dir)
+    }
+
+    #[test]
+    fn test_save_list_and_search() {
+        let gallery = temp_gallery("save");
+        assert!(gallery.list().unwrap().is_empty());
+
+        let first = gallery
+            .save(
+                GeneratedImage {
+                    provider: "openai".to_string(),
+                    model: "dall-e-3".to_string(),
+                    prompt: "a cute cat".to_string(),
+                    revised_prompt: "A fluffy ginger cat".to_string(),
+                    size: "1024x1024".to_string(),
+                    ..Default::default()
+                },
+                b"cat",
+                "png",
+            )
+            .unwrap();
+        gallery
+            .save(
+                GeneratedImage {
+                    prompt: "a dog".to_string(),
+                    ..Default::default()
+                },
+                b"dog",
+                "png",
+            )
+            .unwrap();
+
+        let images = gallery.list().unwrap();
+        assert_eq!(images.len(), 2);
+        // 新しい順
+        assert_eq!(images[0].prompt, "a dog");
+
+        let hits = gallery.search("FLUFFY").unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].id, first.id);
+
+        let url = gallery.to_data_url(&first).unwrap();
+        assert_eq!(url, "data:image/png;base64,Y2F0");
+    }
+
+    #[test]
+    fn test_concurrent_saves_keep_every_entry() {
+        let gallery = std::sync::Arc::new(temp_gallery("concurrent"));
+        let handles = (0..8)
+            .map(|i| {
+                let gallery = gallery.clone();
+                std::thread::spawn(move || {
+                    gallery
+                        .save(
+                            GeneratedImage {
+                                prompt: format!("image {}", i),
+                                ..Default::default()
+                            },
+                            b"x",
+                            "png",
+                        )
+                        .unwrap()
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(gallery.list().unwrap().len(), 8);
+    }
+
+    #[test]
+    fn test_save_all_and_response() {
+        let gallery = temp_gallery("save_all");
+        let template = GeneratedImage {
+            provider: "gemini".to_string(),
+            prompt: "a bird".to_string(),
+            revised_prompt: "Here is a small blue bird".to_string(),
+            ..Default::default()
+        };
+        let saved = gallery
+            .save_all(
+                &template,
+                vec![
+                    ("".to_string(), b"one".to_vec(), "png".to_string()),
+                    (
+                        "".to_string(),
+                        b"two".to_vec(),
+                        extension_of("image/jpeg").to_string(),
+                    ),
+                ],
+            )
+            .unwrap();
+        assert_eq!(saved.len(), 2);
+        assert_eq!(saved[0].image.provider, "gemini");
+        assert!(saved[1].image.filename.ends_with(".jpg"));
+        assert!(saved[1].url.starts_with("data:image/jpeg;base64,"));
+
+        let response: Value = serde_json::from_str(&to_response(&saved)).unwrap();
+        assert_eq!(response["id"], saved[0].image.id.as_str());
+        assert_eq!(response["images"].as_array().unwrap().len(), 2);
+
+        // 索引にも template の revised_prompt が書かれている
+        assert_eq!(gallery.search("blue bird").unwrap().len(), 2);
+        assert_eq!(revised_text(&saved), "Here is a small blue bird");
+        let paths = gallery.paths(&saved);
+        assert!(paths[0].ends_with(&saved[0].image.filename));
+        assert_eq!(to_html(&saved).matches("<img").count(), 2);
+    }
+}
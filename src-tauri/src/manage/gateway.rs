@@ -0,0 +1,848 @@
+use axum::{
+    extract::State as AxumState,
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use log::info;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    result::Result,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::sync::oneshot;
+
+use crate::manage::{
+    capabilities,
+    generation::GenerationConfig,
+    message::{self, Message},
+    provider::{self, Provider, Reply},
+};
+
+const DEFAULT_PORT: u16 = 8787;
+
+/// OpenAI 互換サーバーの設定
+/// token は Authorization: Bearer に必須。models が空なら全モデルを許可する
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct GatewayConfig {
+    #[serde(default)]
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub token: Option<String>,
+    #[serde(default)]
+    pub models: Vec<String>,
+    // 起動中の推定料金(USD)の上限
+    #[serde(default)]
+    pub max_cost: Option<f64>,
+}
+
+/// 起動中のサーバーの状態
+#[derive(Debug, Clone, Serialize)]
+pub struct GatewayStatus {
+    pub running: bool,
+    pub port: u16,
+    pub models: Vec<String>,
+    pub requests: u64,
+    pub spent: f64,
+    pub max_cost: Option<f64>,
+}
+
+#[derive(Debug, Default)]
+struct Usage {
+    requests: u64,
+    spent: f64,
+    // 応答待ちのリクエストの見積もり。同時に送られても上限を超えないよう先に押さえる
+    reserved: f64,
+}
+
+/// 送信前に押さえた見積もり。応答が来たか失敗したら戻す
+struct Reservation<'a> {
+    usage: &'a Mutex<Usage>,
+    amount: f64,
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        self.usage.lock().unwrap().reserved -= self.amount;
+    }
+}
+
+struct Gateway {
+    token: String,
+    models: Vec<String>,
+    max_cost: Option<f64>,
+    usage: Mutex<Usage>,
+}
+
+struct Running {
+    port: u16,
+    gateway: Arc<Gateway>,
+    shutdown: oneshot::Sender<()>,
+}
+
+// アプリ全体で1つだけ起動する
+static RUNNING: Mutex<Option<Running>> = Mutex::new(None);
+// bind を待つ間に2つ目の gateway_start が通らないようにする
+static STARTING: AtomicBool = AtomicBool::new(false);
+
+/// 起動処理が終わったら (失敗しても) STARTING を戻す
+struct Starting;
+
+impl Starting {
+    fn acquire() -> Result<Self, String> {
+        STARTING
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .map(|_| Starting)
+            .map_err(|_| "gateway is already starting".to_string())
+    }
+}
+
+impl Drop for Starting {
+    fn drop(&mut self) {
+        STARTING.store(false, Ordering::Release);
+    }
+}
+
+/// localhost に OpenAI 互換の /v1/chat/completions と /v1/models を立てる
+/// token を省略した場合は環境変数 GATEWAY_TOKEN を使う
+#[tauri::command]
+pub async fn gateway_start(config: GatewayConfig) -> Result<GatewayStatus, String> {
+    let _starting = Starting::acquire()?;
+    if RUNNING.lock().unwrap().is_some() {
+        return Err("gateway is already running".to_string());
+    }
+
+    let token = config
+        .token
+        .clone()
+        .filter(|t| !t.is_empty())
+        .or_else(|| std::env::var("GATEWAY_TOKEN").ok())
+        .filter(|t| !t.is_empty())
+        .ok_or("gateway token is required (config.token or GATEWAY_TOKEN)")?;
+    let port = config.port.unwrap_or(DEFAULT_PORT);
+
+    let gateway = Arc::new(Gateway {
+        token,
+        models: config.models.clone(),
+        max_cost: config.max_cost,
+        usage: Mutex::new(Usage::default()),
+    });
+
+    // 外部からは接続させない
+    let listener = tokio::net::TcpListener::bind(("127.0.0.1", port))
+        .await
+        .map_err(|e| format!("failed to bind 127.0.0.1:{}: {}", port, e))?;
+    let port = listener.local_addr().map(|a| a.port()).unwrap_or(port);
+
+    let app = router(gateway.clone());
+    let (shutdown, rx) = oneshot::channel::<()>();
+    tokio::spawn(async move {
+        let server = axum::serve(listener, app).with_graceful_shutdown(async {
+            rx.await.ok();
+        });
+        if let Err(e) = server.await {
+            log::error!("gateway error: {}", e);
+        }
+    });
+    info!("gateway started on 127.0.0.1:{}", port);
+
+    let mut running = RUNNING.lock().unwrap();
+    *running = Some(Running {
+        port,
+        gateway,
+        shutdown,
+    });
+
+    Ok(status(running.as_ref()))
+}
+
+#[tauri::command]
+pub fn gateway_stop() -> Result<String, String> {
+    match RUNNING.lock().unwrap().take() {
+        Some(running) => {
+            let _ = running.shutdown.send(());
+            info!("gateway stopped");
+            Ok("gateway stopped".to_string())
+        }
+        None => Err("gateway is not running".to_string()),
+    }
+}
+
+#[tauri::command]
+pub fn gateway_status() -> GatewayStatus {
+    status(RUNNING.lock().unwrap().as_ref())
+}
+
+fn status(running: Option<&Running>) -> GatewayStatus {
+    match running {
+        Some(running) => {
+            let usage = running.gateway.usage.lock().unwrap();
+            GatewayStatus {
+                running: true,
+                port: running.port,
+                models: running.gateway.models.clone(),
+                requests: usage.requests,
+                spent: usage.spent,
+                max_cost: running.gateway.max_cost,
+            }
+        }
+        None => GatewayStatus {
+            running: false,
+            port: 0,
+            models: Vec::new(),
+            requests: 0,
+            spent: 0.0,
+            max_cost: None,
+        },
+    }
+}
+
+fn router(gateway: Arc<Gateway>) -> Router {
+    Router::new()
+        .route("/v1/models", get(models))
+        .route("/v1/chat/completions", post(chat_completions))
+        .with_state(gateway)
+}
+
+async fn models(AxumState(gateway): AxumState<Arc<Gateway>>, headers: HeaderMap) -> Response {
+    if let Err(e) = gateway.authorize(&headers) {
+        return e.into_response();
+    }
+
+    let data = gateway
+        .listed_models()
+        .iter()
+        .map(|model| {
+            let owner = route(model).map(|(p, _)| p.name()).unwrap_or("unknown");
+            json!({"id": model, "object": "model", "created": 0, "owned_by": owner})
+        })
+        .collect::<Vec<Value>>();
+
+    Json(json!({"object": "list", "data": data})).into_response()
+}
+
+async fn chat_completions(
+    AxumState(gateway): AxumState<Arc<Gateway>>,
+    headers: HeaderMap,
+    Json(body): Json<Value>,
+) -> Response {
+    match gateway.complete(&headers, &body).await {
+        Ok(reply) => {
+            let id = format!("chatcmpl-{}", chrono::Local::now().timestamp_millis());
+            if body["stream"].as_bool().unwrap_or(false) {
+                (
+                    [(header::CONTENT_TYPE, "text/event-stream")],
+                    to_sse(&id, &reply),
+                )
+                    .into_response()
+            } else {
+                Json(to_completion(&id, &reply)).into_response()
+            }
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+/// OpenAI 形式のエラー
+#[derive(Debug)]
+struct ApiError {
+    status: StatusCode,
+    message: String,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let kind = match self.status {
+            StatusCode::UNAUTHORIZED => "authentication_error",
+            StatusCode::TOO_MANY_REQUESTS => "insufficient_quota",
+            s if s.is_client_error() => "invalid_request_error",
+            _ => "api_error",
+        };
+        let body = json!({"error": {"message": self.message, "type": kind, "code": null}});
+        (self.status, Json(body)).into_response()
+    }
+}
+
+impl Gateway {
+    fn authorize(&self, headers: &HeaderMap) -> Result<(), ApiError> {
+        let token = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+        match token {
+            Some(token) if same_token(token, &self.token) => Ok(()),
+            _ => Err(ApiError::new(
+                StatusCode::UNAUTHORIZED,
+                "invalid or missing bearer token",
+            )),
+        }
+    }
+
+    fn allows(&self, model: &str) -> bool {
+        self.models.is_empty() || self.models.iter().any(|m| m == model)
+    }
+
+    /// 許可リストがなければ各AIの既定モデル
+    fn listed_models(&self) -> Vec<String> {
+        if !self.models.is_empty() {
+            return self.models.clone();
+        }
+        // キーの無い AI は載せない
+        Provider::ALL
+            .iter()
+            .filter(|p| p.configured())
+            .flat_map(|p| [p.model(1).0, p.model(0).0])
+            .collect()
+    }
+
+    async fn complete(&self, headers: &HeaderMap, body: &Value) -> Result<Reply, ApiError> {
+        self.authorize(headers)?;
+
+        let model = body["model"]
+            .as_str()
+            .ok_or(ApiError::new(StatusCode::BAD_REQUEST, "model is required"))?;
+        if !self.allows(model) {
+            return Err(ApiError::new(
+                StatusCode::NOT_FOUND,
+                format!("model is not allowed: {}", model),
+            ));
+        }
+        let (provider, model) = route(model).ok_or(ApiError::new(
+            StatusCode::NOT_FOUND,
+            format!("unknown model: {}", model),
+        ))?;
+
+        if body["stream"].as_bool().unwrap_or(false) {
+            capabilities::check_stream(&model)
+                .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, e))?;
+        }
+
+        let (messages, system_prompt) =
+            to_messages(body).map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, e))?;
+        let max_tokens = body["max_completion_tokens"]
+            .as_u64()
+            .or(body["max_tokens"].as_u64())
+            .map(|n| n as u32)
+            .unwrap_or(provider.max_tokens(&model, provider.model(0).1));
+        let config = to_generation(body);
+        config
+            .validate(provider, &model)
+            .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, e))?;
+        let estimate = estimate_cost(&model, &messages, &system_prompt, max_tokens);
+        let _reserved = self.reserve(&model, estimate)?;
+
+        let reply = provider::ask(
+            provider,
+            &model,
+            max_tokens,
+            &messages,
+            &system_prompt,
+            &config,
+        )
+        .await
+        .map_err(|e| ApiError::new(StatusCode::BAD_GATEWAY, e))?;
+
+        {
+            let mut usage = self.usage.lock().unwrap();
+            usage.requests += 1;
+            usage.spent += reply.cost.or(estimate).unwrap_or(0.0);
+        }
+        info!(
+            "gateway: {}:{} in={} out={} {}ms cost={:?}",
+            reply.provider.name(),
+            reply.model,
+            reply.input_tokens,
+            reply.output_tokens,
+            reply.latency_ms,
+            reply.cost
+        );
+
+        Ok(reply)
+    }
+
+    /// 上限があれば、見積もりを押さえてから送る
+    /// 単価の分からないモデルは料金を数えられないため、上限があるときは使わせない
+    fn reserve(
+        &self,
+        model: &str,
+        estimate: Option<f64>,
+    ) -> Result<Option<Reservation<'_>>, ApiError> {
+        let Some(max_cost) = self.max_cost else {
+            return Ok(None);
+        };
+        let amount = estimate.ok_or(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            format!(
+                "{} has no known price: it cannot be used while a spending cap is set",
+                model
+            ),
+        ))?;
+        let mut usage = self.usage.lock().unwrap();
+        if usage.spent + usage.reserved + amount > max_cost {
+            return Err(ApiError::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                format!(
+                    "spending cap reached: ${:.4} of ${:.4} spent or reserved, this request may cost ${:.4}",
+                    usage.spent + usage.reserved,
+                    max_cost,
+                    amount
+                ),
+            ));
+        }
+        usage.reserved += amount;
+        Ok(Some(Reservation {
+            usage: &self.usage,
+            amount,
+        }))
+    }
+}
+
+/// 送る前の料金の見積もり (USD)。出力は最大トークン数まで使うものとする
+fn estimate_cost(
+    model: &str,
+    messages: &[Message],
+    system_prompt: &str,
+    max_tokens: u32,
+) -> Option<f64> {
+    let input = messages.iter().map(message::estimate_tokens).sum::<usize>()
+        + system_prompt.chars().count() / 2;
+    let output =
+        capabilities::lookup(model).map_or(max_tokens, |caps| max_tokens.min(caps.max_output));
+    provider::cost(model, input as u64, output as u64)
+}
+
+/// トークンを比べる時間から一致した長さが分からないようにする
+fn same_token(given: &str, expected: &str) -> bool {
+    let (given, expected) = (given.as_bytes(), expected.as_bytes());
+    if given.len() != expected.len() {
+        return false;
+    }
+    given
+        .iter()
+        .zip(expected)
+        .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
+
+/// モデル名から送信先を決める
+/// "claude:xxx" のように AI を明示するか、"claude" / "gpt" / "gemini" などの接頭辞で判定する
+/// AI 名だけなら既定の低価格モデル
+pub fn route(model: &str) -> Option<(Provider, String)> {
+    if let Some((name, model)) = model.split_once(':') {
+        let provider = Provider::from_name(name)?;
+        return Some((provider, model.to_string()));
+    }
+    if let Some(provider) = Provider::from_name(model) {
+        return Some((provider, provider.model(0).0));
+    }
+
+    let provider = if model.starts_with("claude") {
+        Provider::Claude
+    } else if model.starts_with("gemini") || model.starts_with("gemma") {
+        Provider::Gemini
+    } else if model.starts_with("gpt")
+        || model.starts_with("chatgpt")
+        || model.starts_with("o1")
+        || model.starts_with("o3")
+        || model.starts_with("o4")
+    {
+        Provider::ChatGPT
+    } else {
+        return None;
+    };
+    Some((provider, model.to_string()))
+}
+
+/// OpenAI 形式の messages を履歴とシステムプロンプトに変換する
+/// system / developer はまとめてシステムプロンプトにし、画像は最初の1枚を src にする
+pub fn to_messages(body: &Value) -> Result<(Vec<Message>, String), String> {
+    let items = body["messages"]
+        .as_array()
+        .ok_or("messages is required".to_string())?;
+
+    let mut messages = Vec::new();
+    let mut system = Vec::new();
+    for item in items {
+        let role = item["role"].as_str().unwrap_or_default();
+        let (content, src) = match &item["content"] {
+            Value::String(text) => (text.clone(), None),
+            Value::Array(parts) => {
+                let text = parts
+                    .iter()
+                    .filter_map(|p| p["text"].as_str())
+                    .collect::<Vec<&str>>()
+                    .join("\n");
+                let src = parts
+                    .iter()
+                    .filter_map(|p| p["image_url"]["url"].as_str())
+                    .find(|url| url.starts_with("data:"))
+                    .map(|url| url.to_string());
+                (text, src)
+            }
+            Value::Null => (String::new(), None),
+            _ => return Err(format!("unsupported content for role {}", role)),
+        };
+
+        match role {
+            "system" | "developer" => system.push(content),
+            "user" | "assistant" => messages.push(Message {
+                role: role.to_string(),
+                content,
+                src,
+                ..Default::default()
+            }),
+            _ => return Err(format!("unsupported role: {}", role)),
+        }
+    }
+
+    if messages.is_empty() {
+        return Err("no user messages".to_string());
+    }
+
+    Ok((messages, system.join("\n\n")))
+}
+
+/// OpenAI 形式のパラメータを生成パラメータにする
+/// n は回答を1件しか返さないので使わない
+/// response_format と tools はモデルが受け付けるか capabilities で確かめてから送る
+pub fn to_generation(body: &Value) -> GenerationConfig {
+    let stop = match &body["stop"] {
+        Value::String(stop) => vec![stop.clone()],
+        Value::Array(stops) => stops
+            .iter()
+            .filter_map(|s| s.as_str().map(|s| s.to_string()))
+            .collect(),
+        _ => Vec::new(),
+    };
+    GenerationConfig {
+        temperature: body["temperature"].as_f64(),
+        top_p: body["top_p"].as_f64(),
+        stop,
+        seed: body["seed"].as_i64(),
+        json_mode: body["response_format"]["type"] == "json_object",
+        tools: body["tools"].as_array().cloned().unwrap_or_default(),
+        ..Default::default()
+    }
+}
+
+fn to_usage(reply: &Reply) -> Value {
+    json!({
+        "prompt_tokens": reply.input_tokens,
+        "completion_tokens": reply.output_tokens,
+        "total_tokens": reply.input_tokens + reply.output_tokens,
+    })
+}
+
+/// 各APIの終了理由を OpenAI の finish_reason に合わせる
+fn to_finish_reason(reply: &Reply) -> &'static str {
+    match reply.finish_reason.as_deref() {
+        Some("max_tokens" | "length" | "MAX_TOKENS") => "length",
+        Some("content_filter" | "refusal" | "SAFETY" | "RECITATION" | "BLOCKLIST") => {
+            "content_filter"
+        }
+        _ => "stop",
+    }
+}
+
+/// chat.completion 形式の応答
+pub fn to_completion(id: &str, reply: &Reply) -> Value {
+    json!({
+        "id": id,
+        "object": "chat.completion",
+        "created": chrono::Local::now().timestamp(),
+        "model": reply.model,
+        "choices": [{
+            "index": 0,
+            "message": {"role": "assistant", "content": reply.text},
+            "finish_reason": to_finish_reason(reply),
+        }],
+        "usage": to_usage(reply),
+    })
+}
+
+/// stream: true 用の SSE
+/// 各 API へは一括で問い合わせるため、回答を段落ごとの chunk に分けて返す
+pub fn to_sse(id: &str, reply: &Reply) -> String {
+    let created = chrono::Local::now().timestamp();
+    let chunk = |delta: Value, finish_reason: Value| {
+        json!({
+            "id": id,
+            "object": "chat.completion.chunk",
+            "created": created,
+            "model": reply.model,
+            "choices": [{"index": 0, "delta": delta, "finish_reason": finish_reason}],
+        })
+    };
+
+    let mut chunks = vec![chunk(
+        json!({"role": "assistant", "content": ""}),
+        Value::Null,
+    )];
+    for part in reply.text.split_inclusive("\n\n") {
+        chunks.push(chunk(json!({"content": part}), Value::Null));
+    }
+    let mut last = chunk(json!({}), json!(to_finish_reason(reply)));
+    last["usage"] = to_usage(reply);
+    chunks.push(last);
+
+    let mut sse = chunks
+        .iter()
+        .map(|c| format!("data: {}\n\n", c))
+        .collect::<String>();
+    sse.push_str("data: [DONE]\n\n");
+    sse
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reply(text: &str) -> Reply {
+        Reply {
+            provider: Provider::Claude,
+            model: "claude-3-5-haiku-latest".to_string(),
+            text: text.to_string(),
+            input_tokens: 10,
+            output_tokens: 5,
+            latency_ms: 0,
+            cost: None,
+            finish_reason: Some("end_turn".to_string()),
+            request_id: None,
+            warnings: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_route_models() {
+        assert_eq!(
+            route("claude-3-7-sonnet-latest"),
+            Some((Provider::Claude, "claude-3-7-sonnet-latest".to_string()))
+        );
+        assert_eq!(route("gpt-4o-mini").unwrap().0, Provider::ChatGPT);
+        assert_eq!(route("o3-mini").unwrap().0, Provider::ChatGPT);
+        assert_eq!(route("gemini-2.0-flash").unwrap().0, Provider::Gemini);
+        assert_eq!(
+            route("openai:my-finetune"),
+            Some((Provider::ChatGPT, "my-finetune".to_string()))
+        );
+        assert_eq!(route("gemini").unwrap().1, Provider::Gemini.model(0).0);
+        assert!(route("llama-3").is_none());
+        assert!(route("mistral:large").is_none());
+    }
+
+    #[test]
+    fn test_to_messages_splits_system_and_images() {
+        let body = json!({
+            "messages": [
+                {"role": "system", "content": "be brief"},
+                {"role": "developer", "content": "use japanese"},
+                {"role": "user", "content": [
+                    {"type": "text", "text": "what is this?"},
+                    {"type": "image_url", "image_url": {"url": "data:image/png;base64,AAAA"}}
+                ]},
+                {"role": "assistant", "content": "a cat"},
+                {"role": "user", "content": "sure?"}
+            ]
+        });
+        let (messages, system) = to_messages(&body).unwrap();
+        assert_eq!(system, "be brief\n\nuse japanese");
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[0].content, "what is this?");
+        assert_eq!(
+            messages[0].src.as_deref(),
+            Some("data:image/png;base64,AAAA")
+        );
+        assert_eq!(messages[2].role, "user");
+
+        let body = json!({"messages": [{"role": "tool", "content": "x"}]});
+        assert!(to_messages(&body).is_err());
+        assert!(to_messages(&json!({})).is_err());
+    }
+
+    #[test]
+    fn test_to_generation() {
+        let body = json!({"temperature": 0.3, "stop": "END", "seed": 7, "n": 3});
+        let config = to_generation(&body);
+        assert_eq!(config.temperature, Some(0.3));
+        assert_eq!(config.stop, vec!["END"]);
+        assert_eq!(config.seed, Some(7));
+        assert_eq!(config.candidates, None);
+        assert!(config
+            .validate(Provider::Claude, "claude-3-5-haiku")
+            .is_err());
+
+        let body = json!({
+            "messages": [{"role": "user", "content": "hi"}],
+            "response_format": {"type": "json_object"},
+            "tools": [{"type": "function", "function": {"name": "now"}}],
+        });
+        let (messages, _) = to_messages(&body).unwrap();
+        let config = to_generation(&body);
+        assert!(config.json_mode);
+        let err = provider::prepare(
+            Provider::ChatGPT,
+            "o1-mini",
+            1000,
+            messages.clone(),
+            "",
+            &config,
+        )
+        .unwrap_err();
+        assert!(err.contains("does not support tools"));
+        let (body, warnings) = provider::prepare(
+            Provider::Claude,
+            "claude-sonnet-4-5",
+            1000,
+            messages,
+            "",
+            &config,
+        )
+        .unwrap();
+        assert!(body.get("response_format").is_none());
+        assert!(warnings[0].contains("no JSON mode"));
+    }
+
+    #[test]
+    fn test_authorize_and_allowlist() {
+        let gateway = Gateway {
+            token: "secret".to_string(),
+            models: vec!["gpt-4o-mini".to_string()],
+            max_cost: None,
+            usage: Mutex::new(Usage::default()),
+        };
+        let mut headers = HeaderMap::new();
+        assert!(gateway.authorize(&headers).is_err());
+        headers.insert(header::AUTHORIZATION, "Bearer wrong".parse().unwrap());
+        assert!(gateway.authorize(&headers).is_err());
+        headers.insert(header::AUTHORIZATION, "Bearer secret".parse().unwrap());
+        assert!(gateway.authorize(&headers).is_ok());
+        headers.insert(header::AUTHORIZATION, "Bearer secreT".parse().unwrap());
+        assert!(gateway.authorize(&headers).is_err());
+        assert!(!same_token("secret", "secret2"));
+
+        assert!(gateway.allows("gpt-4o-mini"));
+        assert!(!gateway.allows("claude-3-7-sonnet-latest"));
+        assert_eq!(gateway.listed_models(), vec!["gpt-4o-mini".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_stream_needs_a_streaming_model() {
+        let gateway = Gateway {
+            token: "secret".to_string(),
+            models: Vec::new(),
+            max_cost: None,
+            usage: Mutex::new(Usage::default()),
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, "Bearer secret".parse().unwrap());
+        let body = json!({
+            "model": "o3-pro",
+            "stream": true,
+            "messages": [{"role": "user", "content": "hi"}]
+        });
+        let e = gateway.complete(&headers, &body).await.unwrap_err();
+        assert_eq!(e.status, StatusCode::BAD_REQUEST);
+        assert!(e.message.contains("does not stream"));
+    }
+
+    #[test]
+    fn test_spending_cap_reserves_before_sending() {
+        let gateway = Gateway {
+            token: "secret".to_string(),
+            models: Vec::new(),
+            max_cost: Some(1.0),
+            usage: Mutex::new(Usage::default()),
+        };
+        let messages = vec![Message {
+            role: "user".to_string(),
+            content: "hi".to_string(),
+            ..Default::default()
+        }];
+        assert!(estimate_cost("gpt-4o-mini", &messages, "", 1000).is_some());
+        assert!(estimate_cost("gpt-4-1106-preview", &messages, "", 1000).is_none());
+
+        // 単価の分からないモデルは上限を守れないので断る
+        let e = gateway.reserve("gpt-4-1106-preview", None).err().unwrap();
+        assert_eq!(e.status, StatusCode::BAD_REQUEST);
+
+        // 応答待ちの見積もりも上限に含める
+        let first = gateway.reserve("gpt-4o-mini", Some(0.6)).unwrap();
+        let e = gateway.reserve("gpt-4o-mini", Some(0.6)).err().unwrap();
+        assert_eq!(e.status, StatusCode::TOO_MANY_REQUESTS);
+        drop(first);
+        assert_eq!(gateway.usage.lock().unwrap().reserved, 0.0);
+        assert!(gateway.reserve("gpt-4o-mini", Some(0.6)).is_ok());
+
+        // 上限が無ければ単価が無くても通す
+        let free = Gateway {
+            max_cost: None,
+            ..gateway
+        };
+        assert!(free.reserve("gpt-4-1106-preview", None).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_starting_blocks_a_second_start() {
+        let first = Starting::acquire().unwrap();
+        assert!(Starting::acquire().is_err());
+        drop(first);
+        assert!(Starting::acquire().is_ok());
+    }
+
+    #[test]
+    fn test_completion_and_sse_format() {
+        let reply = reply("first\n\nsecond");
+        let completion = to_completion("chatcmpl-1", &reply);
+        assert_eq!(
+            completion["choices"][0]["message"]["content"],
+            "first\n\nsecond"
+        );
+        assert_eq!(completion["usage"]["total_tokens"], 15);
+        assert_eq!(completion["choices"][0]["finish_reason"], "stop");
+
+        let mut truncated = reply.clone();
+        truncated.finish_reason = Some("max_tokens".to_string());
+        let completion = to_completion("chatcmpl-1", &truncated);
+        assert_eq!(completion["choices"][0]["finish_reason"], "length");
+
+        let sse = to_sse("chatcmpl-1", &reply);
+        assert!(sse.ends_with("data: [DONE]\n\n"));
+        let events = sse
+            .split("\n\n")
+            .filter(|e| !e.is_empty())
+            .map(|e| e.strip_prefix("data: ").unwrap())
+            .collect::<Vec<&str>>();
+        // role, 2 段落, finish, [DONE]
+        assert_eq!(events.len(), 5);
+        assert_eq!(events[4], "[DONE]");
+        let chunks = events[..4]
+            .iter()
+            .map(|e| serde_json::from_str::<Value>(e).unwrap())
+            .collect::<Vec<Value>>();
+        assert!(chunks
+            .iter()
+            .all(|c| c["object"] == "chat.completion.chunk" && c["id"] == "chatcmpl-1"));
+        assert_eq!(chunks[0]["choices"][0]["delta"]["role"], "assistant");
+        let text = chunks[1..3]
+            .iter()
+            .map(|c| c["choices"][0]["delta"]["content"].as_str().unwrap())
+            .collect::<String>();
+        assert_eq!(text, "first\n\nsecond");
+        assert_eq!(chunks[3]["choices"][0]["finish_reason"], "stop");
+        assert_eq!(chunks[3]["usage"]["total_tokens"], 15);
+    }
+}
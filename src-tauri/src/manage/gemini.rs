@@ -1,7 +1,13 @@
-use crate::manage::{self, message::Message, utils};
+use crate::manage::{
+    self, capabilities,
+    gallery::{self, Gallery, GeneratedImage, SavedImage},
+    generation::{self, GenerationConfig},
+    message::Message,
+    provider::{self, Provider, Target},
+    settings, transport, utils, variables,
+};
 
-use super::utils::get_env;
-use reqwest::Client;
+use base64::Engine;
 use serde_json::{json, Value};
 use std::{
     result::Result,
@@ -12,122 +18,285 @@ use tauri::State;
 #[tauri::command]
 pub async fn gemini_request(
     b: u8,
+    model: Option<String>,
     msg: &str,
     src: &str,
+    config: Option<GenerationConfig>,
     state: State<'_, Arc<Mutex<manage::message::Shelf>>>,
-) -> Result<String, String> {
+) -> Result<utils::ChatResponse, String> {
     let start_time = chrono::Local::now();
 
-    let (high, low) = model();
-    let (set_model, _max_tokens) = if b == 1 {
-        (high.as_str(), 8192)
-    } else {
-        (low.as_str(), 8192)
-    };
+    // キーが無ければ、履歴に追加する前にエラーにする
+    transport::key(Provider::Gemini)?;
+
+    // model があれば b より優先する
+    let (_, set_model, max_tokens) = Target {
+        provider: Provider::Gemini,
+        b,
+        model,
+    }
+    .resolve();
+    let set_model = set_model.as_str();
 
     // add new request message, and get message history
-    let (messages, system_prompt) = {
+    let (mut messages, system_prompt, rag, config) = {
         let set_src = if src.is_empty() {
             None
         } else {
             Some(src.to_string())
         };
         let mut mut_shelf = state.lock().unwrap();
+        // 生成パラメータが上限を超えていれば、履歴に追加する前にエラーにする
+        let config = mut_shelf.generation_config(config.as_ref());
+        config.validate(Provider::Gemini, set_model)?;
+        // 添付を読めないモデルなら、履歴に追加する前にエラーにする
+        capabilities::check_attachment(Provider::Gemini, set_model, set_src.as_deref())?;
+        // システムプロンプトの {user} や {date} などの変数を展開する
+        // 値のない変数があれば、履歴に追加する前にエラーにする
+        // 質問はコードなどの { } をそのまま送るため展開しない
+        let system_prompt =
+            variables::render_system(&mut_shelf.system_prompt(), Provider::Gemini, set_model)?;
         mut_shelf.add_to_messages("user".to_string(), msg.to_string(), set_src);
 
-        let guard_shelf = mut_shelf.clone();
+        (
+            mut_shelf.context_messages(),
+            system_prompt,
+            mut_shelf.rag.clone(),
+            config,
+        )
+    };
 
-        let system_prompts = mut_shelf.system_messages.get();
-        let system_prompt = if !system_prompts.is_empty() {
-            // 最期の配列をStringで出力
-            let prompt = system_prompts.last().unwrap();
-            prompt.content.to_string()
-        } else {
-            "".to_string()
+    // 送れなかった質問は履歴から除く (次の質問で user が続かないように)
+    let sent = async {
+        // 索引が設定されていれば、質問に資料を添える
+        let citations = match &rag {
+            Some(name) => manage::rag::augment(name, &mut messages).await?,
+            None => Vec::new(),
         };
 
-        (guard_shelf.get_messages(), system_prompt)
+        // request
+        let max_tokens = config.max_tokens.unwrap_or(max_tokens);
+        // モデルの性能に合わせる。読めない添付などは送信前にエラー
+        let (body, adapted) = provider::prepare(
+            Provider::Gemini,
+            set_model,
+            max_tokens,
+            messages,
+            &system_prompt,
+            &config,
+        )?;
+        let res = inner(set_model, body)
+            .await
+            .map_err(|e| format!("Request error: {}", e))?;
+        Ok::<_, String>((citations, adapted, res))
+    }
+    .await;
+    let (citations, adapted, res) = match sent {
+        Ok(sent) => sent,
+        Err(e) => {
+            state.lock().unwrap().drop_unanswered();
+            return Err(e);
+        }
     };
 
-    // request
-    let body = if system_prompt.is_empty() {
-        json!({
-            "contents": messages.iter().map(|m| {
-                json!({
-                    // roleがuserの場合はuser、それ以外はmodel as assistant
-                    "role": if m.role == "user" { "user" } else { "model" },
-                    "parts": to_content(m.clone()),
-                })
-            }).collect::<Vec<_>>(),
-        })
-    } else {
-        // println!("systemInstruction: {}", system_prompt);
-        json!({
-            "systemInstruction": {
-                "parts": [
-                    {
-                        "text": system_prompt,
-                    }
-                ],
-            },
-            "contents": messages.iter().map(|m| {
-                json!({
-                    "role": if m.role == "user" { "user" } else { "model" },
-                    "parts": to_content(m.clone()),
-                })
-            }).collect::<Vec<_>>(),
-        })
-    };
-    let res = match inner(set_model, body).await {
-        Ok(res) => res,
-        Err(e) => return Err(format!("Request error: {}", e)),
+    // get response message, images, token count and finish reason
+    let mut meta = provider::meta(Provider::Gemini, set_model, &res, start_time);
+    meta.warnings.extend(adapted);
+    let (text, images) = match manage::utils::get_parts_for_gemini(&res) {
+        Ok((text, images, _)) => (text, images),
+        Err(e) => {
+            meta.warnings.push(e.clone());
+            (format!("Error: {}", e), Vec::new())
+        }
     };
 
-    // get response message and token count
-    let (text, token_count) = match manage::utils::get_content_for_gemini(&res) {
-        Ok(v) => v,
-        Err(e) => (format!("Error: {}", e), 0),
+    // 画像が含まれていればギャラリーに保存する
+    // 保存できなければ、送れなかったときと同じく質問を履歴から除く
+    let stored = Gallery::open().and_then(|gallery| {
+        let saved = save_images(&gallery, set_model, msg, &text, images)?;
+        Ok((gallery, saved))
+    });
+    let (gallery, saved) = match stored {
+        Ok(stored) => stored,
+        Err(e) => {
+            state.lock().unwrap().drop_unanswered();
+            return Err(e);
+        }
     };
 
     // メッセージを履歴に追加
-    {
+    // 画像の保存先は本文とは別に残す
+    let voice = {
         let mut mut_shelf = state.lock().unwrap();
-        mut_shelf.add_to_messages("assistant".to_string(), text.clone(), None);
-    }
+        let texts = generation::candidate_texts(Provider::Gemini, &res);
+        if !saved.is_empty() {
+            mut_shelf.add_images(text.clone(), gallery.paths(&saved), Some(meta.clone()));
+        } else if texts.len() > 1 {
+            if let Err(e) = mut_shelf.add_candidates(texts, meta.clone()) {
+                mut_shelf.drop_unanswered();
+                return Err(e);
+            }
+        } else {
+            mut_shelf.add_answer(text.clone(), meta.clone());
+        }
+        mut_shelf.voice()
+    };
 
-    manage::utils::say(text.to_string());
+    manage::utils::say_as(text.to_string(), voice);
 
     let markdown_content = manage::utils::convert_markdown_to_html(text.as_str())?;
+    let markdown_content = format!(
+        "{}{}",
+        markdown_content,
+        manage::rag::citations_html(&citations)
+    );
+    let markdown_content = format!("{}{}", markdown_content, gallery::to_html(&saved));
 
     Ok(manage::utils::create_response(
-        markdown_content.as_str(),
-        set_model,
-        token_count,
-        start_time,
+        &markdown_content,
+        &text,
+        meta,
     ))
 }
 
-pub fn model() -> (String, String) {
-    let (mut high_model, mut low_model) = utils::model_high_and_low("GEMINI_MODELS");
-    high_model = if high_model.is_empty() {
-        String::from("gemini-2.0-flash")
+/// Gemini で画像を生成する
+/// imagen-* は predict、それ以外は generateContent の画像出力を使う
+/// model 省略時は GEMINI_IMAGE_MODEL、未設定なら gemini-2.0-flash-preview-image-generation
+#[tauri::command]
+pub async fn gemini_request_to_image(
+    msg: &str,
+    model: Option<String>,
+    state: State<'_, Arc<Mutex<manage::message::Shelf>>>,
+) -> Result<String, String> {
+    let set_model = model.filter(|m| !m.is_empty()).unwrap_or(image_model());
+
+    let (text, images) = if set_model.starts_with("imagen") {
+        let body = json!({
+            "instances": [{ "prompt": msg }],
+            "parameters": { "sampleCount": 1 },
+        });
+        let res = match request(&set_model, "predict", body).await {
+            Ok(res) => res,
+            Err(e) => return Err(format!("Request error: {}", e)),
+        };
+        let images = manage::utils::get_images_for_imagen(&res)?;
+        (String::new(), images)
     } else {
-        high_model
+        let body = json!({
+            "contents": [{
+                "role": "user",
+                "parts": [{ "text": msg }],
+            }],
+            "generationConfig": {
+                "responseModalities": ["TEXT", "IMAGE"],
+            },
+        });
+        let res = match inner(&set_model, body).await {
+            Ok(res) => res,
+            Err(e) => return Err(format!("Request error: {}", e)),
+        };
+        let (text, images, _) = manage::utils::get_parts_for_gemini(&res)?;
+        (text, images)
     };
-    low_model = if low_model.is_empty() {
-        String::from("gemini-2.0-flash")
-    } else {
-        low_model
+
+    if images.is_empty() {
+        return Err(format!("no image generated: {}", text));
+    }
+
+    // 添えられた文章は索引にも残し、ギャラリーの検索で見つかるようにする
+    let gallery = Gallery::open()?;
+    let saved = save_images(&gallery, &set_model, msg, &text, images)?;
+
+    // 履歴とメモに残るよう、保存先は本文とは別に追加する
+    {
+        let mut mut_shelf = state.lock().unwrap();
+        mut_shelf.add_to_messages("user".to_string(), msg.to_string(), None);
+        mut_shelf.add_images(gallery::revised_text(&saved), gallery.paths(&saved), None);
+    }
+
+    Ok(gallery::to_response(&saved))
+}
+
+/// (MIME, base64) の画像をギャラリーに保存する
+/// text は画像と一緒に返った文章で、revised_prompt として索引に書く
+fn save_images(
+    gallery: &Gallery,
+    model: &str,
+    prompt: &str,
+    text: &str,
+    images: Vec<utils::InlineImage>,
+) -> Result<Vec<SavedImage>, String> {
+    if images.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut decoded = Vec::new();
+    for (mime, data) in images {
+        let bytes = base64::engine::general_purpose::STANDARD
+            .decode(data)
+            .map_err(|e| format!("base64 decode error: {}", e))?;
+        decoded.push((
+            String::new(),
+            bytes,
+            gallery::extension_of(&mime).to_string(),
+        ));
+    }
+
+    let template = GeneratedImage {
+        provider: "gemini".to_string(),
+        model: model.to_string(),
+        prompt: prompt.to_string(),
+        revised_prompt: text.to_string(),
+        ..Default::default()
     };
+    gallery.save_all(&template, decoded)
+}
+
+pub fn image_model() -> String {
+    settings::current()
+        .gemini_image_model
+        .unwrap_or(String::from("gemini-2.0-flash-preview-image-generation"))
+}
+
+/// 高性能と低価格のモデル
+/// 設定 (GEMINI_MODELS で上書き) が無ければ既定のモデル
+pub fn model() -> (String, String) {
+    let (high, low) = settings::current().models(Provider::Gemini);
+    (
+        high.unwrap_or(String::from("gemini-2.5-pro")),
+        low.unwrap_or(String::from("gemini-2.5-flash")),
+    )
+}
 
-    (high_model, low_model)
+/// リクエストボディを作成する
+/// システムプロンプトは systemInstruction に設定する
+pub fn to_body(_model: &str, max_tokens: u32, messages: &[Message], system_prompt: &str) -> Value {
+    let mut body = json!({
+        "contents": messages.iter().map(|m| {
+            json!({
+                // roleがuserの場合はuser、それ以外はmodel as assistant
+                "role": if m.role == "user" { "user" } else { "model" },
+                "parts": to_content(m.clone()),
+            })
+        }).collect::<Vec<_>>(),
+        "generationConfig": {
+            "maxOutputTokens": max_tokens,
+        },
+    });
+    if !system_prompt.is_empty() {
+        body["systemInstruction"] = json!({
+            "parts": [
+                {
+                    "text": system_prompt,
+                }
+            ],
+        });
+    }
+    body
 }
 
 pub fn to_content(message: Message) -> Value {
-    if message.src.is_none() {
-        json!([{ "text": message.content }])
-    } else {
-        let src = message.src.unwrap();
+    if let Some(src) = message.src {
         let media_type = if src.contains("data:image/png") {
             "image/png"
         } else {
@@ -146,36 +315,26 @@ pub fn to_content(message: Message) -> Value {
                 "data": src,
             }
         }])
+    } else {
+        json!([{ "text": message.content }])
     }
 }
+
 pub async fn inner(model: &str, body: Value) -> Result<Value, String> {
-    let keys = get_env().await.unwrap();
-    // println!("keys: {:?}", keys);
+    request(model, "generateContent", body).await
+}
+
+/// models/{model}:{method} にリクエストする
+pub async fn request(model: &str, method: &str, body: Value) -> Result<Value, String> {
+    let key = transport::key(Provider::Gemini)?;
 
     let url = format!(
-        "https://generativelanguage.googleapis.com/v1beta/models/{}:generateContent?key={}",
-        model, keys.google_key
+        "https://generativelanguage.googleapis.com/v1beta/models/{}:{}?key={}",
+        model, method, key
     );
 
     // リクエストを送信
-    let client = Client::new();
-    let res = match client
-        .post(url)
-        .header("content-type", "application/json")
-        .json(&body)
-        .send()
-        .await
-    {
-        Ok(response) => response,
-        Err(err) => {
-            return Err(format!("Request error: {}", err));
-        }
-    };
-
-    match res.json().await {
-        Ok(json) => Ok(json),
-        Err(err) => Err(format!("JSON parse error: {}", err)),
-    }
+    transport::post_json(&url, &[], &body).await
 }
 
 #[cfg(test)]
@@ -188,20 +347,21 @@ mod tests {
     use base64::Engine;
     use serde_json::json;
 
+    // 1x1 の PNG
+    const PNG: &str = "iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAYAAAAfFcSJAAAADUlEQVR42mP8z8BQDwAEhQGAhKmMIQAAAABJRU5ErkJggg==";
+
+    // tests/cassettes/gemini.json を再生する
     #[tokio::test]
     async fn test_request() {
-        dotenv::dotenv().ok();
-
         let use_model = "gemini-1.5-flash";
 
-        let filepath = Path::new(r"D:\Download\evangelion_tv4_title.png");
+        let filepath = Path::new("title.png");
         let data_type =
             utils::get_file_type_by_extension(filepath.file_name().unwrap().to_str().unwrap())
                 .unwrap();
-        let file_data = match std::fs::read(filepath) {
-            Ok(data) => data,
-            Err(e) => panic!("Failed to read file: {}", e),
-        };
+        let file_data = base64::engine::general_purpose::STANDARD
+            .decode(PNG)
+            .unwrap();
 
         let data = base64::engine::general_purpose::STANDARD.encode(file_data);
         // 最初の20文字だけ表示
@@ -225,7 +385,8 @@ mod tests {
             }]
         });
 
-        let res = inner(use_model, body).await;
+        let mode = transport::cassette_mode("gemini");
+        let res = transport::scope(mode, inner(use_model, body)).await;
         match res {
             Ok(value) => {
                 let (text, tokens) = match get_content_for_gemini(&value) {
@@ -234,12 +395,8 @@ mod tests {
                 };
                 println!("tokens: {}", tokens);
                 println!("response: {:?}", text);
-                // to temp file
-                let path = Path::new(r"D:\Download\response.txt");
-                match std::fs::write(path, value.to_string()) {
-                    Ok(_) => println!("Wrote to file: {:?}", path),
-                    Err(e) => panic!("Failed to write file: {}", e),
-                }
+                assert!(text.contains("東京"));
+                assert_eq!(tokens, 318);
             }
             Err(e) => panic!("Failed to request: {}", e),
         };
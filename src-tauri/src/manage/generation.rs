@@ -0,0 +1,434 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fs::create_dir_all;
+use std::path::PathBuf;
+use std::result::Result;
+use std::sync::{Arc, Mutex};
+
+use tauri::State;
+
+use crate::manage::{self, capabilities, persona::Persona, provider::Provider, utils};
+
+const FILE: &str = "generation.toml";
+
+/// 生成のパラメータ
+/// 全体 → ペルソナ → セッション → リクエストの順に、指定のあるものを上書きする
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct GenerationConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+    // 回答の候補数。2以上なら残りは比較モードと同じく候補として保持する
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub candidates: Option<u32>,
+    // JSON だけを返させる
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub json_mode: bool,
+    // ツールの定義 (OpenAI の形式)。各 API の形式に直して送る
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tools: Vec<Value>,
+}
+
+/// API ごとの上限
+struct Limits {
+    temperature: f64,
+    stop: usize,
+    candidates: u32,
+    seed: bool,
+}
+
+fn limits(provider: Provider) -> Limits {
+    match provider {
+        Provider::Claude => Limits {
+            temperature: 1.0,
+            stop: usize::MAX,
+            candidates: 1,
+            seed: false,
+        },
+        Provider::ChatGPT => Limits {
+            temperature: 2.0,
+            stop: 4,
+            candidates: 8,
+            seed: true,
+        },
+        Provider::Gemini => Limits {
+            temperature: 2.0,
+            stop: 5,
+            candidates: 8,
+            seed: true,
+        },
+    }
+}
+
+/// モデルの最大出力トークン数。不明なモデルは None で、確認しない
+pub fn max_output(model: &str) -> Option<u32> {
+    capabilities::lookup(model).map(|caps| caps.max_output)
+}
+
+impl GenerationConfig {
+    /// other に指定のあるものを上書きする
+    pub fn merge(mut self, other: &GenerationConfig) -> Self {
+        self.max_tokens = other.max_tokens.or(self.max_tokens);
+        self.temperature = other.temperature.or(self.temperature);
+        self.top_p = other.top_p.or(self.top_p);
+        if !other.stop.is_empty() {
+            self.stop = other.stop.clone();
+        }
+        self.seed = other.seed.or(self.seed);
+        self.candidates = other.candidates.or(self.candidates);
+        self.json_mode = other.json_mode || self.json_mode;
+        if !other.tools.is_empty() {
+            self.tools = other.tools.clone();
+        }
+        self
+    }
+
+    /// 値の範囲だけを確認する (ペルソナの保存時など)
+    pub fn check_range(&self) -> Result<(), String> {
+        if self.max_tokens == Some(0) {
+            return Err("max_tokens must be greater than 0".to_string());
+        }
+        if let Some(temperature) = self.temperature {
+            if !(0.0..=2.0).contains(&temperature) {
+                return Err(format!("temperature must be 0.0-2.0: {}", temperature));
+            }
+        }
+        if let Some(top_p) = self.top_p {
+            if !(0.0..=1.0).contains(&top_p) {
+                return Err(format!("top_p must be 0.0-1.0: {}", top_p));
+            }
+        }
+        if self.candidates == Some(0) {
+            return Err("candidates must be greater than 0".to_string());
+        }
+        Ok(())
+    }
+
+    /// API とモデルの上限を確認する
+    pub fn validate(&self, provider: Provider, model: &str) -> Result<(), String> {
+        self.check_range()?;
+        let limits = limits(provider);
+        let name = provider.name();
+        if let (Some(max_tokens), Some(max)) = (self.max_tokens, max_output(model)) {
+            if max_tokens > max {
+                return Err(format!(
+                    "max_tokens {} exceeds the {} limit of {}",
+                    max_tokens, model, max
+                ));
+            }
+        }
+        if let Some(temperature) = self.temperature {
+            if temperature > limits.temperature {
+                return Err(format!(
+                    "temperature must be 0.0-{} for {}: {}",
+                    limits.temperature, name, temperature
+                ));
+            }
+        }
+        if self.stop.len() > limits.stop {
+            return Err(format!(
+                "{} accepts at most {} stop sequences",
+                name, limits.stop
+            ));
+        }
+        if self.seed.is_some() && !limits.seed {
+            return Err(format!("{} does not support seed", name));
+        }
+        if let Some(candidates) = self.candidates {
+            if candidates > limits.candidates {
+                return Err(format!(
+                    "{} accepts at most {} candidates",
+                    name, limits.candidates
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// 各APIのパラメータ名でリクエストボディに設定する
+    /// 最大トークン数は各 to_body で設定する
+    /// JSON モードを受け付けないモデルでは、capabilities::adapt_body が外して警告する
+    pub fn apply(&self, provider: Provider, body: &mut Value) {
+        let stop = (!self.stop.is_empty()).then_some(&self.stop);
+        let json_object = self.json_mode.then(|| json!({"type": "json_object"}));
+        let fields = match provider {
+            Provider::Claude => vec![
+                ("temperature", json!(self.temperature)),
+                ("top_p", json!(self.top_p)),
+                ("stop_sequences", json!(stop)),
+                ("response_format", json!(json_object)),
+            ],
+            Provider::ChatGPT => vec![
+                ("temperature", json!(self.temperature)),
+                ("top_p", json!(self.top_p)),
+                ("stop", json!(stop)),
+                ("seed", json!(self.seed)),
+                ("n", json!(self.candidates)),
+                ("response_format", json!(json_object)),
+            ],
+            Provider::Gemini => vec![
+                ("temperature", json!(self.temperature)),
+                ("topP", json!(self.top_p)),
+                ("stopSequences", json!(stop)),
+                ("seed", json!(self.seed)),
+                ("candidateCount", json!(self.candidates)),
+                (
+                    "responseMimeType",
+                    json!(self.json_mode.then_some("application/json")),
+                ),
+            ],
+        };
+        for (key, value) in fields.into_iter().filter(|(_, v)| !v.is_null()) {
+            match provider {
+                Provider::Gemini => body["generationConfig"][key] = value,
+                _ => body[key] = value,
+            }
+        }
+        if !self.tools.is_empty() {
+            body["tools"] = tools(provider, &self.tools);
+        }
+    }
+}
+
+/// OpenAI 形式のツール定義を各 API の形式にする
+fn tools(provider: Provider, tools: &[Value]) -> Value {
+    let functions = tools.iter().map(|tool| &tool["function"]);
+    match provider {
+        Provider::ChatGPT => json!(tools),
+        Provider::Claude => functions
+            .map(|f| {
+                json!({
+                    "name": f["name"],
+                    "description": f["description"],
+                    "input_schema": f["parameters"],
+                })
+            })
+            .collect(),
+        Provider::Gemini => json!([{ "functionDeclarations": functions.collect::<Vec<_>>() }]),
+    }
+}
+
+/// 回答の候補の本文。candidates が1なら1件
+pub fn candidate_texts(provider: Provider, res: &Value) -> Vec<String> {
+    match provider {
+        Provider::ChatGPT => res["choices"]
+            .as_array()
+            .map(|choices| {
+                choices
+                    .iter()
+                    .filter_map(|c| c["message"]["content"].as_str())
+                    .map(|s| s.to_string())
+                    .collect()
+            })
+            .unwrap_or_default(),
+        Provider::Gemini => res["candidates"]
+            .as_array()
+            .map(|candidates| {
+                candidates
+                    .iter()
+                    .map(|c| {
+                        c["content"]["parts"]
+                            .as_array()
+                            .map(|parts| {
+                                parts
+                                    .iter()
+                                    .filter_map(|p| p["text"].as_str())
+                                    .collect::<Vec<&str>>()
+                                    .join("")
+                            })
+                            .unwrap_or_default()
+                    })
+                    .collect()
+            })
+            .unwrap_or_default(),
+        Provider::Claude => Vec::new(),
+    }
+}
+
+fn path() -> Result<PathBuf, String> {
+    Ok(utils::app_data_dir()?.join(FILE))
+}
+
+/// 全体の既定値。Documents/.appdata/Talk with RustGPT/generation.toml
+pub fn global() -> GenerationConfig {
+    let read = || -> Result<GenerationConfig, String> {
+        let path = path()?;
+        if !path.exists() {
+            return Ok(GenerationConfig::default());
+        }
+        let data = std::fs::read_to_string(&path)
+            .map_err(|e| format!("failed to read generation config: {}", e))?;
+        toml::from_str(&data).map_err(|e| format!("failed to parse generation config: {}", e))
+    };
+    read().unwrap_or_else(|e| {
+        log::warn!("{}", e);
+        GenerationConfig::default()
+    })
+}
+
+/// 送信に使う設定
+/// 全体、ペルソナ、セッション、リクエストの順に重ねる
+pub fn resolve(
+    global: GenerationConfig,
+    persona: Option<&Persona>,
+    session: &GenerationConfig,
+    request: Option<&GenerationConfig>,
+) -> GenerationConfig {
+    let mut config = global;
+    if let Some(persona) = persona {
+        config = config.merge(&persona.generation);
+    }
+    config = config.merge(session);
+    if let Some(request) = request {
+        config = config.merge(request);
+    }
+    config
+}
+
+#[tauri::command]
+pub fn get_generation_defaults() -> Result<GenerationConfig, String> {
+    Ok(global())
+}
+
+#[tauri::command]
+pub fn set_generation_defaults(config: GenerationConfig) -> Result<String, String> {
+    config.check_range()?;
+    let path = path()?;
+    if let Some(dir) = path.parent() {
+        create_dir_all(dir).map_err(|e| format!("failed to create dir: {}", e))?;
+    }
+    let data = toml::to_string(&config)
+        .map_err(|e| format!("failed to serialize generation config: {}", e))?;
+    std::fs::write(path, data).map_err(|e| format!("failed to write generation config: {}", e))?;
+    Ok("success".to_string())
+}
+
+/// このセッションだけの設定。None で解除する
+#[tauri::command]
+pub fn set_session_generation(
+    config: Option<GenerationConfig>,
+    state: State<'_, Arc<Mutex<manage::message::Shelf>>>,
+) -> Result<String, String> {
+    let config = config.unwrap_or_default();
+    config.check_range()?;
+    let mut shelf = state.lock().unwrap();
+    shelf.generation = config;
+    Ok("success".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_layers_and_validate() {
+        let global = GenerationConfig {
+            temperature: Some(0.7),
+            max_tokens: Some(2048),
+            ..Default::default()
+        };
+        let persona = Persona {
+            name: "coder".to_string(),
+            generation: GenerationConfig {
+                temperature: Some(0.2),
+                stop: vec!["END".to_string()],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let session = GenerationConfig {
+            seed: Some(42),
+            ..Default::default()
+        };
+        let request = GenerationConfig {
+            max_tokens: Some(512),
+            ..Default::default()
+        };
+        let config = resolve(global, Some(&persona), &session, Some(&request));
+        assert_eq!(config.temperature, Some(0.2));
+        assert_eq!(config.max_tokens, Some(512));
+        assert_eq!(config.stop, vec!["END"]);
+        assert_eq!(config.seed, Some(42));
+
+        assert!(config
+            .validate(Provider::Gemini, "gemini-2.0-flash")
+            .is_ok());
+        assert!(config
+            .validate(Provider::Claude, "claude-3-5-haiku-latest")
+            .unwrap_err()
+            .contains("seed"));
+        let too_long = GenerationConfig {
+            max_tokens: Some(20000),
+            ..Default::default()
+        };
+        assert!(too_long.validate(Provider::ChatGPT, "gpt-4o-mini").is_err());
+        assert!(too_long
+            .validate(Provider::ChatGPT, "my-local-model")
+            .is_ok());
+        let hot = GenerationConfig {
+            temperature: Some(1.5),
+            ..Default::default()
+        };
+        assert!(hot.validate(Provider::Claude, "claude-3-7-sonnet").is_err());
+        assert!(hot.validate(Provider::ChatGPT, "gpt-4o").is_ok());
+    }
+
+    #[test]
+    fn test_apply_uses_native_field_names() {
+        let config = GenerationConfig {
+            temperature: Some(0.5),
+            top_p: Some(0.9),
+            stop: vec!["END".to_string()],
+            seed: Some(1),
+            candidates: Some(2),
+            ..Default::default()
+        };
+
+        let mut body = json!({"model": "gpt-4o"});
+        config.apply(Provider::ChatGPT, &mut body);
+        assert_eq!(body["stop"], json!(["END"]));
+        assert_eq!(body["n"], 2);
+
+        let mut body = json!({"generationConfig": {"maxOutputTokens": 100}});
+        config.apply(Provider::Gemini, &mut body);
+        assert_eq!(body["generationConfig"]["maxOutputTokens"], 100);
+        assert_eq!(body["generationConfig"]["topP"], 0.9);
+        assert_eq!(body["generationConfig"]["candidateCount"], 2);
+
+        let mut body = json!({});
+        GenerationConfig::default().apply(Provider::Claude, &mut body);
+        assert_eq!(body, json!({}));
+
+        let config = GenerationConfig {
+            json_mode: true,
+            tools: vec![json!({
+                "type": "function",
+                "function": {"name": "now", "description": "current time", "parameters": {"type": "object"}}
+            })],
+            ..Default::default()
+        };
+        let mut body = json!({});
+        config.apply(Provider::ChatGPT, &mut body);
+        assert_eq!(body["response_format"]["type"], "json_object");
+        assert_eq!(body["tools"][0]["function"]["name"], "now");
+
+        let mut body = json!({});
+        config.apply(Provider::Claude, &mut body);
+        assert_eq!(body["tools"][0]["input_schema"]["type"], "object");
+
+        let mut body = json!({});
+        config.apply(Provider::Gemini, &mut body);
+        assert_eq!(
+            body["generationConfig"]["responseMimeType"],
+            "application/json"
+        );
+        assert_eq!(body["tools"][0]["functionDeclarations"][0]["name"], "now");
+    }
+}
@@ -0,0 +1,312 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::create_dir_all;
+use std::path::{Path, PathBuf};
+use std::result::Result;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use tauri::State;
+
+use crate::manage::{self, provider::Provider, utils, variables};
+use crate::sub::template::Rendered;
+
+const BASE_FILE: &str = "system.md";
+// 資料ファイル1件あたりの上限
+const FILE_LIMIT: usize = 32_000;
+
+/// システムプロンプトの層
+/// base (全体の基本設定) → persona → file (プロジェクトの資料) → notes (このセッションのメモ) の順に重ねる
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Base,
+    Persona,
+    File,
+    Notes,
+}
+
+/// セッションごとの層の設定
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Layers {
+    // 無効にした層の id
+    #[serde(default)]
+    pub disabled: Vec<String>,
+    // プロジェクトの資料ファイル
+    #[serde(default)]
+    pub files: Vec<PathBuf>,
+    // このセッションだけのメモ
+    #[serde(default)]
+    pub notes: String,
+}
+
+/// プレビュー用の1層
+/// id は base, persona, notes, file:<path>
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Layer {
+    pub id: String,
+    pub kind: Kind,
+    pub label: String,
+    pub content: String,
+    pub enabled: bool,
+}
+
+impl Layers {
+    /// 各層を順に並べる。内容が空の層も一覧には出す
+    pub fn layers(&self, base: &str, persona: Option<(&str, &str)>) -> Vec<Layer> {
+        let mut layers = vec![self.layer("base", Kind::Base, "base", base.to_string())];
+        let (name, prompt) = persona.unwrap_or(("none", ""));
+        layers.push(self.layer("persona", Kind::Persona, name, prompt.to_string()));
+        for path in &self.files {
+            let label = path
+                .file_name()
+                .map(|n| n.to_string_lossy().to_string())
+                .unwrap_or(path.to_string_lossy().to_string());
+            let content = read_file(path)
+                .map(|text| format!("# {}\n\n{}", label, text))
+                .unwrap_or_else(|e| {
+                    log::warn!("{}", e);
+                    String::new()
+                });
+            let id = format!("file:{}", path.to_string_lossy());
+            layers.push(self.layer(&id, Kind::File, &label, content));
+        }
+        layers.push(self.layer("notes", Kind::Notes, "notes", self.notes.clone()));
+        layers
+    }
+
+    fn layer(&self, id: &str, kind: Kind, label: &str, content: String) -> Layer {
+        Layer {
+            id: id.to_string(),
+            kind,
+            label: label.to_string(),
+            content,
+            enabled: !self.disabled.iter().any(|d| d == id),
+        }
+    }
+
+    pub fn toggle(&mut self, id: &str, enabled: bool) {
+        self.disabled.retain(|d| d != id);
+        if !enabled {
+            self.disabled.push(id.to_string());
+        }
+    }
+
+    pub fn add_file(&mut self, path: PathBuf) -> Result<(), String> {
+        read_file(&path)?;
+        if !self.files.contains(&path) {
+            self.files.push(path);
+        }
+        Ok(())
+    }
+
+    pub fn remove_file(&mut self, path: &Path) -> Result<(), String> {
+        let len = self.files.len();
+        self.files.retain(|p| p != path);
+        if self.files.len() == len {
+            return Err(format!("context file not found: {}", path.display()));
+        }
+        self.toggle(&format!("file:{}", path.to_string_lossy()), true);
+        Ok(())
+    }
+}
+
+/// 有効で空でない層を空行でつなぐ
+pub fn compose(layers: &[Layer]) -> String {
+    layers
+        .iter()
+        .filter(|l| l.enabled && !l.content.trim().is_empty())
+        .map(|l| l.content.trim())
+        .collect::<Vec<&str>>()
+        .join("\n\n")
+}
+
+// 読み込んだファイルと更新日時
+// 送信のたびに Shelf のロック中に読み直さないよう、更新されたときだけ読む
+static FILES: Mutex<Option<HashMap<PathBuf, (SystemTime, String)>>> = Mutex::new(None);
+
+fn read_cached(path: &Path) -> std::io::Result<String> {
+    let modified = std::fs::metadata(path)?.modified()?;
+    if let Some((time, text)) = FILES.lock().unwrap().as_ref().and_then(|f| f.get(path)) {
+        if *time == modified {
+            return Ok(text.clone());
+        }
+    }
+    let text = std::fs::read_to_string(path)?;
+    FILES
+        .lock()
+        .unwrap()
+        .get_or_insert_with(HashMap::new)
+        .insert(path.to_path_buf(), (modified, text.clone()));
+    Ok(text)
+}
+
+fn read_file(path: &Path) -> Result<String, String> {
+    let text = read_cached(path)
+        .map_err(|e| format!("failed to read context file {}: {}", path.display(), e))?;
+    Ok(text.chars().take(FILE_LIMIT).collect())
+}
+
+fn base_path() -> Result<PathBuf, String> {
+    Ok(utils::app_data_dir()?.join(BASE_FILE))
+}
+
+/// 全体の基本設定。Documents/.appdata/Talk with RustGPT/system.md
+/// 無ければ空文字
+pub fn base() -> String {
+    match base_path() {
+        Ok(path) if path.exists() => read_cached(&path).unwrap_or_else(|e| {
+            log::warn!("failed to read base prompt: {}", e);
+            String::new()
+        }),
+        _ => String::new(),
+    }
+}
+
+#[tauri::command]
+pub fn list_system_layers(
+    state: State<'_, Arc<Mutex<manage::message::Shelf>>>,
+) -> Result<Vec<Layer>, String> {
+    let shelf = state.lock().unwrap();
+    Ok(shelf.system_layers(&base()))
+}
+
+/// 送信されるシステムプロンプト全体
+/// 選択中の provider, model で変数を展開し、値のない変数も返す
+/// provider を省略した場合はペルソナの AI、それも無ければ Claude の既定のモデル
+#[tauri::command]
+pub fn preview_system_prompt(
+    provider: Option<Provider>,
+    model: Option<String>,
+    state: State<'_, Arc<Mutex<manage::message::Shelf>>>,
+) -> Result<Rendered, String> {
+    let (system_prompt, persona) = {
+        let shelf = state.lock().unwrap();
+        (shelf.system_prompt(), shelf.persona.clone())
+    };
+    let provider = provider
+        .or(persona.as_ref().and_then(|p| p.provider))
+        .unwrap_or(Provider::Claude);
+    let b = persona.and_then(|p| p.tier).map(|t| t.b()).unwrap_or(0);
+    let model = model
+        .filter(|m| !m.is_empty())
+        .unwrap_or_else(|| provider.model(b).0);
+    Ok(variables::render(&system_prompt, provider, &model))
+}
+
+#[tauri::command]
+pub fn toggle_system_layer(
+    id: &str,
+    enabled: bool,
+    state: State<'_, Arc<Mutex<manage::message::Shelf>>>,
+) -> Result<String, String> {
+    let mut shelf = state.lock().unwrap();
+    shelf.layers.toggle(id, enabled);
+    Ok("success".to_string())
+}
+
+#[tauri::command]
+pub fn set_base_prompt(prompt: &str) -> Result<String, String> {
+    let path = base_path()?;
+    if let Some(dir) = path.parent() {
+        create_dir_all(dir).map_err(|e| format!("failed to create dir: {}", e))?;
+    }
+    std::fs::write(path, prompt).map_err(|e| format!("failed to write base prompt: {}", e))?;
+    Ok("success".to_string())
+}
+
+#[tauri::command]
+pub fn add_context_file(
+    path: PathBuf,
+    state: State<'_, Arc<Mutex<manage::message::Shelf>>>,
+) -> Result<String, String> {
+    let mut shelf = state.lock().unwrap();
+    shelf.layers.add_file(path)?;
+    Ok("success".to_string())
+}
+
+#[tauri::command]
+pub fn remove_context_file(
+    path: PathBuf,
+    state: State<'_, Arc<Mutex<manage::message::Shelf>>>,
+) -> Result<String, String> {
+    let mut shelf = state.lock().unwrap();
+    shelf.layers.remove_file(&path)?;
+    Ok("success".to_string())
+}
+
+#[tauri::command]
+pub fn set_session_notes(
+    notes: &str,
+    state: State<'_, Arc<Mutex<manage::message::Shelf>>>,
+) -> Result<String, String> {
+    let mut shelf = state.lock().unwrap();
+    shelf.layers.notes = notes.to_string();
+    Ok("success".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layers_compose_in_order_and_toggle() {
+        let path =
+            std::env::temp_dir().join(format!("talkwithrust-context-{}.md", std::process::id()));
+        std::fs::write(&path, "use tokio").unwrap();
+
+        let mut layers = Layers {
+            notes: "answer briefly".to_string(),
+            ..Default::default()
+        };
+        layers.add_file(path.clone()).unwrap();
+        assert!(layers.add_file(path.with_extension("missing")).is_err());
+
+        let all = layers.layers("you are helpful", Some(("strict", "be strict")));
+        assert_eq!(all.len(), 4);
+        assert_eq!(all[2].kind, Kind::File);
+        let file_header = format!("# {}", path.file_name().unwrap().to_string_lossy());
+        assert_eq!(
+            compose(&all),
+            format!(
+                "you are helpful\n\nbe strict\n\n{}\n\nuse tokio\n\nanswer briefly",
+                file_header
+            )
+        );
+
+        layers.toggle("persona", false);
+        layers.toggle(&all[2].id, false);
+        let all = layers.layers("you are helpful", Some(("strict", "be strict")));
+        assert!(!all[1].enabled);
+        assert_eq!(compose(&all), "you are helpful\n\nanswer briefly");
+
+        layers.toggle("persona", true);
+        layers.remove_file(&path).unwrap();
+        assert!(layers.disabled.is_empty());
+        assert_eq!(
+            compose(&layers.layers("", Some(("strict", "be strict")))),
+            "be strict\n\nanswer briefly"
+        );
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_read_file_rereads_only_changed_files() {
+        let path =
+            std::env::temp_dir().join(format!("talkwithrust-cached-{}.md", std::process::id()));
+        std::fs::write(&path, "first").unwrap();
+        assert_eq!(read_file(&path).unwrap(), "first");
+        assert_eq!(read_file(&path).unwrap(), "first");
+
+        std::fs::write(&path, "second").unwrap();
+        let later = SystemTime::now() + std::time::Duration::from_secs(5);
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        assert_eq!(read_file(&path).unwrap(), "second");
+        let _ = std::fs::remove_file(&path);
+    }
+}
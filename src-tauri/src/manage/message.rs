@@ -1,4 +1,3 @@
-use directories::UserDirs;
 use serde::{Deserialize, Serialize};
 use std::io::Write;
 
@@ -10,14 +9,44 @@ use log::info;
 
 use std::fs::create_dir_all;
 
-use crate::manage::filetitle;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
 
-const APPNAME: &str = "Talk with RustGPT";
+use crate::manage::{
+    filetitle,
+    generation::{self, GenerationConfig},
+    layers::{self, Layer, Layers},
+    persona::Persona,
+    secrets, settings, utils,
+};
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Shelf {
     pub messages: Messages,
     pub system_messages: Messages,
+    // 比較モードの回答候補
+    #[serde(default)]
+    pub comparison: Option<Comparison>,
+    // 資料検索に使う索引名
+    #[serde(default)]
+    pub rag: Option<String>,
+    // 使用中のペルソナ
+    #[serde(default)]
+    pub persona: Option<Persona>,
+    // システムプロンプトの層
+    #[serde(default)]
+    pub layers: Layers,
+    // このセッションの生成パラメータ
+    #[serde(default)]
+    pub generation: GenerationConfig,
+}
+
+/// 比較モードで得た回答候補
+/// position は履歴上の採用中の assistant メッセージの位置
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Comparison {
+    pub position: usize,
+    pub candidates: Vec<Message>,
 }
 
 impl Shelf {
@@ -25,6 +54,11 @@ impl Shelf {
         Self {
             messages: Messages::new(),
             system_messages: Messages::new(),
+            comparison: None,
+            rag: None,
+            persona: None,
+            layers: Layers::default(),
+            generation: GenerationConfig::default(),
         }
     }
     #[allow(unused)]
@@ -38,6 +72,19 @@ impl Shelf {
         self.messages.get()
     }
 
+    /// AIへ送る履歴
+    /// excluded の付いたメッセージは除き、CONTEXT_TOKEN_BUDGET を超える分は古いやりとりから削る
+    pub fn context_messages(&self) -> Vec<Message> {
+        let messages = self
+            .messages
+            .messages
+            .iter()
+            .filter(|m| !m.excluded)
+            .cloned()
+            .collect();
+        trim_to_budget(messages, context_budget())
+    }
+
     #[allow(unused)]
     pub fn get_system(&self) -> Vec<Message> {
         self.system_messages.get()
@@ -47,14 +94,243 @@ impl Shelf {
         self.messages.add(role, content, src);
     }
 
+    /// AIの回答を、回答したモデルなどの情報と一緒に履歴に追加する
+    pub fn add_answer(&mut self, content: String, meta: Meta) {
+        self.messages.messages.push(Message {
+            role: "assistant".to_string(),
+            content,
+            meta: Some(meta),
+            ..Default::default()
+        });
+    }
+
+    /// 生成画像の回答を追加する
+    /// 保存先のパスは images に入れ、AIへ送る content には含めない
+    pub fn add_images(&mut self, content: String, images: Vec<String>, meta: Option<Meta>) {
+        self.messages.messages.push(Message {
+            role: "assistant".to_string(),
+            content,
+            meta,
+            images,
+            ..Default::default()
+        });
+    }
+
+    /// 1回のリクエストで得た複数の回答 (candidates) を追加する
+    /// 比較モードと同じく先頭を採用し、残りは候補として保持する
+    pub fn add_candidates(&mut self, contents: Vec<String>, meta: Meta) -> Result<(), String> {
+        let candidates = contents
+            .into_iter()
+            .enumerate()
+            .map(|(i, content)| Message {
+                role: "assistant".to_string(),
+                content,
+                label: Some(format!("{} #{}", meta.model, i + 1)),
+                meta: Some(meta.clone()),
+                ..Default::default()
+            })
+            .collect();
+        self.add_comparison(candidates)
+    }
+
+    /// 送信に使う生成パラメータ
+    /// 全体の既定値、ペルソナ、セッション、リクエストの順に重ねる
+    pub fn generation_config(&self, request: Option<&GenerationConfig>) -> GenerationConfig {
+        generation::resolve(
+            generation::global(),
+            self.persona.as_ref(),
+            &self.generation,
+            request,
+        )
+    }
+
+    /// 送信に使うシステムプロンプト
+    /// 基本設定、ペルソナ、資料ファイル、メモのうち有効な層を重ねたもの。無ければ空文字
+    pub fn system_prompt(&self) -> String {
+        layers::compose(&self.system_layers(&layers::base()))
+    }
+
+    /// システムプロンプトの各層
+    /// ペルソナの層は最後に設定したシステムプロンプト
+    pub fn system_layers(&self, base: &str) -> Vec<Layer> {
+        let persona = self.system_messages.messages.last().map(|m| {
+            let name = self.persona.as_ref().map_or("system", |p| p.name.as_str());
+            (name, m.content.as_str())
+        });
+        self.layers.layers(base, persona)
+    }
+
+    /// ペルソナを切り替える。ペルソナの層だけを置き換え、他の層はそのまま
+    /// None ならペルソナの層は空
+    pub fn set_persona(&mut self, persona: Option<Persona>) {
+        self.system_messages.reset();
+        if let Some(prompt) = persona.as_ref().map(|p| p.prompt.clone()) {
+            if !prompt.is_empty() {
+                self.add_to_system(prompt);
+            }
+        }
+        self.persona = persona;
+    }
+
+    /// ペルソナに声が指定されていればその声
+    pub fn voice(&self) -> Option<i16> {
+        self.persona.as_ref().and_then(|p| p.voice)
+    }
+
     #[allow(unused)]
     pub fn add_to_system(&mut self, prompt: String) {
         self.system_messages.add("system".to_string(), prompt, None);
     }
 
+    /// 比較モードの回答候補を履歴に追加する
+    /// 先頭の候補を採用し、残りは alternatives として保持する
+    pub fn add_comparison(&mut self, candidates: Vec<Message>) -> Result<(), String> {
+        if candidates.is_empty() {
+            return Err("no candidates".to_string());
+        }
+
+        self.comparison = Some(Comparison {
+            position: self.messages.messages.len(),
+            candidates,
+        });
+        self.messages.messages.push(Message::default());
+        self.choose(0).map(|_| ())
+    }
+
+    /// 比較モードの回答候補から、履歴に採用するものを選び直す
+    pub fn choose(&mut self, index: usize) -> Result<Message, String> {
+        let comparison = self
+            .comparison
+            .as_ref()
+            .ok_or("no comparison to choose from")?;
+        if comparison.position >= self.messages.messages.len() {
+            return Err("comparison is no longer in the history".to_string());
+        }
+        let chosen = comparison
+            .candidates
+            .get(index)
+            .ok_or(format!("candidate not found: {}", index))?;
+
+        let mut message = chosen.clone();
+        message.alternatives = comparison
+            .candidates
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != index)
+            .map(|(_, m)| m.clone())
+            .collect();
+        self.messages.messages[comparison.position] = message.clone();
+
+        Ok(message)
+    }
+
+    /// id の発言を含むやりとりの範囲
+    /// user の発言と、次の user の発言までの回答をひとまとまりとする
+    fn turn(&self, id: &str) -> Result<Range<usize>, String> {
+        let messages = &self.messages.messages;
+        let index = messages
+            .iter()
+            .position(|m| m.id == id)
+            .ok_or(format!("message not found: {}", id))?;
+        let start = messages[..=index]
+            .iter()
+            .rposition(|m| m.role == "user")
+            .unwrap_or(0);
+        let end = messages[index + 1..]
+            .iter()
+            .position(|m| m.role == "user")
+            .map(|i| index + 1 + i)
+            .unwrap_or(messages.len());
+        Ok(start..end)
+    }
+
+    fn message_mut(&mut self, id: &str) -> Result<&mut Message, String> {
+        self.messages
+            .messages
+            .iter_mut()
+            .find(|m| m.id == id)
+            .ok_or(format!("message not found: {}", id))
+    }
+
+    /// 発言を、質問と回答のやりとりごと削除する
+    pub fn delete_message(&mut self, id: &str) -> Result<(), String> {
+        let range = self.turn(id)?;
+        let mut messages = self.messages.messages.clone();
+        messages.drain(range.clone());
+        validate_roles(&messages)?;
+        self.messages.messages = messages;
+
+        // 比較モードの位置をずらす。削除した場合は選び直せなくする
+        if let Some(comparison) = self.comparison.as_mut() {
+            if comparison.position >= range.end {
+                comparison.position -= range.len();
+            } else if comparison.position >= range.start {
+                self.comparison = None;
+            }
+        }
+        Ok(())
+    }
+
+    /// 送信に失敗して回答のない最後の質問を取り除く
+    pub fn drop_unanswered(&mut self) {
+        if self.messages.messages.last().map(|m| m.role.as_str()) == Some("user") {
+            self.messages.messages.pop();
+        }
+    }
+
+    /// 発言の本文を書き換える
+    pub fn edit_message(&mut self, id: &str, content: &str) -> Result<(), String> {
+        if content.trim().is_empty() {
+            return Err("content is empty".to_string());
+        }
+        self.message_mut(id)?.content = content.to_string();
+        Ok(())
+    }
+
+    /// AIへ送らない発言にする、または戻す
+    /// user の発言を指定した場合はやりとりごと切り替える。戻すときは最後の回答だけを戻す
+    pub fn exclude_message(&mut self, id: &str, excluded: bool) -> Result<(), String> {
+        let range = self.turn(id)?;
+        let mut messages = self.messages.messages.clone();
+        let index = messages.iter().position(|m| m.id == id).unwrap_or(0);
+
+        if messages[index].role == "user" {
+            messages[range.clone()]
+                .iter_mut()
+                .for_each(|m| m.excluded = excluded);
+            if !excluded && range.len() > 1 {
+                messages[range.start + 1..range.end - 1]
+                    .iter_mut()
+                    .for_each(|m| m.excluded = true);
+            }
+        } else {
+            messages[index].excluded = excluded;
+        }
+
+        validate_roles(&messages)?;
+        self.messages.messages = messages;
+        Ok(())
+    }
+
+    /// 予算を超えても削らない発言にする、または戻す
+    pub fn pin_message(&mut self, id: &str, pinned: bool) -> Result<(), String> {
+        self.message_mut(id)?.pinned = pinned;
+        Ok(())
+    }
+
+    pub fn star_message(&mut self, id: &str, starred: bool) -> Result<(), String> {
+        self.message_mut(id)?.starred = starred;
+        Ok(())
+    }
+
     pub fn reset(&mut self) -> Result<(), String> {
         self.messages.reset();
         self.system_messages.reset();
+        self.comparison = None;
+        // システムプロンプトと一緒にペルソナも外す
+        self.persona = None;
+        // 資料ファイルとメモもこのセッションのものなので外す
+        self.layers = Layers::default();
 
         if self.messages.messages.is_empty() {
             println!("success length: {}", self.messages.messages.len());
@@ -65,27 +341,38 @@ impl Shelf {
         }
     }
 
-    pub fn memo(&self) -> Result<(), String> {
-        // save to dir
-        let user_dir: UserDirs = UserDirs::new().unwrap();
-        let document_dir = user_dir.document_dir().unwrap();
-        let save_dir = document_dir.join(".appdata").join(APPNAME);
-
-        // without system messages
-        let copy_shelf = self.clone();
-        let messages = copy_shelf.messages.get();
-        let data = messages
+    /// メモの本文
+    /// 発言ごとに "role [label]: 本文" と、ID などを書いた <!-- meta {...} --> の行を出力する
+    /// 貼り付けられた API キーは伏せる
+    pub fn memo_text(&self) -> String {
+        let text = self
+            .messages
+            .messages
             .iter()
             .map(|m| {
                 if m.role == "user" {
                     // content has image_url, delete it;
-                    format!("{}: {}", m.role, m.content.clone())
+                    format!("{}: {}\n{}", m.speaker(), m.content, m.memo_meta())
                 } else {
-                    format!("{}: {}\n----------------", m.role, m.content)
+                    format!(
+                        "{}: {}\n{}\n----------------",
+                        m.speaker(),
+                        m.content,
+                        m.memo_meta()
+                    )
                 }
             })
             .collect::<Vec<String>>()
             .join("\n\n");
+        secrets::redact(&text)
+    }
+
+    pub fn memo(&self) -> Result<(), String> {
+        // save to dir
+        let save_dir = utils::app_data_dir()?;
+
+        // without system messages
+        let data = self.memo_text();
 
         // create filename & save path
         create_dir_all(save_dir.as_path()).unwrap();
@@ -112,24 +399,348 @@ impl Shelf {
 }
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(from = "StoredMessages")]
 pub struct Messages {
     // add clone
     pub messages: Vec<Message>,
 }
-#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+
+/// 保存された履歴
+/// ID のない古い発言には、読み込むたびに同じになる ID を振る
+#[derive(Deserialize)]
+struct StoredMessages {
+    messages: Vec<Message>,
+}
+
+impl From<StoredMessages> for Messages {
+    fn from(stored: StoredMessages) -> Self {
+        let mut messages = stored.messages;
+        fill_ids(&mut messages, "");
+        Self { messages }
+    }
+}
+
+/// ID のない発言に、位置と内容から決まる ID を振る
+/// 比較の回答候補は親の位置を前に付ける
+pub fn fill_ids(messages: &mut [Message], parent: &str) {
+    for (index, message) in messages.iter_mut().enumerate() {
+        let position = format!("{}{}", parent, index);
+        if message.id.is_empty() {
+            message.id = legacy_id(&position, message);
+        }
+        fill_ids(&mut message.alternatives, &format!("{}.", position));
+    }
+}
+
+/// 古い発言の ID
+/// 同じ内容の発言があっても位置で区別する
+pub fn legacy_id(position: &str, message: &Message) -> String {
+    // FNV-1a
+    let hash = [message.role.as_str(), "\0", message.content.as_str()]
+        .iter()
+        .flat_map(|s| s.bytes())
+        .fold(0xcbf29ce484222325u64, |h, b| {
+            (h ^ b as u64).wrapping_mul(0x100000001b3)
+        });
+    format!("legacy-{}-{:016x}", position, hash)
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Message {
+    // 発言ごとに一意なID。IDのない古い履歴は読み込み時に fill_ids で振る
+    #[serde(default)]
+    pub id: String,
+    // RFC 3339 の日時。古い履歴では None
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<String>,
     // who is speaking
     pub role: String,
     // what is said
     pub content: String,
     // image/file base64 source data
     pub src: Option<String>,
+    // provider:model など、誰の発言かを示すラベル
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    // 比較モードで採用しなかった回答
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub alternatives: Vec<Message>,
+    // 履歴には残すが、AIへは送らない
+    // 討論モードの途中の回答と批評に付け、以降の質問には最終回答だけを送る
+    // (user, assistant の交互の並びを保ち、批評のトークンを毎回送らないため)
+    #[serde(default, alias = "hidden", skip_serializing_if = "std::ops::Not::not")]
+    pub excluded: bool,
+    // 利用者が付けた印
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub starred: bool,
+    // 履歴が CONTEXT_TOKEN_BUDGET を超えても削らない
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub pinned: bool,
+    // 回答したAIとトークン数など。assistant のみ
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<Meta>,
+    // 生成画像の保存先。AIへは送らない
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub images: Vec<String>,
+}
+
+impl Default for Message {
+    fn default() -> Self {
+        Self {
+            id: new_id(),
+            created_at: Some(chrono::Local::now().to_rfc3339()),
+            role: String::new(),
+            content: String::new(),
+            src: None,
+            label: None,
+            alternatives: Vec::new(),
+            excluded: false,
+            starred: false,
+            pinned: false,
+            meta: None,
+            images: Vec::new(),
+        }
+    }
+}
+
+/// 発言のID
+/// 作成日時とプロセス内の連番を組み合わせる
+pub fn new_id() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    format!(
+        "{}-{}",
+        chrono::Local::now().format("%Y%m%d%H%M%S%3f"),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// メモに書く発言の情報
+/// 本文と発言者名以外をまとめて1行の JSON にする
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct MemoMeta {
+    id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    created_at: Option<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    excluded: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    starred: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pinned: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    meta: Option<Meta>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    images: Vec<String>,
+}
+
+const MEMO_META_OPEN: &str = "<!-- meta ";
+const MEMO_META_CLOSE: &str = " -->";
+
+/// Claude と Gemini に送れる並びか確かめる
+/// AIへ送る発言が user から始まり、user と assistant が交互に並び、assistant で終わること
+pub fn validate_roles(messages: &[Message]) -> Result<(), String> {
+    let context = messages
+        .iter()
+        .filter(|m| !m.excluded)
+        .collect::<Vec<&Message>>();
+
+    if let Some(first) = context.first() {
+        if first.role != "user" {
+            return Err(format!(
+                "history must start with a user message: {}",
+                first.id
+            ));
+        }
+    }
+    for pair in context.windows(2) {
+        if pair[0].role == pair[1].role {
+            return Err(format!(
+                "two {} messages in a row: {} and {}",
+                pair[0].role, pair[0].id, pair[1].id
+            ));
+        }
+    }
+    if let Some(last) = context.last() {
+        if last.role != "assistant" {
+            return Err(format!("history must end with an answer: {}", last.id));
+        }
+    }
+    Ok(())
+}
+
+/// 設定か環境変数 CONTEXT_TOKEN_BUDGET。未設定か 0 なら削らない
+fn context_budget() -> Option<usize> {
+    settings::current().context_token_budget.filter(|v| *v > 0)
+}
+
+/// おおよそのトークン数
+/// 英語は4文字、日本語は1文字で1トークン程度なので、間をとって2文字で1トークンとする
+pub fn estimate_tokens(message: &Message) -> usize {
+    message.content.chars().count() / 2 + 1
+}
+
+/// 予算を超える分を、古いやりとりから削る
+/// pinned の発言を含むやりとりと、最後のやりとり(今回の質問)は削らない
+pub fn trim_to_budget(messages: Vec<Message>, budget: Option<usize>) -> Vec<Message> {
+    let Some(budget) = budget else {
+        return messages;
+    };
+
+    // user の発言ごとに区切る
+    let mut turns: Vec<Vec<Message>> = Vec::new();
+    for message in messages {
+        match turns.last_mut() {
+            Some(turn) if message.role != "user" => turn.push(message),
+            _ => turns.push(vec![message]),
+        }
+    }
+
+    let tokens = |turn: &Vec<Message>| turn.iter().map(estimate_tokens).sum::<usize>();
+    let mut total = turns.iter().map(tokens).sum::<usize>();
+    let mut keep = vec![true; turns.len()];
+    for (i, turn) in turns.iter().enumerate().take(turns.len().saturating_sub(1)) {
+        if total <= budget {
+            break;
+        }
+        if turn.iter().any(|m| m.pinned) {
+            continue;
+        }
+        keep[i] = false;
+        total -= tokens(turn);
+    }
+
+    turns
+        .into_iter()
+        .zip(keep)
+        .filter(|(_, keep)| *keep)
+        .flat_map(|(turn, _)| turn)
+        .collect()
+}
+
+/// 1回の回答についての情報
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Meta {
+    pub provider: String,
+    pub model: String,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    pub latency_ms: u64,
+    // end_turn, stop, MAX_TOKENS など各APIの値そのまま
+    #[serde(default)]
+    pub finish_reason: Option<String>,
+    #[serde(default)]
+    pub request_id: Option<String>,
+    // 出力が途中で切れた、などの注意
+    #[serde(default)]
+    pub warnings: Vec<String>,
+}
+
+impl Message {
+    /// メモに書く <!-- meta {...} --> の行
+    pub fn memo_meta(&self) -> String {
+        let meta = MemoMeta {
+            id: self.id.clone(),
+            created_at: self.created_at.clone(),
+            excluded: self.excluded,
+            starred: self.starred,
+            pinned: self.pinned,
+            meta: self.meta.clone(),
+            images: self.images.clone(),
+        };
+        // 本文中の --> でコメントが閉じないようにする
+        let json = serde_json::to_string(&meta)
+            .unwrap_or_default()
+            .replace("-->", "--\\u003e");
+        format!("{}{}{}", MEMO_META_OPEN, json, MEMO_META_CLOSE)
+    }
+
+    /// memo_meta() の行であれば読み込んで true を返す
+    /// 読めない行は本文の一部として扱えるよう false を返す
+    pub fn apply_memo_meta(&mut self, line: &str) -> bool {
+        let Some(json) = line
+            .trim()
+            .strip_prefix(MEMO_META_OPEN)
+            .and_then(|l| l.strip_suffix(MEMO_META_CLOSE))
+        else {
+            return false;
+        };
+        let Ok(meta) = serde_json::from_str::<MemoMeta>(json) else {
+            return false;
+        };
+        if !meta.id.is_empty() {
+            self.id = meta.id;
+        }
+        self.created_at = meta.created_at;
+        self.excluded = meta.excluded;
+        self.starred = meta.starred;
+        self.pinned = meta.pinned;
+        self.meta = meta.meta;
+        self.images = meta.images;
+        true
+    }
+
+    /// 履歴表示やメモでの発言者名
+    /// ラベルがあれば role に添える: assistant [critique claude:claude-3-7-sonnet-latest]
+    pub fn speaker(&self) -> String {
+        match &self.label {
+            Some(label) => format!("{} [{}]", self.role, label),
+            None => self.role.clone(),
+        }
+    }
+}
+
+impl Messages {
+    pub fn new() -> Self {
+        Self {
+            messages: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, role: String, content: String, src: Option<String>) {
+        let message = Message {
+            role,
+            content,
+            src,
+            ..Default::default()
+        };
+        self.messages.push(message);
+    }
+
+    pub fn get(&self) -> Vec<Message> {
+        self.messages.clone()
+    }
+
+    // reset messages
+    pub fn reset(&mut self) {
+        self.messages.clear();
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_reset_clears_persona_and_layers() {
+        let mut shelf = Shelf::new();
+        shelf.layers.notes = "answer briefly".to_string();
+        shelf
+            .layers
+            .files
+            .push(std::path::PathBuf::from("notes.md"));
+        shelf.set_persona(Some(Persona {
+            name: "coder".to_string(),
+            prompt: "be strict".to_string(),
+            voice: Some(3),
+            ..Default::default()
+        }));
+        assert_eq!(shelf.voice(), Some(3));
+        shelf.reset().unwrap();
+        assert!(shelf.persona.is_none());
+        assert!(shelf.layers.notes.is_empty() && shelf.layers.files.is_empty());
+        assert_eq!(shelf.voice(), None);
+        assert!(shelf.system_prompt().is_empty());
+    }
+
     #[test]
     fn test_request_system_overwrites_previous_system_prompt() {
         // request_system を2回呼んだとき、system_messages は1件だけになるべき
@@ -144,36 +755,238 @@ mod tests {
     }
 
     #[test]
-    fn test_get_system_returns_system_messages_not_messages() {
+    fn test_choose_swaps_comparison_candidates() {
         let mut shelf = Shelf::new();
         shelf.add_to_messages("user".to_string(), "hello".to_string(), None);
-        shelf.system_messages.add("system".to_string(), "be strict".to_string(), None);
 
-        let system = shelf.get_system();
-        assert_eq!(system.len(), 1);
-        assert_eq!(system[0].content, "be strict");
-        assert_eq!(system[0].role, "system");
+        let candidate = |label: &str| Message {
+            role: "assistant".to_string(),
+            content: format!("hi from {}", label),
+            label: Some(label.to_string()),
+            ..Default::default()
+        };
+        shelf
+            .add_comparison(vec![candidate("claude"), candidate("gemini")])
+            .unwrap();
+
+        let messages = shelf.get_messages();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[1].label.as_deref(), Some("claude"));
+        assert_eq!(messages[1].alternatives.len(), 1);
+
+        shelf.choose(1).unwrap();
+        let messages = shelf.get_messages();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[1].content, "hi from gemini");
+        assert_eq!(messages[1].alternatives[0].label.as_deref(), Some("claude"));
+
+        assert!(shelf.choose(5).is_err());
     }
-}
 
-impl Messages {
-    pub fn new() -> Self {
-        Self {
-            messages: Vec::new(),
-        }
+    #[test]
+    fn test_context_messages_skips_excluded() {
+        let mut shelf = Shelf::new();
+        shelf.add_to_messages("user".to_string(), "question".to_string(), None);
+        shelf.messages.messages.push(Message {
+            role: "assistant".to_string(),
+            content: "draft".to_string(),
+            label: Some("answer claude:model".to_string()),
+            excluded: true,
+            ..Default::default()
+        });
+        shelf.add_to_messages("assistant".to_string(), "final".to_string(), None);
+
+        assert_eq!(shelf.get_messages().len(), 3);
+        let context = shelf.context_messages();
+        assert_eq!(context.len(), 2);
+        assert_eq!(context[1].content, "final");
+        assert_eq!(
+            shelf.get_messages()[1].speaker(),
+            "assistant [answer claude:model]"
+        );
     }
 
-    pub fn add(&mut self, role: String, content: String, src: Option<String>) {
-        let message = Message { role, content, src };
-        self.messages.push(message);
+    #[test]
+    fn test_add_answer_keeps_meta_and_old_messages_still_load() {
+        let mut shelf = Shelf::new();
+        shelf.add_to_messages("user".to_string(), "hello".to_string(), None);
+        shelf.add_answer(
+            "hi".to_string(),
+            Meta {
+                provider: "claude".to_string(),
+                model: "claude-3-5-haiku".to_string(),
+                input_tokens: 3,
+                output_tokens: 5,
+                ..Default::default()
+            },
+        );
+
+        let json = serde_json::to_string(&shelf).unwrap();
+        let loaded: Shelf = serde_json::from_str(&json).unwrap();
+        let messages = loaded.get_messages();
+        assert_eq!(messages[1].role, "assistant");
+        assert_eq!(messages[1].meta.as_ref().unwrap().output_tokens, 5);
+        assert!(messages[0].meta.is_none());
+
+        let old: Message =
+            serde_json::from_str(r#"{"role": "assistant", "content": "hi", "src": null}"#).unwrap();
+        assert!(old.meta.is_none());
     }
 
-    pub fn get(&self) -> Vec<Message> {
-        self.messages.clone()
+    #[test]
+    fn test_messages_have_ids_and_old_json_still_loads() {
+        let mut shelf = Shelf::new();
+        shelf.add_to_messages("user".to_string(), "a".to_string(), None);
+        shelf.add_to_messages("user".to_string(), "b".to_string(), None);
+        let messages = shelf.get_messages();
+        assert_ne!(messages[0].id, messages[1].id);
+        assert!(messages[0].created_at.is_some());
+
+        // ID は保存し直しても変わらない
+        let json = serde_json::to_string(&shelf).unwrap();
+        let loaded: Shelf = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded.get_messages()[1].id, messages[1].id);
+
+        let json = r#"{"messages": [
+            {"role": "user", "content": "hi", "src": null, "hidden": true},
+            {"role": "assistant", "content": "hi", "src": null},
+            {"role": "user", "content": "hi", "src": null}
+        ]}"#;
+        let loaded: Messages = serde_json::from_str(json).unwrap();
+        let old = &loaded.messages[0];
+        assert!(!old.id.is_empty());
+        // 読み込み直しても ID は同じで、同じ内容の発言とも重ならない
+        let again: Messages = serde_json::from_str(json).unwrap();
+        assert_eq!(again.messages[0].id, old.id);
+        assert_ne!(loaded.messages[2].id, old.id);
+        assert_ne!(loaded.messages[1].id, old.id);
+        assert!(old.created_at.is_none());
+        assert!(old.excluded);
+        assert!(!old.starred);
     }
 
-    // reset messages
-    pub fn reset(&mut self) {
-        self.messages.clear();
+    #[test]
+    fn test_memo_meta_round_trip() {
+        let mut message = Message {
+            role: "assistant".to_string(),
+            content: "a --> b".to_string(),
+            starred: true,
+            meta: Some(Meta {
+                provider: "chatgpt".to_string(),
+                model: "gpt-4o".to_string(),
+                warnings: vec!["cut --> off".to_string()],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let line = message.memo_meta();
+        assert!(line.starts_with("<!-- meta {"));
+        assert_eq!(line.matches("-->").count(), 1);
+
+        let mut loaded = Message::default();
+        assert!(loaded.apply_memo_meta(&line));
+        assert_eq!(loaded.id, message.id);
+        assert!(loaded.starred);
+        assert_eq!(loaded.meta, message.meta);
+
+        message.content = "text".to_string();
+        assert!(!message.apply_memo_meta("<!-- just a comment -->"));
+    }
+
+    fn exchange(shelf: &mut Shelf, question: &str, answer: &str) -> (String, String) {
+        shelf.add_to_messages("user".to_string(), question.to_string(), None);
+        shelf.add_to_messages("assistant".to_string(), answer.to_string(), None);
+        let messages = shelf.get_messages();
+        let n = messages.len();
+        (messages[n - 2].id.clone(), messages[n - 1].id.clone())
+    }
+
+    #[test]
+    fn test_delete_and_exclude_work_on_whole_turns() {
+        let mut shelf = Shelf::new();
+        let (q1, _) = exchange(&mut shelf, "q1", "a1");
+        let (_, a2) = exchange(&mut shelf, "q2", "a2");
+        let (q3, a3) = exchange(&mut shelf, "log paste", "a3");
+
+        // 回答を指定しても質問ごと消える
+        shelf.delete_message(&a2).unwrap();
+        let contents = shelf
+            .get_messages()
+            .iter()
+            .map(|m| m.content.clone())
+            .collect::<Vec<String>>();
+        assert_eq!(contents, vec!["q1", "a1", "log paste", "a3"]);
+
+        shelf.exclude_message(&q3, true).unwrap();
+        assert_eq!(shelf.context_messages().len(), 2);
+        shelf.exclude_message(&q3, false).unwrap();
+        assert_eq!(shelf.context_messages().len(), 4);
+
+        // 回答だけを外すと user が続くので受け付けない
+        assert!(shelf.exclude_message(&a3, true).is_err());
+        assert!(!shelf.get_messages()[3].excluded);
+
+        shelf.edit_message(&q1, "edited").unwrap();
+        assert_eq!(shelf.get_messages()[0].content, "edited");
+        assert!(shelf.edit_message(&q1, "  ").is_err());
+        assert!(shelf.delete_message("nope").is_err());
+
+        // 送信に失敗した質問は取り除ける
+        shelf.add_to_messages("user".to_string(), "failed".to_string(), None);
+        shelf.drop_unanswered();
+        assert_eq!(shelf.get_messages().len(), 4);
+        shelf.drop_unanswered();
+        assert_eq!(shelf.get_messages().len(), 4);
+    }
+
+    #[test]
+    fn test_validate_roles() {
+        let message = |role: &str, excluded: bool| Message {
+            role: role.to_string(),
+            excluded,
+            ..Default::default()
+        };
+        assert!(validate_roles(&[]).is_ok());
+        assert!(validate_roles(&[message("user", false), message("assistant", false)]).is_ok());
+        assert!(validate_roles(&[message("assistant", false)]).is_err());
+        assert!(validate_roles(&[message("user", false)]).is_err());
+        assert!(validate_roles(&[
+            message("user", false),
+            message("assistant", true),
+            message("assistant", false),
+        ])
+        .is_ok());
+    }
+
+    #[test]
+    fn test_trim_to_budget_keeps_pinned_and_last_turn() {
+        let mut shelf = Shelf::new();
+        let (q1, _) = exchange(&mut shelf, &"a".repeat(100), &"b".repeat(100));
+        exchange(&mut shelf, &"c".repeat(100), &"d".repeat(100));
+        exchange(&mut shelf, &"e".repeat(100), &"f".repeat(100));
+        shelf.add_to_messages("user".to_string(), "now".to_string(), None);
+        shelf.pin_message(&q1, true).unwrap();
+
+        let messages = shelf.get_messages();
+        assert_eq!(trim_to_budget(messages.clone(), None).len(), 7);
+
+        let trimmed = trim_to_budget(messages, Some(250));
+        let firsts = trimmed
+            .iter()
+            .map(|m| m.content.chars().next().unwrap())
+            .collect::<String>();
+        assert_eq!(firsts, "abefn");
+    }
+
+    #[test]
+    fn test_get_system_returns_system_messages_not_messages() {
+        let mut shelf = Shelf::new();
+        shelf.add_to_messages("user".to_string(), "hello".to_string(), None);
+        shelf.system_messages.add("system".to_string(), "be strict".to_string(), None);
+
+        let system = shelf.get_system();
+        assert_eq!(system.len(), 1);
+        assert_eq!(system[0].content, "be strict");
+        assert_eq!(system[0].role, "system");
     }
 }
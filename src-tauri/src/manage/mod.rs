@@ -1,6 +1,23 @@
+pub mod capabilities;
 pub mod chatgpt;
 pub mod claude;
+pub mod compare;
+pub mod debate;
+pub mod embedding;
 pub mod filetitle;
+pub mod gallery;
+pub mod gateway;
 pub mod gemini;
+pub mod generation;
+pub mod layers;
 pub mod message;
+pub mod models;
+pub mod persona;
+pub mod provider;
+pub mod rag;
+pub mod search;
+pub mod secrets;
+pub mod settings;
+pub mod transport;
 pub mod utils;
+pub mod variables;
@@ -0,0 +1,248 @@
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::result::Result;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::manage::{capabilities, provider::Provider, secrets, transport};
+
+// 取得した一覧を使い回す時間
+const TTL: Duration = Duration::from_secs(60 * 60);
+
+// OpenAI の一覧から外す音声・画像・埋め込みなどのモデル
+const OPENAI_EXCLUDE: [&str; 9] = [
+    "audio",
+    "realtime",
+    "transcribe",
+    "tts",
+    "image",
+    "search",
+    "embedding",
+    "instruct",
+    "moderation",
+];
+
+/// モデル選択用の1件
+/// 性能が不明なものは None
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ModelInfo {
+    pub id: String,
+    pub provider: Provider,
+    pub name: String,
+    pub context_window: Option<u32>,
+    pub max_output: Option<u32>,
+    pub vision: Option<bool>,
+    pub tools: Option<bool>,
+}
+
+impl ModelInfo {
+    /// 既知の性能で埋める
+    pub fn new(provider: Provider, id: &str, name: Option<&str>) -> Self {
+        let caps = capabilities::lookup(id);
+        ModelInfo {
+            id: id.to_string(),
+            provider,
+            name: name.unwrap_or(id).to_string(),
+            context_window: caps.map(|c| c.context_window),
+            max_output: caps.map(|c| c.max_output),
+            vision: caps.map(|c| c.vision),
+            tools: caps.map(|c| c.tools),
+        }
+    }
+}
+
+/// 各APIのモデル一覧の応答から、会話に使えるモデルを取り出す
+pub fn parse(provider: Provider, res: &Value) -> Vec<ModelInfo> {
+    let empty = Vec::new();
+    match provider {
+        Provider::Claude => res["data"]
+            .as_array()
+            .unwrap_or(&empty)
+            .iter()
+            .filter_map(|m| {
+                let id = m["id"].as_str()?;
+                Some(ModelInfo::new(provider, id, m["display_name"].as_str()))
+            })
+            .collect(),
+        Provider::ChatGPT => {
+            // 新しいものから並べる
+            let mut data = res["data"].as_array().unwrap_or(&empty).clone();
+            data.sort_by_key(|m| std::cmp::Reverse(m["created"].as_i64().unwrap_or(0)));
+            data.iter()
+                .filter_map(|m| m["id"].as_str())
+                .filter(|id| is_openai_chat(id))
+                .map(|id| ModelInfo::new(provider, id, None))
+                .collect()
+        }
+        Provider::Gemini => res["models"]
+            .as_array()
+            .unwrap_or(&empty)
+            .iter()
+            .filter(|m| {
+                m["supportedGenerationMethods"]
+                    .as_array()
+                    .is_some_and(|methods| methods.iter().any(|x| x == "generateContent"))
+            })
+            .filter_map(|m| {
+                let id = m["name"].as_str()?;
+                let id = id.strip_prefix("models/").unwrap_or(id);
+                let mut info = ModelInfo::new(provider, id, m["displayName"].as_str());
+                // Gemini は一覧に上限が載っている
+                if let Some(input) = m["inputTokenLimit"].as_u64() {
+                    info.context_window = Some(input as u32);
+                }
+                if let Some(output) = m["outputTokenLimit"].as_u64() {
+                    info.max_output = Some(output as u32);
+                }
+                Some(info)
+            })
+            .collect(),
+    }
+}
+
+fn is_openai_chat(id: &str) -> bool {
+    let base = id.strip_prefix("ft:").unwrap_or(id);
+    ["gpt-", "chatgpt-", "o1", "o3", "o4"]
+        .iter()
+        .any(|prefix| base.starts_with(prefix))
+        && !OPENAI_EXCLUDE.iter().any(|word| base.contains(word))
+}
+
+// 取得した時刻と一覧
+type Listed = (Instant, Vec<ModelInfo>);
+
+static CACHE: Mutex<Option<HashMap<Provider, Listed>>> = Mutex::new(None);
+
+/// キーを変えたら、前のキーで取得した一覧を使わない
+pub fn clear_cache(provider: Provider) {
+    if let Some(cache) = CACHE.lock().unwrap().as_mut() {
+        cache.remove(&provider);
+    }
+}
+
+fn cached(provider: Provider) -> Option<Vec<ModelInfo>> {
+    let cache = CACHE.lock().unwrap();
+    match cache.as_ref()?.get(&provider) {
+        Some((fetched, models)) if fetched.elapsed() < TTL => Some(models.clone()),
+        _ => None,
+    }
+}
+
+/// モデル一覧を API から取得する。TTL の間は取得済みの一覧を返す
+pub async fn discover(provider: Provider, refresh: bool) -> Result<Vec<ModelInfo>, String> {
+    if !refresh {
+        if let Some(models) = cached(provider) {
+            return Ok(models);
+        }
+    }
+
+    let key = transport::key(provider)?;
+    let res = secrets::list_models(provider, &key).await?;
+    if let Some(message) = secrets::api_error(provider, &res) {
+        return Err(format!(
+            "failed to list {} models: {}",
+            provider.name(),
+            message
+        ));
+    }
+    let models = parse(provider, &res);
+
+    CACHE
+        .lock()
+        .unwrap()
+        .get_or_insert_with(HashMap::new)
+        .insert(provider, (Instant::now(), models.clone()));
+    Ok(models)
+}
+
+/// provider を省略するとキーのある全 AI の一覧をつなげる
+/// その場合、取得に失敗した AI は飛ばす
+#[tauri::command]
+pub async fn list_models(
+    provider: Option<Provider>,
+    refresh: Option<bool>,
+) -> Result<Vec<ModelInfo>, String> {
+    let refresh = refresh.unwrap_or(false);
+    if let Some(provider) = provider {
+        return discover(provider, refresh).await;
+    }
+
+    let mut models = Vec::new();
+    for provider in Provider::ALL.into_iter().filter(|p| p.configured()) {
+        match discover(provider, refresh).await {
+            Ok(found) => models.extend(found),
+            Err(e) => log::warn!("{}", e),
+        }
+    }
+    Ok(models)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_model_lists() {
+        let openai = json!({"data": [
+            {"id": "gpt-4o", "created": 1},
+            {"id": "text-embedding-3-small", "created": 3},
+            {"id": "gpt-4o-realtime-preview", "created": 4},
+            {"id": "o3-mini", "created": 2},
+            {"id": "dall-e-3", "created": 5}
+        ]});
+        let ids = parse(Provider::ChatGPT, &openai)
+            .into_iter()
+            .map(|m| m.id)
+            .collect::<Vec<String>>();
+        assert_eq!(ids, vec!["o3-mini", "gpt-4o"]);
+
+        let claude =
+            json!({"data": [{"id": "claude-sonnet-4-5", "display_name": "Claude Sonnet 4.5"}]});
+        let models = parse(Provider::Claude, &claude);
+        assert_eq!(models[0].name, "Claude Sonnet 4.5");
+        assert_eq!(models[0].context_window, Some(200_000));
+        assert_eq!(models[0].vision, Some(true));
+
+        let gemini = json!({"models": [
+            {"name": "models/gemini-2.5-flash", "displayName": "Gemini 2.5 Flash",
+             "inputTokenLimit": 1048576, "outputTokenLimit": 65536,
+             "supportedGenerationMethods": ["generateContent", "countTokens"]},
+            {"name": "models/text-embedding-004", "supportedGenerationMethods": ["embedContent"]}
+        ]});
+        let models = parse(Provider::Gemini, &gemini);
+        assert_eq!(models.len(), 1);
+        assert_eq!(models[0].id, "gemini-2.5-flash");
+        assert_eq!(models[0].max_output, Some(65536));
+    }
+
+    #[test]
+    fn test_clear_cache_forgets_one_provider() {
+        let listed = (
+            Instant::now(),
+            vec![ModelInfo::new(Provider::Claude, "claude-x", None)],
+        );
+        CACHE
+            .lock()
+            .unwrap()
+            .get_or_insert_with(HashMap::new)
+            .extend([
+                (Provider::Claude, listed.clone()),
+                (Provider::Gemini, listed),
+            ]);
+        clear_cache(Provider::Claude);
+        assert!(cached(Provider::Claude).is_none());
+        assert!(cached(Provider::Gemini).is_some());
+        clear_cache(Provider::Gemini);
+    }
+
+    #[test]
+    fn test_unknown_model_has_no_capabilities() {
+        let info = ModelInfo::new(Provider::ChatGPT, "ft:gpt-4o-mini:org::abc", None);
+        assert_eq!(info.tools, Some(true));
+        let info = ModelInfo::new(Provider::Claude, "claude-next", None);
+        assert_eq!(info.context_window, None);
+        assert_eq!(info.vision, None);
+    }
+}
@@ -0,0 +1,469 @@
+use serde::{Deserialize, Serialize};
+use std::fs::create_dir_all;
+use std::path::{Path, PathBuf};
+use std::{
+    result::Result,
+    sync::{Arc, Mutex},
+};
+use tauri::State;
+
+use log::warn;
+
+use crate::manage::{self, generation::GenerationConfig, provider::Provider, utils};
+use crate::sub;
+
+/// 高性能か低価格か
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Tier {
+    High,
+    Low,
+}
+
+impl Tier {
+    /// 各 *_request コマンドの b
+    pub fn b(&self) -> u8 {
+        match self {
+            Tier::High => 1,
+            Tier::Low => 0,
+        }
+    }
+}
+
+/// システムプロンプトと、既定のAIなどの組
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Persona {
+    // ファイルに無ければファイル名を使う
+    #[serde(default)]
+    pub name: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub prompt: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provider: Option<Provider>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tier: Option<Tier>,
+    // temperature, max_tokens などの生成パラメータ
+    #[serde(flatten)]
+    pub generation: GenerationConfig,
+    // 棒読みちゃんの声。VOICEID の代わりに使う
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub voice: Option<i16>,
+    // 組み込みのペルソナは編集・削除できない
+    #[serde(
+        default,
+        skip_deserializing,
+        skip_serializing_if = "std::ops::Not::not"
+    )]
+    pub builtin: bool,
+}
+
+impl Persona {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.name.trim().is_empty() {
+            return Err("persona name is empty".to_string());
+        }
+        self.generation.check_range()
+    }
+}
+
+/// ペルソナのファイル形式
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Toml,
+    Yaml,
+    // YAML の front matter と、本文のシステムプロンプト
+    Markdown,
+}
+
+impl Format {
+    pub fn from_path(path: &Path) -> Option<Format> {
+        match path.extension()?.to_str()?.to_lowercase().as_str() {
+            "toml" => Some(Format::Toml),
+            "yaml" | "yml" => Some(Format::Yaml),
+            "md" | "markdown" => Some(Format::Markdown),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Toml => "toml",
+            Format::Yaml => "yaml",
+            Format::Markdown => "md",
+        }
+    }
+}
+
+/// ファイルの内容を読む。name が無ければ stem を使う
+pub fn parse(content: &str, format: Format, stem: &str) -> Result<Persona, String> {
+    let mut persona: Persona = match format {
+        Format::Toml => toml::from_str(content).map_err(|e| format!("{}", e))?,
+        Format::Yaml => serde_yaml::from_str(content).map_err(|e| format!("{}", e))?,
+        Format::Markdown => {
+            let content = content.replace("\r\n", "\n");
+            let front = content
+                .strip_prefix("---\n")
+                .and_then(|rest| rest.split_once("\n---"));
+            match front {
+                Some((front, body)) => {
+                    let mut persona: Persona = if front.trim().is_empty() {
+                        Persona::default()
+                    } else {
+                        serde_yaml::from_str(front).map_err(|e| format!("{}", e))?
+                    };
+                    // 閉じの --- の行の残りを除く
+                    let body = body.split_once('\n').map(|(_, b)| b).unwrap_or("");
+                    persona.prompt = body.trim().to_string();
+                    persona
+                }
+                None => Persona {
+                    prompt: content.trim().to_string(),
+                    ..Default::default()
+                },
+            }
+        }
+    };
+    if persona.name.trim().is_empty() {
+        persona.name = stem.to_string();
+    }
+    persona.builtin = false;
+    Ok(persona)
+}
+
+/// ファイルに書く内容
+pub fn render(persona: &Persona, format: Format) -> Result<String, String> {
+    match format {
+        Format::Toml => toml::to_string(persona).map_err(|e| format!("{}", e)),
+        Format::Yaml => serde_yaml::to_string(persona).map_err(|e| format!("{}", e)),
+        Format::Markdown => {
+            let front = Persona {
+                prompt: String::new(),
+                ..persona.clone()
+            };
+            let front = serde_yaml::to_string(&front).map_err(|e| format!("{}", e))?;
+            Ok(format!("---\n{}---\n\n{}\n", front, persona.prompt))
+        }
+    }
+}
+
+/// 組み込みのペルソナ。request_system の 1〜4 の順
+pub fn builtins() -> Vec<Persona> {
+    sub::prompts::BUILTIN
+        .iter()
+        .map(|(name, prompt)| Persona {
+            name: name.to_string(),
+            prompt: prompt.to_string(),
+            builtin: true,
+            ..Default::default()
+        })
+        .collect()
+}
+
+/// ファイル名に使えない文字を - にする
+fn slug(name: &str) -> String {
+    name.trim()
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect()
+}
+
+/// ペルソナの保存先
+/// Documents/.appdata/Talk with RustGPT/personas に *.toml, *.yaml, *.md を置く
+pub struct Library {
+    dir: PathBuf,
+}
+
+impl Library {
+    pub fn open() -> Result<Self, String> {
+        let dir = utils::app_data_dir()?.join("personas");
+        Ok(Self::at(dir))
+    }
+
+    pub fn at(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    /// ファイルのペルソナ。読めないファイルは飛ばす
+    fn files(&self) -> Result<Vec<(PathBuf, Persona)>, String> {
+        if !self.dir.exists() {
+            return Ok(Vec::new());
+        }
+        let entries = std::fs::read_dir(&self.dir)
+            .map_err(|e| format!("failed to read persona dir: {}", e))?;
+
+        let mut personas = Vec::new();
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let Some(format) = Format::from_path(&path) else {
+                continue;
+            };
+            let stem = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .unwrap_or_default()
+                .to_string();
+            let parsed = std::fs::read_to_string(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|content| parse(&content, format, &stem));
+            match parsed {
+                Ok(persona) => personas.push((path, persona)),
+                Err(e) => warn!("skip persona {:?}: {}", path, e),
+            }
+        }
+        personas.sort_by(|a, b| a.1.name.cmp(&b.1.name));
+        Ok(personas)
+    }
+
+    /// 組み込み、ファイルの順
+    pub fn list(&self) -> Result<Vec<Persona>, String> {
+        let mut personas = builtins();
+        personas.extend(self.files()?.into_iter().map(|(_, p)| p));
+        Ok(personas)
+    }
+
+    pub fn get(&self, name: &str) -> Result<Persona, String> {
+        self.list()?
+            .into_iter()
+            .find(|p| p.name == name)
+            .ok_or(format!("persona not found: {}", name))
+    }
+
+    fn path_of(&self, name: &str) -> Result<PathBuf, String> {
+        if builtins().iter().any(|p| p.name == name) {
+            return Err(format!("built-in persona cannot be changed: {}", name));
+        }
+        self.files()?
+            .into_iter()
+            .find(|(_, p)| p.name == name)
+            .map(|(path, _)| path)
+            .ok_or(format!("persona not found: {}", name))
+    }
+
+    fn write(&self, path: &Path, persona: &Persona, format: Format) -> Result<(), String> {
+        create_dir_all(&self.dir).map_err(|e| format!("failed to create persona dir: {}", e))?;
+        std::fs::write(path, render(persona, format)?)
+            .map_err(|e| format!("failed to write persona: {}", e))
+    }
+
+    pub fn create(&self, persona: Persona, format: Format) -> Result<Persona, String> {
+        persona.validate()?;
+        if self.list()?.iter().any(|p| p.name == persona.name) {
+            return Err(format!("persona already exists: {}", persona.name));
+        }
+
+        let slug = slug(&persona.name);
+        let mut path = self.dir.join(format!("{}.{}", slug, format.extension()));
+        let mut n = 2;
+        while path.exists() {
+            path = self
+                .dir
+                .join(format!("{}-{}.{}", slug, n, format.extension()));
+            n += 1;
+        }
+
+        let persona = Persona {
+            builtin: false,
+            ..persona
+        };
+        self.write(&path, &persona, format)?;
+        Ok(persona)
+    }
+
+    /// 同じ形式のまま書き換える。名前を変えた場合はファイルも作り直す
+    pub fn update(&self, name: &str, persona: Persona) -> Result<Persona, String> {
+        persona.validate()?;
+        let path = self.path_of(name)?;
+        let format = Format::from_path(&path).unwrap_or_default();
+
+        if persona.name == name {
+            let persona = Persona {
+                builtin: false,
+                ..persona
+            };
+            self.write(&path, &persona, format)?;
+            return Ok(persona);
+        }
+
+        let persona = self.create(persona, format)?;
+        std::fs::remove_file(&path).map_err(|e| format!("failed to remove persona: {}", e))?;
+        Ok(persona)
+    }
+
+    /// 組み込みのペルソナも複製でき、複製は編集できる
+    pub fn duplicate(&self, name: &str, new_name: &str) -> Result<Persona, String> {
+        let source = self.get(name)?;
+        let format = if source.builtin {
+            Format::default()
+        } else {
+            self.path_of(name)
+                .ok()
+                .and_then(|path| Format::from_path(&path))
+                .unwrap_or_default()
+        };
+        self.create(
+            Persona {
+                name: new_name.to_string(),
+                ..source
+            },
+            format,
+        )
+    }
+
+    pub fn delete(&self, name: &str) -> Result<(), String> {
+        let path = self.path_of(name)?;
+        std::fs::remove_file(path).map_err(|e| format!("failed to remove persona: {}", e))
+    }
+}
+
+#[tauri::command]
+pub fn list_personas() -> Result<Vec<Persona>, String> {
+    Library::open()?.list()
+}
+
+/// format 省略時は TOML で保存する
+#[tauri::command]
+pub fn create_persona(persona: Persona, format: Option<Format>) -> Result<Persona, String> {
+    Library::open()?.create(persona, format.unwrap_or_default())
+}
+
+#[tauri::command]
+pub fn update_persona(name: &str, persona: Persona) -> Result<Persona, String> {
+    Library::open()?.update(name, persona)
+}
+
+#[tauri::command]
+pub fn duplicate_persona(name: &str, new_name: &str) -> Result<Persona, String> {
+    Library::open()?.duplicate(name, new_name)
+}
+
+#[tauri::command]
+pub fn delete_persona(name: &str) -> Result<String, String> {
+    Library::open()?.delete(name)?;
+    Ok("success".to_string())
+}
+
+/// ペルソナのシステムプロンプトを使う
+/// 既定のAIやモデルの種類は返り値を見て画面側で切り替える
+#[tauri::command]
+pub fn use_persona(
+    name: &str,
+    state: State<'_, Arc<Mutex<manage::message::Shelf>>>,
+) -> Result<Persona, String> {
+    let persona = Library::open()?.get(name)?;
+    let mut shelf = state.lock().unwrap();
+    shelf.set_persona(Some(persona.clone()));
+    Ok(persona)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn library(name: &str) -> Library {
+        let dir = std::env::temp_dir().join(format!(
+            "talkwithrust-persona-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        Library::at(dir)
+    }
+
+    #[test]
+    fn test_parse_each_format() {
+        let toml = "name = \"reviewer\"\nprovider = \"claude\"\ntier = \"high\"\ntemperature = 0.2\nprompt = \"\"\"\nReview code.\nBe strict.\"\"\"\n";
+        let persona = parse(toml, Format::Toml, "file").unwrap();
+        assert_eq!(persona.name, "reviewer");
+        assert_eq!(persona.provider, Some(Provider::Claude));
+        assert_eq!(persona.tier.map(|t| t.b()), Some(1));
+        assert_eq!(persona.prompt, "Review code.\nBe strict.");
+        assert_eq!(persona.generation.temperature, Some(0.2));
+
+        let yaml = "prompt: hello\nvoice: 3\n";
+        let persona = parse(yaml, Format::Yaml, "greeter").unwrap();
+        assert_eq!(persona.name, "greeter");
+        assert_eq!(persona.voice, Some(3));
+
+        let md = "---\nname: 翻訳\nprovider: gemini\n---\n\n英語に翻訳してください。\n";
+        let persona = parse(md, Format::Markdown, "x").unwrap();
+        assert_eq!(persona.name, "翻訳");
+        assert_eq!(persona.provider, Some(Provider::Gemini));
+        assert_eq!(persona.prompt, "英語に翻訳してください。");
+
+        let persona = parse("just a prompt", Format::Markdown, "plain").unwrap();
+        assert_eq!(persona.name, "plain");
+        assert_eq!(persona.prompt, "just a prompt");
+
+        // 書き出したものを読み戻せる
+        for format in [Format::Toml, Format::Yaml, Format::Markdown] {
+            let text = render(&persona, format).unwrap();
+            assert_eq!(parse(&text, format, "other").unwrap(), persona);
+        }
+    }
+
+    #[test]
+    fn test_library_crud() {
+        let library = library("crud");
+        assert_eq!(library.list().unwrap().len(), 4);
+        assert!(library.get("strict").unwrap().builtin);
+
+        // 組み込みは編集・削除できず、複製は編集できる
+        assert!(library.delete("strict").is_err());
+        let copy = library.duplicate("strict", "strict copy").unwrap();
+        assert!(!copy.builtin);
+        assert_eq!(copy.prompt, library.get("strict").unwrap().prompt);
+        assert!(library.dir.join("strict-copy.toml").exists());
+
+        let created = library
+            .create(
+                Persona {
+                    name: "notes".to_string(),
+                    prompt: "Take notes.".to_string(),
+                    ..Default::default()
+                },
+                Format::Markdown,
+            )
+            .unwrap();
+        assert!(library
+            .create(created.clone(), Format::Toml)
+            .unwrap_err()
+            .contains("already exists"));
+
+        // 名前を変えても形式は変わらない
+        let renamed = library
+            .update(
+                "notes",
+                Persona {
+                    name: "memo".to_string(),
+                    ..created
+                },
+            )
+            .unwrap();
+        assert_eq!(renamed.name, "memo");
+        assert!(library.dir.join("memo.md").exists());
+        assert!(!library.dir.join("notes.md").exists());
+
+        library.delete("memo").unwrap();
+        library.delete("strict copy").unwrap();
+        assert_eq!(library.list().unwrap().len(), 4);
+
+        let invalid = Persona {
+            name: "hot".to_string(),
+            generation: GenerationConfig {
+                temperature: Some(3.0),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(library.create(invalid, Format::Toml).is_err());
+        let _ = std::fs::remove_dir_all(&library.dir);
+    }
+}
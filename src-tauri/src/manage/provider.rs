@@ -0,0 +1,457 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::result::Result;
+
+use crate::manage::{
+    capabilities, chatgpt, claude, gemini,
+    generation::GenerationConfig,
+    message::{Message, Meta},
+    secrets::{self, Source},
+    utils,
+};
+
+/// 会話を送る先のAI
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Provider {
+    Claude,
+    ChatGPT,
+    Gemini,
+}
+
+impl Provider {
+    pub const ALL: [Provider; 3] = [Provider::Claude, Provider::ChatGPT, Provider::Gemini];
+
+    /// name() の逆。"openai" も ChatGPT として受け付ける
+    pub fn from_name(name: &str) -> Option<Provider> {
+        match name.to_lowercase().as_str() {
+            "claude" | "anthropic" => Some(Provider::Claude),
+            "chatgpt" | "openai" => Some(Provider::ChatGPT),
+            "gemini" | "google" => Some(Provider::Gemini),
+            _ => None,
+        }
+    }
+
+    /// キーがあれば使える。無い AI は一覧やリクエストから外す
+    pub fn configured(&self) -> bool {
+        secrets::key(*self).is_some()
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Provider::Claude => "claude",
+            Provider::ChatGPT => "chatgpt",
+            Provider::Gemini => "gemini",
+        }
+    }
+
+    /// model の既定の最大トークン数。max_tokens は高性能か低価格かの既定値
+    pub fn max_tokens(&self, model: &str, max_tokens: u32) -> u32 {
+        match self {
+            Provider::ChatGPT => chatgpt::default_max_tokens(model, max_tokens),
+            _ => max_tokens,
+        }
+    }
+
+    /// b == 1 で高性能、それ以外は低価格なモデルと最大トークン数
+    /// 各 *_request コマンドと同じ設定を使う
+    pub fn model(&self, b: u8) -> (String, u32) {
+        match self {
+            Provider::Claude => {
+                let (high, low) = claude::model();
+                if b == 1 {
+                    (high, 8192)
+                } else {
+                    (low, 4096)
+                }
+            }
+            Provider::ChatGPT => {
+                let (high, low) = chatgpt::model();
+                if b == 1 {
+                    (high, 4096)
+                } else {
+                    (low, 16384)
+                }
+            }
+            Provider::Gemini => {
+                let (high, low) = gemini::model();
+                if b == 1 {
+                    (high, 8192)
+                } else {
+                    (low, 8192)
+                }
+            }
+        }
+    }
+}
+
+/// AI ごとの接続状態
+/// valid, reachable, models は確かめていなければ None
+#[derive(Debug, Clone, Serialize)]
+pub struct ProviderStatus {
+    pub provider: Provider,
+    pub configured: bool,
+    pub source: Option<Source>,
+    pub hint: Option<String>,
+    // キーが受け付けられたか
+    pub valid: Option<bool>,
+    // API に接続できたか
+    pub reachable: Option<bool>,
+    // 使えるモデル数
+    pub models: Option<usize>,
+    pub error: Option<String>,
+}
+
+impl ProviderStatus {
+    /// モデル一覧の取得で確かめる (課金されない)
+    pub async fn check(provider: Provider) -> Self {
+        let mut status = ProviderStatus {
+            provider,
+            configured: false,
+            source: None,
+            hint: None,
+            valid: None,
+            reachable: None,
+            models: None,
+            error: None,
+        };
+        let Some((key, source)) = secrets::get(provider) else {
+            status.error = Some(format!("set {} or save a key", secrets::env_var(provider)));
+            return status;
+        };
+        status.configured = true;
+        status.source = Some(source);
+        status.hint = Some(secrets::hint(&key));
+
+        match secrets::list_models(provider, &key).await {
+            Ok(res) => {
+                status.reachable = Some(true);
+                match secrets::api_error(provider, &res) {
+                    Some(message) => {
+                        status.valid = Some(false);
+                        status.error = Some(message);
+                    }
+                    None => {
+                        status.valid = Some(true);
+                        status.models = count_models(&res);
+                    }
+                }
+            }
+            Err(e) => {
+                status.reachable = Some(false);
+                status.error = Some(secrets::redact(&e));
+            }
+        }
+        status
+    }
+}
+
+/// OpenAI, Claude は data、Gemini は models に一覧が入る
+fn count_models(res: &Value) -> Option<usize> {
+    res["data"]
+        .as_array()
+        .or(res["models"].as_array())
+        .map(|models| models.len())
+}
+
+/// 全 AI の状態を同時に確かめる
+#[tauri::command]
+pub async fn provider_status() -> Result<Vec<ProviderStatus>, String> {
+    let handles = Provider::ALL.map(|provider| tokio::spawn(ProviderStatus::check(provider)));
+    let mut statuses = Vec::new();
+    for handle in handles {
+        statuses.push(handle.await.map_err(|e| format!("task error: {}", e))?);
+    }
+    Ok(statuses)
+}
+
+/// 質問する相手
+/// 比較モードや討論モードで、AIとモデルの組を指定する
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Target {
+    pub provider: Provider,
+    // 1: 高性能, それ以外: 低価格
+    #[serde(default)]
+    pub b: u8,
+    // 指定すれば b より優先する
+    #[serde(default)]
+    pub model: Option<String>,
+}
+
+impl Target {
+    /// (AI, モデル, 最大トークン数)
+    pub fn resolve(&self) -> (Provider, String, u32) {
+        let (default_model, max_tokens) = self.provider.model(self.b);
+        let model = self
+            .model
+            .clone()
+            .filter(|m| !m.is_empty())
+            .unwrap_or(default_model);
+        (
+            self.provider,
+            model.clone(),
+            self.provider.max_tokens(&model, max_tokens),
+        )
+    }
+
+    /// 回答ラベル用の provider:model
+    pub fn label(&self) -> String {
+        let (provider, model, _) = self.resolve();
+        format!("{}:{}", provider.name(), model)
+    }
+}
+
+/// 1回分の回答
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Reply {
+    pub provider: Provider,
+    pub model: String,
+    pub text: String,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    pub latency_ms: u64,
+    // 推定料金(USD)。単価が不明なモデルは None
+    pub cost: Option<f64>,
+    pub finish_reason: Option<String>,
+    pub request_id: Option<String>,
+    pub warnings: Vec<String>,
+}
+
+impl Reply {
+    /// 履歴に残す回答の情報
+    pub fn meta(&self) -> Meta {
+        Meta {
+            provider: self.provider.name().to_string(),
+            model: self.model.clone(),
+            input_tokens: self.input_tokens,
+            output_tokens: self.output_tokens,
+            latency_ms: self.latency_ms,
+            finish_reason: self.finish_reason.clone(),
+            request_id: self.request_id.clone(),
+            warnings: self.warnings.clone(),
+        }
+    }
+}
+
+/// 履歴とシステムプロンプトを各APIの形式で送信し、回答を得る
+/// config の max_tokens があれば max_tokens より優先する。履歴の更新は呼び出し側で行う
+pub async fn ask(
+    provider: Provider,
+    model: &str,
+    max_tokens: u32,
+    messages: &[Message],
+    system_prompt: &str,
+    config: &GenerationConfig,
+) -> Result<Reply, String> {
+    let start_time = chrono::Local::now();
+
+    config.validate(provider, model)?;
+    let max_tokens = config.max_tokens.unwrap_or(max_tokens);
+    let (body, adapted) = prepare(
+        provider,
+        model,
+        max_tokens,
+        messages.to_vec(),
+        system_prompt,
+        config,
+    )?;
+    let (text, res) = match provider {
+        Provider::Claude => {
+            let res = claude::inner(body).await?;
+            (utils::get_content_for_claude(&res)?.0, res)
+        }
+        Provider::ChatGPT => {
+            let res = chatgpt::inner(body).await?;
+            (utils::get_content_for_chatgpt(&res)?.0, res)
+        }
+        Provider::Gemini => {
+            let res: Value = gemini::inner(model, body).await?;
+            (utils::get_content_for_gemini(&res)?.0, res)
+        }
+    };
+    let mut meta = meta(provider, model, &res, start_time);
+    meta.warnings.extend(adapted);
+
+    Ok(Reply {
+        provider,
+        model: model.to_string(),
+        text,
+        input_tokens: meta.input_tokens,
+        output_tokens: meta.output_tokens,
+        latency_ms: meta.latency_ms,
+        cost: cost(model, meta.input_tokens, meta.output_tokens),
+        finish_reason: meta.finish_reason,
+        request_id: meta.request_id,
+        warnings: meta.warnings,
+    })
+}
+
+/// モデルの性能に合わせて、各APIのリクエストボディを作る
+/// 読めない添付やツールはエラーにし、外したパラメータなどは警告として返す
+pub fn prepare(
+    provider: Provider,
+    model: &str,
+    max_tokens: u32,
+    messages: Vec<Message>,
+    system_prompt: &str,
+    config: &GenerationConfig,
+) -> Result<(Value, Vec<String>), String> {
+    let (messages, mut warnings) =
+        capabilities::adapt_messages(provider, model, max_tokens, messages, system_prompt)?;
+    let mut body = match provider {
+        Provider::Claude => claude::to_body(model, max_tokens, &messages, system_prompt),
+        Provider::ChatGPT => chatgpt::to_body(model, max_tokens, &messages, system_prompt),
+        Provider::Gemini => gemini::to_body(model, max_tokens, &messages, system_prompt),
+    };
+    config.apply(provider, &mut body);
+    warnings.extend(capabilities::adapt_body(provider, model, &mut body)?);
+    Ok((body, warnings))
+}
+
+/// 各APIのレスポンスから、トークン数や終了理由などを取り出す
+/// latency_ms は start_time から今までの時間
+pub fn meta(
+    provider: Provider,
+    model: &str,
+    res: &Value,
+    start_time: chrono::DateTime<chrono::Local>,
+) -> Meta {
+    let ((input_tokens, output_tokens), finish_reason) = match provider {
+        Provider::Claude => (
+            utils::get_usage_for_claude(res),
+            utils::get_finish_for_claude(res),
+        ),
+        Provider::ChatGPT => (
+            utils::get_usage_for_chatgpt(res),
+            utils::get_finish_for_chatgpt(res),
+        ),
+        Provider::Gemini => (
+            utils::get_usage_for_gemini(res),
+            utils::get_finish_for_gemini(res),
+        ),
+    };
+    let latency_ms = chrono::Local::now()
+        .signed_duration_since(start_time)
+        .num_milliseconds()
+        .max(0) as u64;
+
+    Meta {
+        provider: provider.name().to_string(),
+        model: model.to_string(),
+        input_tokens,
+        output_tokens,
+        latency_ms,
+        warnings: warnings(finish_reason.as_deref()),
+        finish_reason,
+        request_id: utils::get_request_id(res),
+    }
+}
+
+/// 終了理由から、利用者に伝える注意を作る
+fn warnings(finish_reason: Option<&str>) -> Vec<String> {
+    match finish_reason {
+        Some(reason @ ("max_tokens" | "length" | "MAX_TOKENS")) => vec![format!(
+            "the answer was cut off by the token limit ({})",
+            reason
+        )],
+        Some(reason @ ("content_filter" | "refusal" | "SAFETY" | "RECITATION" | "BLOCKLIST")) => {
+            vec![format!("the answer was blocked by a filter ({})", reason)]
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// 推定料金(USD)。単価は capabilities の登録から引く
+pub fn cost(model: &str, input_tokens: u64, output_tokens: u64) -> Option<f64> {
+    let (input, output) = capabilities::price(model)?;
+
+    Some((input_tokens as f64 * input + output_tokens as f64 * output) / 1_000_000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_provider_serde_names() {
+        let p: Provider = serde_json::from_str(r#""chatgpt""#).unwrap();
+        assert_eq!(p, Provider::ChatGPT);
+        assert_eq!(
+            serde_json::to_string(&Provider::Claude).unwrap(),
+            r#""claude""#
+        );
+
+        for provider in Provider::ALL {
+            assert_eq!(Provider::from_name(provider.name()), Some(provider));
+        }
+        assert_eq!(Provider::from_name("OpenAI"), Some(Provider::ChatGPT));
+        assert_eq!(Provider::from_name("mistral"), None);
+    }
+
+    #[test]
+    fn test_count_models_reads_each_list_shape() {
+        let openai =
+            serde_json::json!({"object": "list", "data": [{"id": "gpt-4o"}, {"id": "o3"}]});
+        let gemini = serde_json::json!({"models": [{"name": "models/gemini-2.5-pro"}]});
+        assert_eq!(count_models(&openai), Some(2));
+        assert_eq!(count_models(&gemini), Some(1));
+        assert_eq!(count_models(&serde_json::json!({"error": {}})), None);
+    }
+
+    #[test]
+    fn test_target_model_overrides_tier() {
+        let target = Target {
+            provider: Provider::Gemini,
+            b: 1,
+            model: Some("gemini-2.5-pro".to_string()),
+        };
+        let (provider, model, _) = target.resolve();
+        assert_eq!(provider, Provider::Gemini);
+        assert_eq!(model, "gemini-2.5-pro");
+        assert_eq!(target.label(), "gemini:gemini-2.5-pro");
+
+        let target: Target = serde_json::from_str(r#"{"provider": "claude"}"#).unwrap();
+        assert_eq!(target.resolve().1, Provider::Claude.model(0).0);
+    }
+
+    #[test]
+    fn test_meta_reads_usage_finish_reason_and_warnings() {
+        let res: Value = serde_json::from_str(
+            r#"{"id": "msg_1", "stop_reason": "max_tokens", "usage": {"input_tokens": 10, "output_tokens": 5}}"#,
+        )
+        .unwrap();
+        let meta = meta(
+            Provider::Claude,
+            "claude-3-5-haiku",
+            &res,
+            chrono::Local::now(),
+        );
+        assert_eq!(meta.provider, "claude");
+        assert_eq!((meta.input_tokens, meta.output_tokens), (10, 5));
+        assert_eq!(meta.finish_reason.as_deref(), Some("max_tokens"));
+        assert_eq!(meta.request_id.as_deref(), Some("msg_1"));
+        assert_eq!(meta.warnings.len(), 1);
+
+        let res: Value =
+            serde_json::from_str(r#"{"candidates": [{"finishReason": "STOP"}]}"#).unwrap();
+        let meta = super::meta(
+            Provider::Gemini,
+            "gemini-2.0-flash",
+            &res,
+            chrono::Local::now(),
+        );
+        assert!(meta.warnings.is_empty());
+        assert_eq!(meta.request_id, None);
+    }
+
+    #[test]
+    fn test_cost_uses_longest_prefix() {
+        let mini = cost("gpt-4o-mini-2024-07-18", 1_000_000, 0).unwrap();
+        assert!((mini - 0.15).abs() < 1e-9);
+
+        let full = cost("gpt-4o", 0, 1_000_000).unwrap();
+        assert!((full - 10.0).abs() < 1e-9);
+
+        assert!(cost("unknown-model", 10, 10).is_none());
+    }
+}
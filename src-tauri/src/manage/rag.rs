@@ -0,0 +1,589 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fs::create_dir_all;
+use std::path::{Path, PathBuf};
+use std::{
+    result::Result,
+    sync::{Arc, Mutex},
+};
+use tauri::State;
+
+use log::info;
+
+use crate::manage::{self, message::Message, provider::Provider, settings, utils};
+use crate::sub::tokenize::tokenize;
+
+// 1チャンクの行数と、前のチャンクとの重なり
+const CHUNK_LINES: usize = 40;
+const OVERLAP_LINES: usize = 10;
+// これより大きいファイルは索引しない
+const MAX_FILE_BYTES: u64 = 512 * 1024;
+// 索引しないディレクトリ
+const SKIP_DIRS: &[&str] = &["target", "node_modules", "dist", "build", "__pycache__"];
+// ハッシュ埋め込みの次元数
+const HASHING_DIMS: usize = 512;
+// 埋め込みAPIへ1回に送るチャンク数
+const BATCH_SIZE: usize = 64;
+// 質問に添えるチャンク数
+pub const TOP_K: usize = 5;
+
+/// 埋め込みの作成方法
+/// local は CPU で動く文埋め込みモデルで、APIキーを使わずに意味で検索する (初回だけモデルを取得する)
+/// hashing は語のハッシュを次元に割り当てるだけの手法で、意味ではなく語の一致で検索する
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Embedder {
+    Local,
+    Hashing,
+    OpenAI,
+    Gemini,
+}
+
+/// ファイルの一部分と、その埋め込み
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Chunk {
+    pub path: String,
+    pub start_line: usize,
+    pub end_line: usize,
+    pub text: String,
+    pub vector: Vec<f32>,
+}
+
+/// フォルダ単位のベクトル索引
+/// Documents/.appdata/Talk with RustGPT/rag/<name>.json に保存する
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Index {
+    pub name: String,
+    pub root: String,
+    pub embedder: Embedder,
+    pub created_at: String,
+    pub chunks: Vec<Chunk>,
+}
+
+/// 回答の下に表示する出典
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Citation {
+    pub path: String,
+    pub start_line: usize,
+    pub end_line: usize,
+    pub score: f32,
+}
+
+impl Index {
+    pub fn dir() -> Result<PathBuf, String> {
+        Ok(utils::app_data_dir()?.join("rag"))
+    }
+
+    /// 索引ファイルのパス
+    pub fn path(name: &str) -> Result<PathBuf, String> {
+        check_name(name)?;
+        Ok(Self::dir()?.join(format!("{}.json", name)))
+    }
+
+    pub fn load(name: &str) -> Result<Self, String> {
+        let path = Self::path(name)?;
+        let data = std::fs::read_to_string(path)
+            .map_err(|e| format!("index not found: {}: {}", name, e))?;
+        let index: Self =
+            serde_json::from_str(&data).map_err(|e| format!("failed to parse index: {}", e))?;
+        Ok(index.migrated())
+    }
+
+    /// 以前の "local" はハッシュ埋め込みだったため、次元数で見分けて hashing として読む
+    fn migrated(mut self) -> Self {
+        let hashed = self.chunks.first().map(|c| c.vector.len()) == Some(HASHING_DIMS);
+        if self.embedder == Embedder::Local && hashed {
+            self.embedder = Embedder::Hashing;
+        }
+        self
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        let path = Self::path(&self.name)?;
+        create_dir_all(Self::dir()?.as_path())
+            .map_err(|e| format!("failed to create rag dir: {}", e))?;
+        let data =
+            serde_json::to_string(self).map_err(|e| format!("failed to serialize index: {}", e))?;
+        info!("save rag index to {:?}", path);
+        std::fs::write(path, data).map_err(|e| format!("failed to write index: {}", e))
+    }
+
+    /// 質問に近いチャンクを類似度の高い順に返す
+    pub fn search(&self, query_vector: &[f32], k: usize) -> Vec<(f32, &Chunk)> {
+        let mut hits = self
+            .chunks
+            .iter()
+            .map(|chunk| (cosine(query_vector, &chunk.vector), chunk))
+            .collect::<Vec<(f32, &Chunk)>>();
+        hits.sort_by(|a, b| b.0.total_cmp(&a.0));
+        hits.truncate(k);
+        hits
+    }
+}
+
+/// 索引ディレクトリの外を指さないよう、名前は英数字と - _ だけにする (C:x のようなドライブ指定も通さない)
+fn check_name(name: &str) -> Result<(), String> {
+    let allowed = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+    if name.is_empty() || !name.chars().all(allowed) {
+        return Err(format!("invalid index name: {}", name));
+    }
+    Ok(())
+}
+
+/// フォルダ以下のテキストファイルを行単位のチャンクに分ける
+pub fn collect_chunks(root: &Path) -> Result<Vec<Chunk>, String> {
+    if !root.is_dir() {
+        return Err(format!("not a directory: {}", root.display()));
+    }
+
+    let mut files = Vec::new();
+    walk(root, &mut files);
+    files.sort();
+
+    let mut chunks = Vec::new();
+    for file in files {
+        // バイナリなど UTF-8 で読めないファイルは飛ばす
+        let Ok(content) = std::fs::read_to_string(&file) else {
+            continue;
+        };
+        let relative = file.strip_prefix(root).unwrap_or(&file);
+        chunks.extend(split_lines(&relative.to_string_lossy(), &content));
+    }
+
+    Ok(chunks)
+}
+
+fn walk(dir: &Path, files: &mut Vec<PathBuf>) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with('.') {
+            continue;
+        }
+        if path.is_dir() {
+            if !SKIP_DIRS.contains(&name.as_str()) {
+                walk(&path, files);
+            }
+        } else if entry
+            .metadata()
+            .map(|m| m.len() <= MAX_FILE_BYTES)
+            .unwrap_or(false)
+        {
+            files.push(path);
+        }
+    }
+}
+
+/// CHUNK_LINES 行ずつ、OVERLAP_LINES 行重ねて分割する
+/// 行番号は 1 始まり
+pub fn split_lines(path: &str, content: &str) -> Vec<Chunk> {
+    let lines = content.lines().collect::<Vec<&str>>();
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while start < lines.len() {
+        let end = (start + CHUNK_LINES).min(lines.len());
+        let text = lines[start..end].join("\n");
+        if !text.trim().is_empty() {
+            chunks.push(Chunk {
+                path: path.to_string(),
+                start_line: start + 1,
+                end_line: end,
+                text,
+                vector: Vec::new(),
+            });
+        }
+        if end == lines.len() {
+            break;
+        }
+        start = end - OVERLAP_LINES;
+    }
+    chunks
+}
+
+/// 語のハッシュを次元に割り当てた埋め込み (feature hashing)
+/// 同じ語を含むかどうかしか表さない
+pub fn embed_hashing(text: &str) -> Vec<f32> {
+    let mut vector = vec![0.0; HASHING_DIMS];
+    for token in tokenize(text) {
+        // FNV-1a
+        let hash = token.bytes().fold(0xcbf29ce484222325u64, |h, b| {
+            (h ^ b as u64).wrapping_mul(0x100000001b3)
+        });
+        let sign = if hash >> 63 == 0 { 1.0 } else { -1.0 };
+        vector[(hash % HASHING_DIMS as u64) as usize] += sign;
+    }
+    normalize(&mut vector);
+    vector
+}
+
+fn normalize(vector: &mut [f32]) {
+    let norm = vector.iter().map(|v| v * v).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|v| *v /= norm);
+    }
+}
+
+pub fn cosine(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+    let dot = a.iter().zip(b).map(|(x, y)| x * y).sum::<f32>();
+    let na = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let nb = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if na == 0.0 || nb == 0.0 {
+        0.0
+    } else {
+        dot / (na * nb)
+    }
+}
+
+/// テキストをまとめて埋め込む
+pub async fn embed(embedder: Embedder, texts: &[String]) -> Result<Vec<Vec<f32>>, String> {
+    match embedder {
+        Embedder::Local => return manage::embedding::embed(texts).await,
+        Embedder::Hashing => return Ok(texts.iter().map(|t| embed_hashing(t)).collect()),
+        _ => {}
+    }
+
+    let mut vectors = Vec::new();
+    for batch in texts.chunks(BATCH_SIZE) {
+        let batch_vectors = match embedder {
+            Embedder::OpenAI => embed_openai(batch).await?,
+            Embedder::Gemini => embed_gemini(batch).await?,
+            Embedder::Local | Embedder::Hashing => unreachable!(),
+        };
+        vectors.extend(batch_vectors);
+    }
+    Ok(vectors)
+}
+
+async fn embed_openai(texts: &[String]) -> Result<Vec<Vec<f32>>, String> {
+    let key = manage::transport::key(Provider::ChatGPT)?;
+    let model = settings::current()
+        .openai_embedding_model
+        .unwrap_or(String::from("text-embedding-3-small"));
+
+    let v = manage::transport::post_json(
+        "https://api.openai.com/v1/embeddings",
+        &[("Authorization", format!("Bearer {}", key))],
+        &json!({ "model": model, "input": texts }),
+    )
+    .await?;
+
+    let data = v["data"]
+        .as_array()
+        .ok_or(format!("data not found, error: {:?}", v))?;
+    Ok(data.iter().map(|d| to_vector(&d["embedding"])).collect())
+}
+
+async fn embed_gemini(texts: &[String]) -> Result<Vec<Vec<f32>>, String> {
+    let model = settings::current()
+        .gemini_embedding_model
+        .unwrap_or(String::from("text-embedding-004"));
+
+    let body = json!({
+        "requests": texts.iter().map(|t| {
+            json!({
+                "model": format!("models/{}", model),
+                "content": { "parts": [{ "text": t }] },
+            })
+        }).collect::<Vec<_>>(),
+    });
+    let v = manage::gemini::request(&model, "batchEmbedContents", body).await?;
+
+    let embeddings = v["embeddings"]
+        .as_array()
+        .ok_or(format!("embeddings not found, error: {:?}", v))?;
+    Ok(embeddings.iter().map(|e| to_vector(&e["values"])).collect())
+}
+
+fn to_vector(v: &Value) -> Vec<f32> {
+    v.as_array()
+        .map(|values| {
+            values
+                .iter()
+                .map(|x| x.as_f64().unwrap_or(0.0) as f32)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// 送信する履歴の最後のユーザー発言に、索引から探した資料を添える
+/// 履歴そのものは書き換えず、送信用のコピーだけを変更する
+pub async fn augment(name: &str, messages: &mut [Message]) -> Result<Vec<Citation>, String> {
+    let Some(last) = messages.iter_mut().rev().find(|m| m.role == "user") else {
+        return Ok(Vec::new());
+    };
+
+    let index = Index::load(name)?;
+    let query = embed(index.embedder, std::slice::from_ref(&last.content)).await?;
+    let hits = index.search(&query[0], TOP_K);
+    if hits.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let context = hits
+        .iter()
+        .enumerate()
+        .map(|(i, (_, chunk))| {
+            format!(
+                "[{}] {}:{}-{}\n```\n{}\n```",
+                i + 1,
+                chunk.path,
+                chunk.start_line,
+                chunk.end_line,
+                chunk.text
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("\n\n");
+    last.content = format!(
+        "以下の資料を参考に回答してください。資料を使った箇所には [番号] で出典を示してください。\n\n{}\n\n# 質問\n{}",
+        context, last.content
+    );
+
+    Ok(hits
+        .iter()
+        .map(|(score, chunk)| Citation {
+            path: chunk.path.clone(),
+            start_line: chunk.start_line,
+            end_line: chunk.end_line,
+            score: *score,
+        })
+        .collect())
+}
+
+/// 回答HTMLの下に付ける出典一覧
+pub fn citations_html(citations: &[Citation]) -> String {
+    if citations.is_empty() {
+        return String::new();
+    }
+
+    let items = citations
+        .iter()
+        .enumerate()
+        .map(|(i, c)| {
+            format!(
+                "<li>[{}] {}:{}-{}</li>",
+                i + 1,
+                ammonia::clean_text(&c.path),
+                c.start_line,
+                c.end_line
+            )
+        })
+        .collect::<String>();
+    format!("<hr><p>Sources:</p><ol>{}</ol>", items)
+}
+
+/// フォルダを索引する
+#[tauri::command]
+pub async fn rag_index_folder(
+    name: &str,
+    path: PathBuf,
+    embedder: Embedder,
+) -> Result<String, String> {
+    Index::path(name)?;
+
+    let mut chunks = collect_chunks(&path)?;
+    if chunks.is_empty() {
+        return Err(format!("no text files in {}", path.display()));
+    }
+
+    let texts = chunks
+        .iter()
+        .map(|c| c.text.clone())
+        .collect::<Vec<String>>();
+    let vectors = embed(embedder, &texts).await?;
+    if vectors.len() != chunks.len() {
+        return Err("embedding count does not match chunk count".to_string());
+    }
+    for (chunk, vector) in chunks.iter_mut().zip(vectors) {
+        chunk.vector = vector;
+    }
+
+    let files = chunks
+        .iter()
+        .map(|c| c.path.as_str())
+        .collect::<std::collections::HashSet<&str>>()
+        .len();
+    let index = Index {
+        name: name.to_string(),
+        root: path.display().to_string(),
+        embedder,
+        created_at: chrono::Local::now().to_rfc3339(),
+        chunks,
+    };
+    index.save()?;
+
+    Ok(json!({
+        "name": index.name,
+        "files": files,
+        "chunks": index.chunks.len(),
+    })
+    .to_string())
+}
+
+/// 索引の一覧
+#[tauri::command]
+pub fn rag_list() -> Result<Vec<String>, String> {
+    let dir = Index::dir()?;
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut names = std::fs::read_dir(dir)
+        .map_err(|e| format!("failed to read rag dir: {}", e))?
+        .flatten()
+        .filter_map(|entry| {
+            let path = entry.path();
+            if path.extension()? == "json" {
+                Some(path.file_stem()?.to_string_lossy().to_string())
+            } else {
+                None
+            }
+        })
+        .collect::<Vec<String>>();
+    names.sort();
+    Ok(names)
+}
+
+#[tauri::command]
+pub fn rag_delete(name: &str) -> Result<String, String> {
+    let path = Index::path(name)?;
+    std::fs::remove_file(path).map_err(|e| format!("failed to delete index: {}", e))?;
+    Ok("success".to_string())
+}
+
+/// 以降のリクエストで使う索引を設定する。None で解除
+#[tauri::command]
+pub fn rag_use(
+    name: Option<String>,
+    state: State<'_, Arc<Mutex<manage::message::Shelf>>>,
+) -> Result<String, String> {
+    if let Some(name) = name.as_deref().filter(|n| !n.is_empty()) {
+        Index::load(name)?;
+    }
+
+    let mut shelf = state.lock().unwrap();
+    shelf.rag = name.filter(|n| !n.is_empty());
+    Ok("success".to_string())
+}
+
+/// 索引を検索する
+#[tauri::command]
+pub async fn rag_search(name: &str, query: &str) -> Result<Vec<Citation>, String> {
+    let index = Index::load(name)?;
+    let query = embed(index.embedder, &[query.to_string()]).await?;
+
+    Ok(index
+        .search(&query[0], TOP_K)
+        .into_iter()
+        .map(|(score, chunk)| Citation {
+            path: chunk.path.clone(),
+            start_line: chunk.start_line,
+            end_line: chunk.end_line,
+            score,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_index_names_cannot_leave_the_rag_dir() {
+        for name in [
+            "../x", "..\\x", "a/b", "x.json", "", "C:x", "C:\\x", "名前", "a b",
+        ] {
+            assert!(Index::path(name).is_err(), "{}", name);
+            assert!(Index::load(name).is_err(), "{}", name);
+        }
+        let err = rag_delete("../x").unwrap_err();
+        assert!(err.starts_with("invalid index name"));
+        assert!(check_name("my-project_2").is_ok());
+    }
+
+    #[test]
+    fn test_old_local_indexes_are_read_as_hashing() {
+        let index = |embedder: Embedder, dims: usize| Index {
+            name: "test".to_string(),
+            root: ".".to_string(),
+            embedder,
+            created_at: String::new(),
+            chunks: vec![Chunk {
+                path: "a.txt".to_string(),
+                start_line: 1,
+                end_line: 1,
+                text: "a".to_string(),
+                vector: vec![0.0; dims],
+            }],
+        };
+        let old = index(Embedder::Local, HASHING_DIMS).migrated();
+        assert_eq!(old.embedder, Embedder::Hashing);
+        let new = index(Embedder::Local, manage::embedding::DIMS).migrated();
+        assert_eq!(new.embedder, Embedder::Local);
+    }
+
+    #[test]
+    fn test_split_lines_overlaps_and_numbers_from_one() {
+        let content = (1..=85)
+            .map(|i| format!("line {}", i))
+            .collect::<Vec<String>>()
+            .join("\n");
+        let chunks = split_lines("src/main.rs", &content);
+
+        let ranges = chunks
+            .iter()
+            .map(|c| (c.start_line, c.end_line))
+            .collect::<Vec<_>>();
+        assert_eq!(ranges, vec![(1, 40), (31, 70), (61, 85)]);
+        assert!(chunks[0].text.starts_with("line 1\n"));
+        assert!(chunks[2].text.ends_with("line 85"));
+    }
+
+    #[test]
+    fn test_hashing_embedding_ranks_related_chunk_first() {
+        let chunks = ["東京の天気は晴れです", "fn main() { println!(\"hello\"); }"]
+            .iter()
+            .enumerate()
+            .map(|(i, text)| Chunk {
+                path: format!("{}.txt", i),
+                start_line: 1,
+                end_line: 1,
+                text: text.to_string(),
+                vector: embed_hashing(text),
+            })
+            .collect();
+        let index = Index {
+            name: "test".to_string(),
+            root: ".".to_string(),
+            embedder: Embedder::Hashing,
+            created_at: String::new(),
+            chunks,
+        };
+
+        let hits = index.search(&embed_hashing("東京の天気"), 2);
+        assert_eq!(hits[0].1.path, "0.txt");
+        assert!(hits[0].0 > hits[1].0);
+
+        let hits = index.search(&embed_hashing("println main"), 1);
+        assert_eq!(hits[0].1.path, "1.txt");
+    }
+
+    #[test]
+    fn test_citations_html_escapes_paths() {
+        let html = citations_html(&[Citation {
+            path: "<script>.rs".to_string(),
+            start_line: 3,
+            end_line: 9,
+            score: 0.5,
+        }]);
+        assert!(!html.contains("<script>"));
+        assert!(html.contains(":3-9"));
+        assert!(citations_html(&[]).is_empty());
+    }
+}
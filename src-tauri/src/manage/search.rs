@@ -0,0 +1,540 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::create_dir_all;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use std::{
+    result::Result,
+    sync::{Arc, Mutex},
+};
+use tauri::State;
+
+use crate::manage::{
+    self,
+    message::{self, Message},
+    rag::{self, Embedder},
+    utils,
+};
+use crate::sub::tokenize::tokenize;
+
+const INDEX_FILE: &str = "memo-index.json";
+// BM25 のパラメータ
+const K1: f32 = 1.2;
+const B: f32 = 0.75;
+// 埋め込みに使う先頭の文字数
+const EMBED_CHARS: usize = 8000;
+// スニペットの前後の文字数
+const SNIPPET_CHARS: usize = 60;
+const MAX_HITS: usize = 20;
+
+/// メモ1件分の索引
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Doc {
+    pub filename: String,
+    pub modified: u64,
+    pub length: usize,
+    pub terms: HashMap<String, u32>,
+    // 埋め込み方法ごとのベクトル
+    #[serde(default)]
+    pub vectors: HashMap<String, Vec<f32>>,
+}
+
+/// メモの全文検索索引
+/// Documents/.appdata/Talk with RustGPT/search/memo-index.json に保存し、
+/// 更新されたメモだけを索引し直す
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MemoIndex {
+    pub docs: Vec<Doc>,
+}
+
+/// 検索結果1件
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Hit {
+    pub filename: String,
+    pub score: f32,
+    pub snippet: String,
+    pub modified: u64,
+}
+
+impl MemoIndex {
+    fn path() -> Result<PathBuf, String> {
+        Ok(utils::app_data_dir()?.join("search").join(INDEX_FILE))
+    }
+
+    pub fn load() -> Self {
+        Self::path()
+            .ok()
+            .and_then(|path| std::fs::read_to_string(path).ok())
+            .and_then(|data| serde_json::from_str(&data).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        let path = Self::path()?;
+        if let Some(dir) = path.parent() {
+            create_dir_all(dir).map_err(|e| format!("failed to create search dir: {}", e))?;
+        }
+        let data =
+            serde_json::to_string(self).map_err(|e| format!("failed to serialize index: {}", e))?;
+        std::fs::write(path, data).map_err(|e| format!("failed to write index: {}", e))
+    }
+
+    /// メモのディレクトリと索引を突き合わせ、追加・更新・削除を反映する
+    /// 変更があれば true
+    pub fn sync(&mut self, dir: &Path) -> Result<bool, String> {
+        let mut changed = false;
+        let files = memo_files(dir)?;
+
+        let before = self.docs.len();
+        self.docs
+            .retain(|d| files.iter().any(|(name, _)| *name == d.filename));
+        changed |= before != self.docs.len();
+
+        for (filename, modified) in files {
+            let current = self.docs.iter().position(|d| d.filename == filename);
+            if let Some(i) = current {
+                if self.docs[i].modified == modified {
+                    continue;
+                }
+            }
+
+            let content = read_memo(&dir.join(&filename));
+            let doc = to_doc(&filename, modified, &content);
+            match current {
+                Some(i) => self.docs[i] = doc,
+                None => self.docs.push(doc),
+            }
+            changed = true;
+        }
+
+        Ok(changed)
+    }
+
+    /// BM25 で採点する
+    pub fn bm25(&self, query: &str) -> Vec<(usize, f32)> {
+        let terms = tokenize(query);
+        if terms.is_empty() || self.docs.is_empty() {
+            return Vec::new();
+        }
+
+        let n = self.docs.len() as f32;
+        let avg_length = self.docs.iter().map(|d| d.length).sum::<usize>() as f32 / n;
+
+        let mut scores = vec![0.0; self.docs.len()];
+        for term in terms {
+            let df = self
+                .docs
+                .iter()
+                .filter(|d| d.terms.contains_key(&term))
+                .count() as f32;
+            if df == 0.0 {
+                continue;
+            }
+            let idf = ((n - df + 0.5) / (df + 0.5) + 1.0).ln();
+            for (i, doc) in self.docs.iter().enumerate() {
+                let Some(tf) = doc.terms.get(&term) else {
+                    continue;
+                };
+                let tf = *tf as f32;
+                let norm = 1.0 - B + B * doc.length as f32 / avg_length.max(1.0);
+                scores[i] += idf * tf * (K1 + 1.0) / (tf + K1 * norm);
+            }
+        }
+
+        scores
+            .into_iter()
+            .enumerate()
+            .filter(|(_, score)| *score > 0.0)
+            .collect()
+    }
+}
+
+/// 検索用にメモを読む。<!-- meta {...} --> の行は除く
+fn read_memo(path: &Path) -> String {
+    std::fs::read_to_string(path)
+        .unwrap_or_default()
+        .lines()
+        .filter(|line| !line.starts_with("<!-- meta "))
+        .collect::<Vec<&str>>()
+        .join("\n")
+}
+
+fn to_doc(filename: &str, modified: u64, content: &str) -> Doc {
+    let tokens = tokenize(content);
+    let mut terms = HashMap::new();
+    for token in &tokens {
+        *terms.entry(token.clone()).or_insert(0) += 1;
+    }
+    Doc {
+        filename: filename.to_string(),
+        modified,
+        length: tokens.len(),
+        terms,
+        vectors: HashMap::new(),
+    }
+}
+
+/// (ファイル名, 更新時刻) の一覧
+fn memo_files(dir: &Path) -> Result<Vec<(String, u64)>, String> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let entries = std::fs::read_dir(dir).map_err(|e| format!("failed to read memo dir: {}", e))?;
+    Ok(entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if !(name.starts_with("memo-") && name.ends_with(".txt")) {
+                return None;
+            }
+            let modified = entry
+                .metadata()
+                .and_then(|m| m.modified())
+                .ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            Some((name, modified))
+        })
+        .collect())
+}
+
+/// 最初に一致した語の前後を切り出す
+pub fn snippet(content: &str, query: &str) -> String {
+    // to_lowercase すると長さの変わる文字もあるため、元の文字列を1文字ずつ比べる
+    let chars = content.chars().collect::<Vec<char>>();
+    let center = tokenize(query)
+        .iter()
+        .filter_map(|term| find_ignore_case(&chars, term))
+        .min()
+        .unwrap_or(0);
+    let start = center.saturating_sub(SNIPPET_CHARS);
+    let end = (center + SNIPPET_CHARS).min(chars.len());
+
+    let mut text = chars[start..end]
+        .iter()
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ");
+    if start > 0 {
+        text = format!("…{}", text);
+    }
+    if end < chars.len() {
+        text.push('…');
+    }
+    text
+}
+
+/// 大文字小文字を区別せずに term を探し、文字単位の位置を返す
+fn find_ignore_case(chars: &[char], term: &str) -> Option<usize> {
+    let term = term.chars().collect::<Vec<char>>();
+    if term.is_empty() || term.len() > chars.len() {
+        return None;
+    }
+    let same = |a: char, b: char| a == b || a.to_lowercase().eq(b.to_lowercase());
+    (0..=chars.len() - term.len()).find(|&i| {
+        chars[i..i + term.len()]
+            .iter()
+            .zip(&term)
+            .all(|(a, b)| same(*a, *b))
+    })
+}
+
+/// メモの本文を履歴に戻す
+/// "user: ..." / "assistant: ...\n----------------" の形式を読む
+/// <!-- meta {...} --> の行があれば ID やトークン数なども戻す
+/// 本文中の "user: " で始まる行で分けないよう、発言が閉じてから空行の後に来た行だけを発言の始まりとする
+/// 閉じるのは meta の行、assistant の区切り線、または古いメモの user の後の assistant
+pub fn parse_memo(content: &str) -> Vec<Message> {
+    let mut messages: Vec<Message> = Vec::new();
+    let mut closed = true;
+    let mut after_blank = true;
+    for line in content.lines() {
+        if let Some(message) = messages.last_mut() {
+            if message.apply_memo_meta(line) {
+                closed = true;
+                continue;
+            }
+        }
+        if let Some((role, label, rest)) = parse_speaker(line) {
+            let starts = match messages.last() {
+                None => true,
+                Some(last) => {
+                    after_blank && (closed || (last.role == "user" && role == "assistant"))
+                }
+            };
+            if starts {
+                // ID は meta の行から戻すか、最後に fill_ids で振る
+                messages.push(Message {
+                    id: String::new(),
+                    role,
+                    content: rest.to_string(),
+                    label,
+                    ..Default::default()
+                });
+                closed = false;
+                after_blank = false;
+                continue;
+            }
+        }
+        if let Some(message) = messages.last_mut() {
+            message.content.push('\n');
+            message.content.push_str(line);
+            if message.role == "assistant" && line == "----------------" {
+                closed = true;
+            } else if !line.is_empty() {
+                closed = false;
+            }
+        }
+        after_blank = line.is_empty();
+    }
+
+    for message in messages.iter_mut() {
+        let trimmed = message.content.trim_end();
+        let trimmed = trimmed.strip_suffix("----------------").unwrap_or(trimmed);
+        message.content = trimmed.trim_end().to_string();
+    }
+    message::fill_ids(&mut messages, "");
+    messages
+}
+
+/// "assistant [label]: text" を (role, label, text) に分ける
+fn parse_speaker(line: &str) -> Option<(String, Option<String>, &str)> {
+    for role in ["user", "assistant"] {
+        let Some(rest) = line.strip_prefix(role) else {
+            continue;
+        };
+        if let Some(text) = rest.strip_prefix(": ") {
+            return Some((role.to_string(), None, text));
+        }
+        if rest == ":" {
+            return Some((role.to_string(), None, ""));
+        }
+        if let Some(rest) = rest.strip_prefix(" [") {
+            let (label, text) = rest.split_once("]: ")?;
+            return Some((role.to_string(), Some(label.to_string()), text));
+        }
+    }
+    None
+}
+
+/// メモを検索する
+/// embedder を指定すると、全文検索と埋め込みの類似度を半々で合わせて採点する
+#[tauri::command]
+pub async fn search_memos(query: &str, embedder: Option<Embedder>) -> Result<Vec<Hit>, String> {
+    let dir = utils::app_data_dir()?;
+    let mut index = MemoIndex::load();
+    // 埋め込みに失敗しても同期した結果は残す
+    if index.sync(&dir)? {
+        index.save()?;
+    }
+
+    let mut scores = index.bm25(query);
+    let max = scores.iter().map(|(_, s)| *s).fold(0.0, f32::max);
+
+    if let Some(embedder) = embedder {
+        let key = serde_json::to_string(&embedder).unwrap_or_default();
+
+        // 未計算のメモだけ埋め込む
+        let missing = index
+            .docs
+            .iter()
+            .enumerate()
+            .filter(|(_, d)| !d.vectors.contains_key(&key))
+            .map(|(i, d)| {
+                let content = read_memo(&dir.join(&d.filename));
+                (i, content.chars().take(EMBED_CHARS).collect::<String>())
+            })
+            .collect::<Vec<(usize, String)>>();
+        if !missing.is_empty() {
+            let texts = missing.iter().map(|(_, t)| t.clone()).collect::<Vec<_>>();
+            let vectors = rag::embed(embedder, &texts).await?;
+            for ((i, _), vector) in missing.iter().zip(vectors) {
+                index.docs[*i].vectors.insert(key.clone(), vector);
+            }
+            index.save()?;
+        }
+
+        let query_vector = rag::embed(embedder, &[query.to_string()]).await?;
+        let mut combined = vec![0.0; index.docs.len()];
+        for (i, score) in &scores {
+            combined[*i] += 0.5 * score / max.max(f32::EPSILON);
+        }
+        for (i, doc) in index.docs.iter().enumerate() {
+            if let Some(vector) = doc.vectors.get(&key) {
+                combined[i] += 0.5 * rag::cosine(&query_vector[0], vector).max(0.0);
+            }
+        }
+        scores = combined
+            .into_iter()
+            .enumerate()
+            .filter(|(_, s)| *s > 0.0)
+            .collect();
+    }
+
+    scores.sort_by(|a, b| b.1.total_cmp(&a.1));
+    scores.truncate(MAX_HITS);
+
+    Ok(scores
+        .into_iter()
+        .map(|(i, score)| {
+            let doc = &index.docs[i];
+            let content = read_memo(&dir.join(&doc.filename));
+            Hit {
+                filename: doc.filename.clone(),
+                score,
+                snippet: snippet(&content, query),
+                modified: doc.modified,
+            }
+        })
+        .collect())
+}
+
+/// メモを新しいセッションとして開く
+/// 現在の履歴はメモしてから置き換える
+#[tauri::command]
+pub fn open_memo(
+    filename: &str,
+    state: State<'_, Arc<Mutex<manage::message::Shelf>>>,
+) -> Result<String, String> {
+    if filename.contains(['/', '\\']) || !filename.starts_with("memo-") {
+        return Err(format!("invalid memo filename: {}", filename));
+    }
+
+    let path = utils::app_data_dir()?.join(filename);
+    let content =
+        std::fs::read_to_string(path).map_err(|e| format!("failed to read memo: {}", e))?;
+    let messages = parse_memo(&content);
+    if messages.is_empty() {
+        return Err(format!("no messages in memo: {}", filename));
+    }
+
+    let mut shelf = state.lock().unwrap();
+    if !shelf.get_messages().is_empty() {
+        shelf.memo()?;
+    }
+    shelf.reset()?;
+    shelf.messages.messages = messages;
+
+    Ok(format!("opened {}", filename))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_memo_roundtrip() {
+        let memo = "user: 東京の天気は？\n\nassistant: 晴れです。\n\n明日も晴れ。\n----------------\n\nuser: ありがとう\n\nassistant [critique 1 gemini:gemini-2.0-flash]: 根拠が不足\n----------------";
+        let messages = parse_memo(memo);
+        assert_eq!(messages.len(), 4);
+        assert_eq!(messages[0].role, "user");
+        assert_eq!(messages[0].content, "東京の天気は？");
+        assert_eq!(messages[1].content, "晴れです。\n\n明日も晴れ。");
+        assert_eq!(
+            messages[3].label.as_deref(),
+            Some("critique 1 gemini:gemini-2.0-flash")
+        );
+        assert_eq!(messages[3].content, "根拠が不足");
+        // meta のない古いメモは開くたびに同じ ID になる
+        assert_eq!(parse_memo(memo)[1].id, messages[1].id);
+        assert_ne!(messages[0].id, messages[2].id);
+    }
+
+    #[test]
+    fn test_parse_memo_restores_ids_and_meta() {
+        let mut shelf = manage::message::Shelf::new();
+        shelf.add_to_messages("user".to_string(), "hello".to_string(), None);
+        shelf.add_answer(
+            "hi\n\nthere".to_string(),
+            manage::message::Meta {
+                provider: "claude".to_string(),
+                model: "claude-3-5-haiku".to_string(),
+                output_tokens: 7,
+                ..Default::default()
+            },
+        );
+        shelf.messages.messages[0].starred = true;
+
+        let original = shelf.get_messages();
+        let messages = parse_memo(&shelf.memo_text());
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].id, original[0].id);
+        assert!(messages[0].starred);
+        assert_eq!(messages[1].content, "hi\n\nthere");
+        assert_eq!(messages[1].created_at, original[1].created_at);
+        assert_eq!(messages[1].meta.as_ref().unwrap().output_tokens, 7);
+    }
+
+    #[test]
+    fn test_bm25_ranks_matching_memo_first_and_syncs_changes() {
+        let dir = std::env::temp_dir().join(format!("talkwithrust-search-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("memo-1.txt"),
+            "user: 東京の天気を教えて\n\nassistant: 晴れです",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("memo-2.txt"),
+            "user: Rust の所有権\n\nassistant: borrow checker",
+        )
+        .unwrap();
+        std::fs::write(dir.join("note.txt"), "東京 東京 東京").unwrap();
+
+        let mut index = MemoIndex::default();
+        assert!(index.sync(&dir).unwrap());
+        assert_eq!(index.docs.len(), 2);
+        assert!(!index.sync(&dir).unwrap());
+
+        let scores = index.bm25("東京の天気");
+        assert_eq!(scores.len(), 1);
+        assert_eq!(index.docs[scores[0].0].filename, "memo-1.txt");
+
+        let scores = index.bm25("BORROW");
+        assert_eq!(index.docs[scores[0].0].filename, "memo-2.txt");
+
+        std::fs::remove_file(dir.join("memo-2.txt")).unwrap();
+        assert!(index.sync(&dir).unwrap());
+        assert_eq!(index.docs.len(), 1);
+    }
+
+    #[test]
+    fn test_parse_memo_keeps_speaker_like_lines_in_body() {
+        let mut shelf = manage::message::Shelf::new();
+        shelf.add_to_messages(
+            "user".to_string(),
+            "log:\n\nuser: admin\nassistant: bot".to_string(),
+            None,
+        );
+        shelf.add_to_messages(
+            "assistant".to_string(),
+            "see\n\nuser: admin is the login".to_string(),
+            None,
+        );
+        let messages = parse_memo(&shelf.memo_text());
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].content, "log:\n\nuser: admin\nassistant: bot");
+        assert_eq!(messages[1].content, "see\n\nuser: admin is the login");
+    }
+
+    #[test]
+    fn test_snippet_handles_case_changing_chars() {
+        // İ は小文字にすると2文字になる
+        let content = format!("{}Rust{}", "İ".repeat(200), "x".repeat(10));
+        let text = snippet(&content, "rust");
+        assert!(text.contains("Rust"));
+    }
+
+    #[test]
+    fn test_snippet_centers_on_match() {
+        let content = format!("{}東京の天気{}", "あ".repeat(100), "い".repeat(100));
+        let text = snippet(&content, "天気");
+        assert!(text.starts_with('…'));
+        assert!(text.ends_with('…'));
+        assert!(text.contains("東京の天気"));
+    }
+}
@@ -0,0 +1,578 @@
+use base64::Engine;
+use ring::{
+    aead::{self, Aad, LessSafeKey, Nonce, UnboundKey},
+    pbkdf2,
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::create_dir_all;
+use std::num::NonZeroU32;
+use std::path::PathBuf;
+use std::result::Result;
+use std::sync::Mutex;
+
+use crate::manage::{models, provider::Provider, settings::Settings, transport};
+
+pub const REDACTED: &str = "REDACTED";
+
+// キーチェーンのサービス名。tauri.conf.json の identifier と同じ
+const SERVICE: &str = "com.talkwithrustv2.app";
+const FILE: &str = "secrets.json";
+const ITERATIONS: u32 = 100_000;
+
+/// 各AIのキーを上書きする環境変数
+pub fn env_var(provider: Provider) -> &'static str {
+    match provider {
+        Provider::Claude => "ANTHROPIC_API_KEY",
+        Provider::ChatGPT => "CHATGPTTOKEN",
+        Provider::Gemini => "GOOGLE_GEMINI_API_KEY",
+    }
+}
+
+/// キーの取得元
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    Env,
+    Keyring,
+    File,
+}
+
+/// 暗号化したファイル
+/// キーチェーンが使えない環境 (ヘッドレスの Linux など) で使う
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct VaultFile {
+    version: u32,
+    salt: String,
+    entries: BTreeMap<String, Sealed>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Sealed {
+    nonce: String,
+    data: String,
+}
+
+/// 設定と同じディレクトリの secrets.json
+/// 鍵は SECRETS_PASSPHRASE から作り、未設定ならマシン固有の値を使う (同じマシンの他の利用者からは守れない)
+pub struct Vault {
+    path: PathBuf,
+    passphrase: String,
+}
+
+fn b64() -> base64::engine::GeneralPurpose {
+    base64::engine::general_purpose::STANDARD
+}
+
+impl Vault {
+    pub fn open() -> Result<Self, String> {
+        let path = Settings::path()?.with_file_name(FILE);
+        let passphrase = std::env::var("SECRETS_PASSPHRASE")
+            .ok()
+            .filter(|p| !p.is_empty())
+            .unwrap_or_else(machine_passphrase);
+        Ok(Self::at(path, passphrase))
+    }
+
+    pub fn at(path: PathBuf, passphrase: String) -> Self {
+        Self { path, passphrase }
+    }
+
+    fn load(&self) -> Result<VaultFile, String> {
+        if !self.path.exists() {
+            let mut salt = [0u8; 16];
+            SystemRandom::new()
+                .fill(&mut salt)
+                .map_err(|_| "failed to generate salt".to_string())?;
+            return Ok(VaultFile {
+                version: 1,
+                salt: b64().encode(salt),
+                entries: BTreeMap::new(),
+            });
+        }
+        let data = std::fs::read_to_string(&self.path)
+            .map_err(|e| format!("failed to read secrets: {}", e))?;
+        serde_json::from_str(&data).map_err(|e| format!("failed to parse secrets: {}", e))
+    }
+
+    fn save(&self, file: &VaultFile) -> Result<(), String> {
+        if let Some(dir) = self.path.parent() {
+            create_dir_all(dir).map_err(|e| format!("failed to create dir: {}", e))?;
+        }
+        let data = serde_json::to_string_pretty(file)
+            .map_err(|e| format!("failed to serialize secrets: {}", e))?;
+        std::fs::write(&self.path, data).map_err(|e| format!("failed to write secrets: {}", e))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let _ = std::fs::set_permissions(&self.path, std::fs::Permissions::from_mode(0o600));
+        }
+        Ok(())
+    }
+
+    fn key(&self, file: &VaultFile) -> Result<LessSafeKey, String> {
+        let salt = b64()
+            .decode(&file.salt)
+            .map_err(|e| format!("invalid salt: {}", e))?;
+        let mut key = [0u8; 32];
+        pbkdf2::derive(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            NonZeroU32::new(ITERATIONS).unwrap(),
+            &salt,
+            self.passphrase.as_bytes(),
+            &mut key,
+        );
+        let key = UnboundKey::new(&aead::CHACHA20_POLY1305, &key)
+            .map_err(|_| "failed to create key".to_string())?;
+        Ok(LessSafeKey::new(key))
+    }
+
+    pub fn get(&self, name: &str) -> Result<Option<String>, String> {
+        let file = self.load()?;
+        let Some(sealed) = file.entries.get(name) else {
+            return Ok(None);
+        };
+        let nonce: [u8; 12] = b64()
+            .decode(&sealed.nonce)
+            .ok()
+            .and_then(|n| n.try_into().ok())
+            .ok_or("invalid nonce")?;
+        let mut data = b64()
+            .decode(&sealed.data)
+            .map_err(|e| format!("invalid secret: {}", e))?;
+        let plain = self
+            .key(&file)?
+            .open_in_place(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(name.as_bytes()),
+                &mut data,
+            )
+            .map_err(|_| format!("failed to decrypt {} (wrong passphrase?)", name))?;
+        String::from_utf8(plain.to_vec())
+            .map(Some)
+            .map_err(|e| format!("invalid secret: {}", e))
+    }
+
+    pub fn set(&self, name: &str, secret: &str) -> Result<(), String> {
+        let mut file = self.load()?;
+        let mut nonce = [0u8; 12];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .map_err(|_| "failed to generate nonce".to_string())?;
+        let mut data = secret.as_bytes().to_vec();
+        self.key(&file)?
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(name.as_bytes()),
+                &mut data,
+            )
+            .map_err(|_| "failed to encrypt".to_string())?;
+        file.entries.insert(
+            name.to_string(),
+            Sealed {
+                nonce: b64().encode(nonce),
+                data: b64().encode(data),
+            },
+        );
+        self.save(&file)
+    }
+
+    /// 削除したら true
+    pub fn delete(&self, name: &str) -> Result<bool, String> {
+        if !self.path.exists() {
+            return Ok(false);
+        }
+        let mut file = self.load()?;
+        let removed = file.entries.remove(name).is_some();
+        if removed {
+            self.save(&file)?;
+        }
+        Ok(removed)
+    }
+}
+
+/// マシン ID と利用者名から作る合言葉
+fn machine_passphrase() -> String {
+    let id = ["/etc/machine-id", "/var/lib/dbus/machine-id"]
+        .iter()
+        .find_map(|path| std::fs::read_to_string(path).ok())
+        .or_else(|| std::env::var("COMPUTERNAME").ok())
+        .unwrap_or_default();
+    let user = std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_default();
+    format!("{}:{}:{}", SERVICE, id.trim(), user)
+}
+
+fn entry(provider: Provider) -> Result<keyring::Entry, keyring::Error> {
+    keyring::Entry::new(SERVICE, provider.name())
+}
+
+// 読み出したキー。無かったことも覚え、キーチェーンや暗号化ファイルへの問い合わせを毎回しない
+// set と delete で入れ替える
+type Cache = HashMap<Provider, Option<(String, Source)>>;
+static CACHE: Mutex<Option<Cache>> = Mutex::new(None);
+
+fn cache<T>(f: impl FnOnce(&mut Cache) -> T) -> T {
+    let mut cache = CACHE.lock().unwrap();
+    f(cache.get_or_insert_with(HashMap::new))
+}
+
+fn cached(
+    cache: &mut Cache,
+    provider: Provider,
+    load: impl FnOnce() -> Option<(String, Source)>,
+) -> Option<(String, Source)> {
+    cache.entry(provider).or_insert_with(load).clone()
+}
+
+/// 保存したキー (キーチェーン、無ければ暗号化ファイル)
+#[cfg(not(test))]
+fn stored(provider: Provider) -> Option<(String, Source)> {
+    cache(|c| cached(c, provider, || load(provider)))
+}
+
+// テストではキーチェーンも利用者の secrets.json も読まず、STORED に入れたキーだけを使う
+#[cfg(test)]
+thread_local! {
+    static STORED: std::cell::RefCell<HashMap<Provider, (String, Source)>> =
+        std::cell::RefCell::new(HashMap::new());
+}
+
+#[cfg(test)]
+fn stored(provider: Provider) -> Option<(String, Source)> {
+    STORED.with(|stored| stored.borrow().get(&provider).cloned())
+}
+
+#[cfg(not(test))]
+fn load(provider: Provider) -> Option<(String, Source)> {
+    match entry(provider).and_then(|e| e.get_password()) {
+        Ok(key) => Some((key, Source::Keyring)),
+        Err(e) => {
+            if !matches!(e, keyring::Error::NoEntry) {
+                log::warn!("keyring unavailable, using {}: {}", FILE, e);
+            }
+            Vault::open()
+                .and_then(|vault| vault.get(provider.name()))
+                .unwrap_or_else(|e| {
+                    log::warn!("{}", e);
+                    None
+                })
+                .map(|key| (key, Source::File))
+        }
+    }
+}
+
+/// 使用するキーと取得元
+/// 環境変数 (.env を含む) があれば優先する
+pub fn get(provider: Provider) -> Option<(String, Source)> {
+    match std::env::var(env_var(provider)) {
+        Ok(key) if !key.is_empty() => Some((key, Source::Env)),
+        _ => stored(provider),
+    }
+}
+
+/// 使用するキー
+pub fn key(provider: Provider) -> Option<String> {
+    get(provider).map(|(key, _)| key)
+}
+
+/// キーチェーンに保存する。使えなければ暗号化ファイルに保存する
+pub fn set(provider: Provider, key: &str) -> Result<Source, String> {
+    let key = key.trim();
+    if key.is_empty() || key.contains(char::is_whitespace) {
+        return Err(format!("invalid {} key", provider.name()));
+    }
+    let source = match entry(provider).and_then(|e| e.set_password(key)) {
+        Ok(_) => {
+            // 古い暗号化ファイルの分は残さない
+            if let Err(e) = Vault::open().and_then(|vault| vault.delete(provider.name())) {
+                log::warn!("{}", e);
+            }
+            Source::Keyring
+        }
+        Err(e) => {
+            log::warn!("keyring unavailable, using {}: {}", FILE, e);
+            Vault::open()?.set(provider.name(), key)?;
+            Source::File
+        }
+    };
+    cache(|c| c.insert(provider, Some((key.to_string(), source))));
+    models::clear_cache(provider);
+    Ok(source)
+}
+
+/// キーチェーンと暗号化ファイルの両方から消す
+pub fn delete(provider: Provider) -> Result<(), String> {
+    cache(|c| c.remove(&provider));
+    models::clear_cache(provider);
+    let keyring = match entry(provider).and_then(|e| e.delete_credential()) {
+        Ok(_) => true,
+        Err(keyring::Error::NoEntry) => false,
+        Err(e) => {
+            log::warn!("{}", e);
+            false
+        }
+    };
+    let file = Vault::open()?.delete(provider.name())?;
+    if !keyring && !file {
+        return Err(format!("no stored key for {}", provider.name()));
+    }
+    Ok(())
+}
+
+// キーの先頭 (OpenAI と Anthropic は sk-、Google は AIza)
+const KEY_PREFIXES: [&str; 2] = ["sk-", "AIza"];
+// 先頭に続く文字がこれより短ければキーとみなさない
+const KEY_MIN_BODY: usize = 16;
+
+/// 文章に含まれる API キーを伏せる (メモ、書き出し、カセット、エラー用)
+/// 環境変数、キーチェーン、暗号化ファイルのキーに加え、キーの形をした文字列も伏せる
+pub fn redact(text: &str) -> String {
+    let mut text = text.to_string();
+    for provider in Provider::ALL {
+        let keys = [
+            std::env::var(env_var(provider)).ok(),
+            stored(provider).map(|(key, _)| key),
+        ];
+        for key in keys.into_iter().flatten().filter(|k| k.len() >= 8) {
+            text = text.replace(&key, REDACTED);
+        }
+    }
+    redact_patterns(&text)
+}
+
+/// sk-... や AIza... の形の文字列を伏せる
+fn redact_patterns(text: &str) -> String {
+    let is_key_char = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some((start, prefix)) = KEY_PREFIXES
+        .iter()
+        .filter_map(|p| rest.find(p).map(|i| (i, *p)))
+        .min()
+    {
+        let before = rest[..start].chars().last();
+        let body = &rest[start + prefix.len()..];
+        let len = body.find(|c: char| !is_key_char(c)).unwrap_or(body.len());
+        out.push_str(&rest[..start]);
+        if len >= KEY_MIN_BODY && !before.is_some_and(is_key_char) {
+            out.push_str(REDACTED);
+        } else {
+            out.push_str(&rest[start..start + prefix.len() + len]);
+        }
+        rest = &body[len..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// 画面に出す末尾4文字だけのキー
+pub fn hint(key: &str) -> String {
+    let tail: String = key
+        .chars()
+        .rev()
+        .take(4)
+        .collect::<Vec<char>>()
+        .into_iter()
+        .rev()
+        .collect();
+    format!("…{}", tail)
+}
+
+/// 応答のエラーの文言。エラーでなければ None
+pub fn api_error(provider: Provider, res: &Value) -> Option<String> {
+    let error = match provider {
+        Provider::Claude if res["type"] == "error" => &res["error"],
+        Provider::Claude => return None,
+        _ if res["error"].is_null() => return None,
+        _ => &res["error"],
+    };
+    Some(
+        error["message"]
+            .as_str()
+            .map(|m| m.to_string())
+            .unwrap_or(error.to_string()),
+    )
+}
+
+/// モデル一覧の応答をそのまま返す。認証エラーも Ok の JSON になる
+/// Err は接続できなかったとき
+pub async fn list_models(provider: Provider, key: &str) -> Result<Value, String> {
+    match provider {
+        Provider::Claude => {
+            transport::get_json(
+                "https://api.anthropic.com/v1/models?limit=1000",
+                &[
+                    ("x-api-key", key.to_string()),
+                    ("anthropic-version", "2023-06-01".to_string()),
+                ],
+            )
+            .await
+        }
+        Provider::ChatGPT => {
+            transport::get_json(
+                "https://api.openai.com/v1/models",
+                &[("Authorization", format!("Bearer {}", key))],
+            )
+            .await
+        }
+        Provider::Gemini => {
+            transport::get_json(
+                &format!(
+                    "https://generativelanguage.googleapis.com/v1beta/models?pageSize=1000&key={}",
+                    key
+                ),
+                &[],
+            )
+            .await
+        }
+    }
+}
+
+/// モデル一覧を取得してキーを確かめる (課金されない)
+pub async fn check(provider: Provider, key: &str) -> Result<Value, String> {
+    let res = list_models(provider, key).await?;
+    match api_error(provider, &res) {
+        Some(message) => Err(format!("{} key was rejected: {}", provider.name(), message)),
+        None => Ok(res),
+    }
+}
+
+/// 保存したキーの状態
+#[derive(Debug, Clone, Serialize)]
+pub struct KeyStatus {
+    pub provider: Provider,
+    pub source: Source,
+    pub hint: String,
+}
+
+#[tauri::command]
+pub fn set_api_key(provider: Provider, key: &str) -> Result<KeyStatus, String> {
+    let source = set(provider, key)?;
+    Ok(KeyStatus {
+        provider,
+        source,
+        hint: hint(key.trim()),
+    })
+}
+
+/// 新しいキーを確かめてから置き換える。失敗したら古いキーのまま
+#[tauri::command]
+pub async fn rotate_api_key(provider: Provider, key: String) -> Result<KeyStatus, String> {
+    check(provider, key.trim()).await?;
+    set_api_key(provider, &key)
+}
+
+/// key を省略すると使用中のキーを確かめる
+#[tauri::command]
+pub async fn test_api_key(provider: Provider, key: Option<String>) -> Result<String, String> {
+    let key = match key.filter(|k| !k.is_empty()) {
+        Some(key) => key,
+        None => self::key(provider).ok_or(format!("no {} key", provider.name()))?,
+    };
+    check(provider, key.trim()).await?;
+    Ok("success".to_string())
+}
+
+#[tauri::command]
+pub fn delete_api_key(provider: Provider) -> Result<String, String> {
+    delete(provider)?;
+    Ok("success".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vault_encrypts_and_rejects_wrong_passphrase() {
+        let path =
+            std::env::temp_dir().join(format!("talkwithrust-secrets-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let vault = Vault::at(path.clone(), "passphrase".to_string());
+        assert_eq!(vault.get("claude").unwrap(), None);
+        vault.set("claude", "sk-ant-secret-key").unwrap();
+        vault.set("gemini", "AIza-secret-key").unwrap();
+        assert_eq!(
+            vault.get("claude").unwrap().as_deref(),
+            Some("sk-ant-secret-key")
+        );
+
+        let data = std::fs::read_to_string(&path).unwrap();
+        assert!(!data.contains("sk-ant-secret-key"));
+
+        let other = Vault::at(path.clone(), "other".to_string());
+        assert!(other.get("claude").is_err());
+
+        assert!(vault.delete("claude").unwrap());
+        assert!(!vault.delete("claude").unwrap());
+        assert_eq!(
+            vault.get("gemini").unwrap().as_deref(),
+            Some("AIza-secret-key")
+        );
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_stored_keys_are_cached_even_when_missing() {
+        let mut cache = Cache::new();
+        let mut loads = 0;
+        for _ in 0..3 {
+            assert_eq!(
+                cached(&mut cache, Provider::Claude, || {
+                    loads += 1;
+                    None
+                }),
+                None
+            );
+        }
+        assert_eq!(loads, 1);
+
+        cache.insert(
+            Provider::Claude,
+            Some(("sk-ant-new".to_string(), Source::Keyring)),
+        );
+        assert_eq!(
+            cached(&mut cache, Provider::Claude, || unreachable!()),
+            Some(("sk-ant-new".to_string(), Source::Keyring))
+        );
+    }
+
+    #[test]
+    fn test_redact_hint_and_api_error() {
+        STORED.with(|stored| {
+            stored.borrow_mut().insert(
+                Provider::Gemini,
+                ("AIza-cached-key-1234".to_string(), Source::Keyring),
+            )
+        });
+        assert_eq!(redact("key=AIza-cached-key-1234&x=1"), "key=REDACTED&x=1");
+        // 保存していないキーも形で伏せる
+        assert_eq!(
+            redact("Bearer sk-proj-abcdefghijklmnop1234 and sk-ant-REDACTED"),
+            "Bearer REDACTED and REDACTED"
+        );
+        assert_eq!(
+            redact("url?key=AIzaSyA1234567890abcdefgh"),
+            "url?key=REDACTED"
+        );
+        assert_eq!(
+            redact("task-based risk-free sk-short"),
+            "task-based risk-free sk-short"
+        );
+        assert_eq!(hint("AIza-cached-key-1234"), "…1234");
+
+        let rejected =
+            serde_json::json!({"type": "error", "error": {"message": "invalid x-api-key"}});
+        assert_eq!(
+            api_error(Provider::Claude, &rejected).as_deref(),
+            Some("invalid x-api-key")
+        );
+        let models = serde_json::json!({"data": [{"id": "gpt-4o"}]});
+        assert_eq!(api_error(Provider::ChatGPT, &models), None);
+    }
+}
@@ -0,0 +1,422 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fs::create_dir_all;
+use std::path::PathBuf;
+use std::result::Result;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use directories::BaseDirs;
+
+use crate::manage::provider::Provider;
+
+/// 設定ファイルの形式の版。項目の意味を変えるときに上げる
+pub const VERSION: u32 = 1;
+
+const FILE: &str = "settings.toml";
+// tauri.conf.json の identifier と同じ
+const IDENTIFIER: &str = "com.talkwithrustv2.app";
+
+/// アプリの設定
+/// 空欄は既定値を使う。環境変数 (.env を含む) があればそちらを優先する
+/// スキーマの additionalProperties: false と同じく、知らない項目はエラーにする
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    #[serde(default)]
+    pub version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chatgpt_high: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chatgpt_low: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub claude_high: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub claude_low: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gemini_high: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gemini_low: Option<String>,
+    // 棒読みちゃんの声
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub voice_id: Option<i16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image_model: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gemini_image_model: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reasoning_effort: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context_token_budget: Option<usize>,
+    // 索引とメモ検索の埋め込みモデル
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub openai_embedding_model: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gemini_embedding_model: Option<String>,
+}
+
+/// 設定を上書きする環境変数
+pub const ENV_OVERRIDES: [&str; 10] = [
+    "CHATGPT_MODELS",
+    "CLAUDE_MODELS",
+    "GEMINI_MODELS",
+    "VOICEID",
+    "IMAGE_MODEL",
+    "GEMINI_IMAGE_MODEL",
+    "REASONING_EFFORT",
+    "CONTEXT_TOKEN_BUDGET",
+    "OPENAI_EMBEDDING_MODEL",
+    "GEMINI_EMBEDDING_MODEL",
+];
+
+/// "high,low" を2つのモデル名に分ける
+pub fn split_models(value: &str) -> Option<(String, String)> {
+    let (high, low) = value.split_once(',')?;
+    let (high, low) = (high.trim(), low.trim());
+    if high.is_empty() || low.is_empty() || low.contains(',') {
+        return None;
+    }
+    Some((high.to_string(), low.to_string()))
+}
+
+impl Settings {
+    pub fn path() -> Result<PathBuf, String> {
+        let dirs = BaseDirs::new().ok_or("config directory not found")?;
+        Ok(dirs.config_dir().join(IDENTIFIER).join(FILE))
+    }
+
+    /// ファイルを読む。無ければ既定値
+    pub fn at(path: &PathBuf) -> Result<Self, String> {
+        if !path.exists() {
+            return Ok(Self {
+                version: VERSION,
+                ..Default::default()
+            });
+        }
+        let data =
+            std::fs::read_to_string(path).map_err(|e| format!("failed to read settings: {}", e))?;
+        let mut settings: Settings =
+            toml::from_str(&data).map_err(|e| format!("failed to parse settings: {}", e))?;
+        // 版の無いファイルは最初の版として扱う
+        if settings.version == 0 {
+            settings.version = VERSION;
+        }
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    pub fn save(&self, path: &PathBuf) -> Result<(), String> {
+        self.validate()?;
+        if let Some(dir) = path.parent() {
+            create_dir_all(dir).map_err(|e| format!("failed to create dir: {}", e))?;
+        }
+        let settings = Settings {
+            version: VERSION,
+            ..self.clone()
+        };
+        let data = toml::to_string(&settings)
+            .map_err(|e| format!("failed to serialize settings: {}", e))?;
+        std::fs::write(path, data).map_err(|e| format!("failed to write settings: {}", e))
+    }
+
+    /// 問題をまとめて1つのエラーにする
+    pub fn validate(&self) -> Result<(), String> {
+        let mut errors = Vec::new();
+        if self.version > VERSION {
+            errors.push(format!(
+                "version {} is newer than this app supports ({})",
+                self.version, VERSION
+            ));
+        }
+        let models = [
+            ("chatgpt_high", &self.chatgpt_high),
+            ("chatgpt_low", &self.chatgpt_low),
+            ("claude_high", &self.claude_high),
+            ("claude_low", &self.claude_low),
+            ("gemini_high", &self.gemini_high),
+            ("gemini_low", &self.gemini_low),
+            ("image_model", &self.image_model),
+            ("gemini_image_model", &self.gemini_image_model),
+            ("openai_embedding_model", &self.openai_embedding_model),
+            ("gemini_embedding_model", &self.gemini_embedding_model),
+        ];
+        for (name, model) in models {
+            if let Some(model) = model {
+                if model.trim().is_empty() || model.contains([',', ' ']) {
+                    errors.push(format!("{}: invalid model name \"{}\"", name, model));
+                }
+            }
+        }
+        if let Some(voice_id) = self.voice_id {
+            if voice_id < 0 {
+                errors.push(format!("voice_id: must be 0 or more: {}", voice_id));
+            }
+        }
+        if let Some(effort) = &self.reasoning_effort {
+            if !["minimal", "low", "medium", "high"].contains(&effort.as_str()) {
+                errors.push(format!(
+                    "reasoning_effort: must be minimal, low, medium or high: {}",
+                    effort
+                ));
+            }
+        }
+        if self.context_token_budget == Some(0) {
+            errors.push("context_token_budget: must be greater than 0".to_string());
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("invalid settings: {}", errors.join("; ")))
+        }
+    }
+
+    /// 環境変数で上書きし、上書きした環境変数の名前を返す
+    /// 読めない値は無視して警告する
+    pub fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>) -> Vec<String> {
+        let mut applied = Vec::new();
+        for name in ENV_OVERRIDES {
+            let Some(value) = var(name).filter(|v| !v.is_empty()) else {
+                continue;
+            };
+            let ok = match name {
+                "CHATGPT_MODELS" | "CLAUDE_MODELS" | "GEMINI_MODELS" => {
+                    match (split_models(&value), name) {
+                        (Some((high, low)), "CHATGPT_MODELS") => {
+                            (self.chatgpt_high, self.chatgpt_low) = (Some(high), Some(low));
+                            true
+                        }
+                        (Some((high, low)), "CLAUDE_MODELS") => {
+                            (self.claude_high, self.claude_low) = (Some(high), Some(low));
+                            true
+                        }
+                        (Some((high, low)), _) => {
+                            (self.gemini_high, self.gemini_low) = (Some(high), Some(low));
+                            true
+                        }
+                        (None, _) => false,
+                    }
+                }
+                "VOICEID" => value.parse().map(|v| self.voice_id = Some(v)).is_ok(),
+                "IMAGE_MODEL" => {
+                    self.image_model = Some(value.clone());
+                    true
+                }
+                "GEMINI_IMAGE_MODEL" => {
+                    self.gemini_image_model = Some(value.clone());
+                    true
+                }
+                "REASONING_EFFORT" => {
+                    self.reasoning_effort = Some(value.clone());
+                    true
+                }
+                "OPENAI_EMBEDDING_MODEL" => {
+                    self.openai_embedding_model = Some(value.clone());
+                    true
+                }
+                "GEMINI_EMBEDDING_MODEL" => {
+                    self.gemini_embedding_model = Some(value.clone());
+                    true
+                }
+                _ => value
+                    .parse()
+                    .map(|v| self.context_token_budget = Some(v))
+                    .is_ok(),
+            };
+            if ok {
+                applied.push(name.to_string());
+            } else {
+                log::warn!("ignored {}: \"{}\"", name, value);
+            }
+        }
+        applied
+    }
+
+    /// 高性能と低価格のモデル。未設定は None
+    pub fn models(&self, provider: Provider) -> (Option<String>, Option<String>) {
+        match provider {
+            Provider::ChatGPT => (self.chatgpt_high.clone(), self.chatgpt_low.clone()),
+            Provider::Claude => (self.claude_high.clone(), self.claude_low.clone()),
+            Provider::Gemini => (self.gemini_high.clone(), self.gemini_low.clone()),
+        }
+    }
+}
+
+/// 読み込んだ設定と、読めなかったときのエラー
+type Loaded = (Settings, Option<String>);
+
+// ファイルの更新時刻と内容
+static CACHE: Mutex<Option<(Option<SystemTime>, Loaded)>> = Mutex::new(None);
+
+/// ファイルだけの設定
+/// 更新時刻が変わっていれば読み直すため、アプリを再起動しなくても反映される
+/// 読めなければ既定値とエラー
+fn file() -> Loaded {
+    let path = match Settings::path() {
+        Ok(path) => path,
+        Err(e) => return (Settings::default(), Some(e)),
+    };
+    let modified = std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+    let mut cache = CACHE.lock().unwrap();
+    match cache.as_ref() {
+        Some((cached, loaded)) if *cached == modified => loaded.clone(),
+        _ => {
+            let loaded = match Settings::at(&path) {
+                Ok(settings) => (settings, None),
+                Err(e) => {
+                    log::warn!("{}", e);
+                    (Settings::default(), Some(e))
+                }
+            };
+            *cache = Some((modified, loaded.clone()));
+            loaded
+        }
+    }
+}
+
+/// 使用中の設定。ファイルの内容を環境変数で上書きしたもの
+pub fn current() -> Settings {
+    let (mut settings, _) = file();
+    settings.apply_env(|name| std::env::var(name).ok());
+    settings
+}
+
+/// 設定画面用
+#[derive(Debug, Clone, Serialize)]
+pub struct SettingsView {
+    pub path: PathBuf,
+    // ファイルの内容
+    pub file: Settings,
+    // 環境変数で上書きした後の値
+    pub effective: Settings,
+    // 上書きしている環境変数
+    pub overridden: Vec<String>,
+    // ファイルを読めなかった理由。この場合 file は既定値
+    pub error: Option<String>,
+}
+
+fn view() -> Result<SettingsView, String> {
+    let path = Settings::path()?;
+    let (file, error) = file();
+    let mut effective = file.clone();
+    let overridden = effective.apply_env(|name| std::env::var(name).ok());
+    Ok(SettingsView {
+        path,
+        file,
+        effective,
+        overridden,
+        error,
+    })
+}
+
+/// 設定ファイルの JSON Schema
+pub fn schema() -> Value {
+    let model = |description: &str| json!({"type": "string", "description": description});
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "Talk with RustGPT settings",
+        "type": "object",
+        "properties": {
+            "version": {"type": "integer", "minimum": 1, "maximum": VERSION},
+            "chatgpt_high": model("ChatGPT high model (CHATGPT_MODELS)"),
+            "chatgpt_low": model("ChatGPT low model (CHATGPT_MODELS)"),
+            "claude_high": model("Claude high model (CLAUDE_MODELS)"),
+            "claude_low": model("Claude low model (CLAUDE_MODELS)"),
+            "gemini_high": model("Gemini high model (GEMINI_MODELS)"),
+            "gemini_low": model("Gemini low model (GEMINI_MODELS)"),
+            "voice_id": {"type": "integer", "minimum": 0, "description": "棒読みちゃん voice (VOICEID)"},
+            "image_model": model("OpenAI image model (IMAGE_MODEL)"),
+            "gemini_image_model": model("Gemini image model (GEMINI_IMAGE_MODEL)"),
+            "reasoning_effort": {"enum": ["minimal", "low", "medium", "high"], "description": "REASONING_EFFORT"},
+            "context_token_budget": {"type": "integer", "minimum": 1, "description": "CONTEXT_TOKEN_BUDGET"},
+            "openai_embedding_model": model("OpenAI embedding model for RAG and memo search (OPENAI_EMBEDDING_MODEL)"),
+            "gemini_embedding_model": model("Gemini embedding model for RAG and memo search (GEMINI_EMBEDDING_MODEL)"),
+        },
+        "additionalProperties": false,
+    })
+}
+
+#[tauri::command]
+pub fn get_settings() -> Result<SettingsView, String> {
+    view()
+}
+
+/// 設定を保存する。次のリクエストから反映される
+#[tauri::command]
+pub fn update_settings(settings: Settings) -> Result<SettingsView, String> {
+    settings.save(&Settings::path()?)?;
+    // 更新時刻が同じでも読み直す
+    *CACHE.lock().unwrap() = None;
+    view()
+}
+
+#[tauri::command]
+pub fn settings_schema() -> Value {
+    schema()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_settings_file_validation_and_env_overrides() {
+        let path =
+            std::env::temp_dir().join(format!("talkwithrust-settings-{}.toml", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(Settings::at(&path).unwrap().version, VERSION);
+
+        let settings = Settings {
+            claude_high: Some("claude-sonnet-4-0".to_string()),
+            voice_id: Some(3),
+            ..Default::default()
+        };
+        settings.save(&path).unwrap();
+        let mut loaded = Settings::at(&path).unwrap();
+        assert_eq!(loaded.version, VERSION);
+        assert_eq!(loaded.claude_high.as_deref(), Some("claude-sonnet-4-0"));
+
+        let env = |name: &str| match name {
+            "CLAUDE_MODELS" => Some("claude-opus-4-1, claude-3-5-haiku-latest".to_string()),
+            "VOICEID" => Some("x".to_string()),
+            "CHATGPT_MODELS" => Some("gpt-4o.gpt-4o-mini".to_string()),
+            "GEMINI_EMBEDDING_MODEL" => Some("gemini-embedding-001".to_string()),
+            _ => None,
+        };
+        assert_eq!(
+            loaded.apply_env(env),
+            vec!["CLAUDE_MODELS", "GEMINI_EMBEDDING_MODEL"]
+        );
+        assert_eq!(
+            loaded.gemini_embedding_model.as_deref(),
+            Some("gemini-embedding-001")
+        );
+        assert_eq!(
+            loaded.models(Provider::Claude),
+            (
+                Some("claude-opus-4-1".to_string()),
+                Some("claude-3-5-haiku-latest".to_string())
+            )
+        );
+        assert_eq!(loaded.voice_id, Some(3));
+        assert_eq!(loaded.chatgpt_high, None);
+
+        let invalid = Settings {
+            version: VERSION + 1,
+            chatgpt_low: Some("gpt-4o,gpt-4o-mini".to_string()),
+            reasoning_effort: Some("max".to_string()),
+            ..Default::default()
+        };
+        let error = invalid.save(&path).unwrap_err();
+        assert!(error.contains("version"), "{}", error);
+        assert!(error.contains("chatgpt_low"), "{}", error);
+        assert!(error.contains("reasoning_effort"), "{}", error);
+
+        std::fs::write(&path, "version = 1\nvoice_id = \"loud\"\n").unwrap();
+        assert!(Settings::at(&path).is_err());
+        // スキーマにない項目 (綴りの誤りなど) も読まない
+        std::fs::write(&path, "version = 1\nclaude_hi = \"claude-opus-4-1\"\n").unwrap();
+        let error = Settings::at(&path).unwrap_err();
+        assert!(error.contains("claude_hi"), "{}", error);
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,618 @@
+use axum::{
+    body::Bytes,
+    extract::State as AxumState,
+    http::{header, Method, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    Json, Router,
+};
+use log::info;
+use reqwest::multipart::Form;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::{
+    result::Result,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::oneshot;
+
+use crate::manage::{
+    provider::Provider,
+    secrets::{self, REDACTED},
+};
+
+/// 各AIへのHTTPリクエストの送り方
+/// Live: そのまま送信
+/// Record: 送信し、リクエストと応答の組をカセットに追記する
+/// Replay: 送信せず、カセットから応答を返す
+/// Mock: URL を mock_server に向けて送信する
+#[derive(Debug, Clone, PartialEq)]
+pub enum Mode {
+    Live,
+    Record(PathBuf),
+    Replay(PathBuf),
+    Mock(String),
+}
+
+impl Mode {
+    /// 環境変数 HTTP_MODE (live, record, replay, mock) で切り替える
+    /// record と replay は HTTP_CASSETTE、mock は HTTP_MOCK_URL を使う
+    pub fn from_env() -> Mode {
+        let cassette = std::env::var("HTTP_CASSETTE")
+            .ok()
+            .filter(|p| !p.is_empty());
+        let mock_url = std::env::var("HTTP_MOCK_URL")
+            .ok()
+            .filter(|u| !u.is_empty());
+        match std::env::var("HTTP_MODE").unwrap_or_default().as_str() {
+            "record" => cassette.map(|p| Mode::Record(PathBuf::from(p))),
+            "replay" => cassette.map(|p| Mode::Replay(PathBuf::from(p))),
+            "mock" => mock_url.map(Mode::Mock),
+            _ => None,
+        }
+        .unwrap_or(Mode::Live)
+    }
+}
+
+tokio::task_local! {
+    static MODE: Mode;
+}
+
+/// f の中だけ送り方を差し替える
+/// テストは環境変数を書き換えずに、これでカセットを指定する
+pub async fn scope<F: Future>(mode: Mode, f: F) -> F::Output {
+    MODE.scope(mode, f).await
+}
+
+fn current() -> Mode {
+    MODE.try_with(|mode| mode.clone())
+        .unwrap_or_else(|_| Mode::from_env())
+}
+
+/// テスト用カセットのパス (src-tauri/tests/cassettes/{name}.json)
+pub fn cassette(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("cassettes")
+        .join(format!("{}.json", name))
+}
+
+/// テストで使うカセットの送り方
+/// 普段は再生し、HTTP_MODE=record なら本物の API に送って記録し直す
+/// 記録し直すときは先に古いカセットを消しておく (追記されるため)
+pub fn cassette_mode(name: &str) -> Mode {
+    match std::env::var("HTTP_MODE").as_deref() {
+        Ok("record") => Mode::Record(cassette(name)),
+        _ => Mode::Replay(cassette(name)),
+    }
+}
+
+/// API キー
+/// Replay と Mock では送信しないため、未設定でもダミーのキーを返す
+pub fn key(provider: Provider) -> Result<String, String> {
+    match (secrets::key(provider), current()) {
+        (Some(key), _) => Ok(key),
+        (None, Mode::Replay(_) | Mode::Mock(_)) => Ok(REDACTED.to_string()),
+        (None, _) => Err(format!(
+            "{} is not configured: set {} or save a key with set_api_key",
+            provider.name(),
+            secrets::env_var(provider)
+        )),
+    }
+}
+
+/// 記録したリクエストと応答の組
+/// ヘッダーは記録しない
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Interaction {
+    pub method: String,
+    pub url: String,
+    // multipart は Null
+    #[serde(default)]
+    pub request: Value,
+    pub status: u16,
+    pub response: Value,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Cassette {
+    // 手書きなど、実際の API から記録していないカセットの説明
+    // Record で作り直したカセットには付かない
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+    pub interactions: Vec<Interaction>,
+}
+
+// 記録中のカセットの書き込みを直列にする
+static RECORDING: Mutex<()> = Mutex::new(());
+
+impl Cassette {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let data = std::fs::read_to_string(path)
+            .map_err(|e| format!("failed to read cassette {}: {}", path.display(), e))?;
+        serde_json::from_str(&data).map_err(|e| format!("failed to parse cassette: {}", e))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .map_err(|e| format!("failed to create cassette dir: {}", e))?;
+        }
+        let data = serde_json::to_string_pretty(self)
+            .map_err(|e| format!("failed to serialize cassette: {}", e))?;
+        std::fs::write(path, data).map_err(|e| format!("failed to write cassette: {}", e))
+    }
+
+    /// method と URL、リクエスト本文が一致する最初の記録
+    /// 本文が Null の記録は URL だけで一致させる
+    pub fn find(&self, method: &str, url: &str, request: &Value) -> Option<&Interaction> {
+        let url = scrub_url(url);
+        let request = scrub(request);
+        self.interactions.iter().find(|i| {
+            i.method == method && i.url == url && (i.request.is_null() || i.request == request)
+        })
+    }
+
+    fn append(path: &Path, interaction: Interaction) -> Result<(), String> {
+        let _guard = RECORDING.lock().unwrap();
+        let mut cassette = if path.exists() {
+            Cassette::load(path)?
+        } else {
+            Cassette::default()
+        };
+        cassette.interactions.push(interaction);
+        cassette.save(path)
+    }
+}
+
+/// URL の key= を伏せる
+pub fn scrub_url(url: &str) -> String {
+    let Some((base, query)) = url.split_once('?') else {
+        return url.to_string();
+    };
+    let query = query
+        .split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some(("key", _)) => format!("key={}", REDACTED),
+            _ => pair.to_string(),
+        })
+        .collect::<Vec<String>>()
+        .join("&");
+    format!("{}?{}", base, query)
+}
+
+/// 値の中に含まれる API キー (環境変数とキーチェーンの両方) を伏せる
+pub fn scrub(value: &Value) -> Value {
+    let text = value.to_string();
+    let scrubbed = secrets::redact(&text);
+    if scrubbed == text {
+        return value.clone();
+    }
+    serde_json::from_str(&scrubbed).unwrap_or(Value::Null)
+}
+
+fn replay(path: &Path, method: &str, url: &str, request: &Value) -> Result<Value, String> {
+    let cassette = Cassette::load(path)?;
+    cassette
+        .find(method, url, request)
+        .map(|i| i.response.clone())
+        .ok_or(format!(
+            "no recorded interaction for {} {} in {}",
+            method,
+            scrub_url(url),
+            path.display()
+        ))
+}
+
+fn record(path: &Path, method: &str, url: &str, request: &Value, status: u16, response: &Value) {
+    let interaction = Interaction {
+        method: method.to_string(),
+        url: scrub_url(url),
+        request: scrub(request),
+        status,
+        response: scrub(response),
+    };
+    if let Err(e) = Cassette::append(path, interaction) {
+        log::error!("failed to record interaction: {}", e);
+    }
+}
+
+/// https://api.openai.com/v1/... を {base}/api.openai.com/v1/... にする
+fn to_mock_url(base: &str, url: &str) -> String {
+    let rest = url
+        .trim_start_matches("https://")
+        .trim_start_matches("http://");
+    format!("{}/{}", base.trim_end_matches('/'), rest)
+}
+
+/// JSON を POST して JSON の応答を得る
+/// HTTP エラーでも応答の JSON をそのまま返す
+pub async fn post_json(
+    url: &str,
+    headers: &[(&str, String)],
+    body: &Value,
+) -> Result<Value, String> {
+    match current() {
+        Mode::Live => send_json(url, headers, body).await.map(|(_, v)| v),
+        Mode::Record(path) => {
+            let (status, v) = send_json(url, headers, body).await?;
+            record(&path, "POST", url, body, status, &v);
+            Ok(v)
+        }
+        Mode::Replay(path) => replay(&path, "POST", url, body),
+        Mode::Mock(base) => send_json(&to_mock_url(&base, url), headers, body)
+            .await
+            .map(|(_, v)| v),
+    }
+}
+
+/// GET で JSON の応答を得る (モデル一覧など)
+/// HTTP エラーでも応答の JSON をそのまま返す
+pub async fn get_json(url: &str, headers: &[(&str, String)]) -> Result<Value, String> {
+    match current() {
+        Mode::Live => send_get(url, headers).await.map(|(_, v)| v),
+        Mode::Record(path) => {
+            let (status, v) = send_get(url, headers).await?;
+            record(&path, "GET", url, &Value::Null, status, &v);
+            Ok(v)
+        }
+        Mode::Replay(path) => replay(&path, "GET", url, &Value::Null),
+        Mode::Mock(base) => send_get(&to_mock_url(&base, url), headers)
+            .await
+            .map(|(_, v)| v),
+    }
+}
+
+/// multipart/form-data を POST して JSON の応答を得る
+/// 本文は記録せず、再生時は URL だけで一致させる
+pub async fn post_form(url: &str, headers: &[(&str, String)], form: Form) -> Result<Value, String> {
+    match current() {
+        Mode::Live => send_form(url, headers, form).await.map(|(_, v)| v),
+        Mode::Record(path) => {
+            let (status, v) = send_form(url, headers, form).await?;
+            record(&path, "POST", url, &Value::Null, status, &v);
+            Ok(v)
+        }
+        Mode::Replay(path) => replay(&path, "POST", url, &Value::Null),
+        Mode::Mock(base) => send_form(&to_mock_url(&base, url), headers, form)
+            .await
+            .map(|(_, v)| v),
+    }
+}
+
+async fn send_json(
+    url: &str,
+    headers: &[(&str, String)],
+    body: &Value,
+) -> Result<(u16, Value), String> {
+    let mut request = Client::new().post(url).json(body);
+    for (k, v) in headers {
+        request = request.header(*k, v);
+    }
+    to_json(request.send().await).await
+}
+
+async fn send_get(url: &str, headers: &[(&str, String)]) -> Result<(u16, Value), String> {
+    let mut request = Client::new().get(url);
+    for (k, v) in headers {
+        request = request.header(*k, v);
+    }
+    to_json(request.send().await).await
+}
+
+async fn send_form(
+    url: &str,
+    headers: &[(&str, String)],
+    form: Form,
+) -> Result<(u16, Value), String> {
+    let mut request = Client::new().post(url).multipart(form);
+    for (k, v) in headers {
+        request = request.header(*k, v);
+    }
+    to_json(request.send().await).await
+}
+
+async fn to_json(res: reqwest::Result<reqwest::Response>) -> Result<(u16, Value), String> {
+    let res = match res {
+        Ok(response) => response,
+        // URL の ?key= などを出さない
+        Err(err) => {
+            return Err(secrets::redact(&format!(
+                "Request error: {}",
+                err.without_url()
+            )));
+        }
+    };
+    let status = res.status().as_u16();
+
+    match res.json().await {
+        Ok(json) => Ok((status, json)),
+        Err(err) => Err(secrets::redact(&format!(
+            "JSON parse error: {}",
+            err.without_url()
+        ))),
+    }
+}
+
+/// mock_server の動作
+/// cassette があれば記録した応答を優先し、なければ各APIの最小限の応答を返す
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MockConfig {
+    #[serde(default)]
+    pub cassette: Option<PathBuf>,
+    // 応答までの遅延
+    #[serde(default)]
+    pub delay_ms: u64,
+    // 指定すると全リクエストをこのステータスで失敗させる
+    #[serde(default)]
+    pub fail_status: Option<u16>,
+    // n 回に1回 429 を返す
+    #[serde(default)]
+    pub rate_limit_every: Option<u32>,
+}
+
+struct MockState {
+    config: MockConfig,
+    cassette: Cassette,
+    count: AtomicU32,
+}
+
+/// 各APIを模したサーバーを 127.0.0.1:port で起動する (port 0 で空きポート)
+/// HTTP_MODE=mock と HTTP_MOCK_URL=http://127.0.0.1:{port} で各AIへのリクエストをここに向ける
+/// 戻り値は (実際のポート, 停止用の送信側)
+pub async fn mock_server(
+    port: u16,
+    config: MockConfig,
+) -> Result<(u16, oneshot::Sender<()>), String> {
+    let cassette = match &config.cassette {
+        Some(path) => Cassette::load(path)?,
+        None => Cassette::default(),
+    };
+    let state = Arc::new(MockState {
+        config,
+        cassette,
+        count: AtomicU32::new(0),
+    });
+
+    let listener = tokio::net::TcpListener::bind(("127.0.0.1", port))
+        .await
+        .map_err(|e| format!("failed to bind 127.0.0.1:{}: {}", port, e))?;
+    let port = listener.local_addr().map(|a| a.port()).unwrap_or(port);
+
+    let app = Router::new().fallback(mock_handler).with_state(state);
+    let (shutdown, rx) = oneshot::channel::<()>();
+    tokio::spawn(async move {
+        let server = axum::serve(listener, app).with_graceful_shutdown(async {
+            rx.await.ok();
+        });
+        if let Err(e) = server.await {
+            log::error!("mock server error: {}", e);
+        }
+    });
+    info!("mock server started on 127.0.0.1:{}", port);
+
+    Ok((port, shutdown))
+}
+
+async fn mock_handler(
+    AxumState(state): AxumState<Arc<MockState>>,
+    method: Method,
+    uri: Uri,
+    body: Bytes,
+) -> Response {
+    let count = state.count.fetch_add(1, Ordering::SeqCst) + 1;
+    if state.config.delay_ms > 0 {
+        tokio::time::sleep(std::time::Duration::from_millis(state.config.delay_ms)).await;
+    }
+
+    if let Some(every) = state.config.rate_limit_every.filter(|n| *n > 0) {
+        if count % every == 0 {
+            let body = json!({"error": {"message": "rate limit exceeded (mock)", "type": "rate_limit_error"}});
+            return (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, "1")],
+                Json(body),
+            )
+                .into_response();
+        }
+    }
+    if let Some(status) = state.config.fail_status {
+        let status = StatusCode::from_u16(status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        let body = json!({"error": {"message": format!("simulated error {} (mock)", status.as_u16()), "type": "api_error"}});
+        return (status, Json(body)).into_response();
+    }
+
+    let url = format!("https:/{}", uri);
+    let request = serde_json::from_slice(&body).unwrap_or(Value::Null);
+    if let Some(interaction) = state.cassette.find(method.as_str(), &url, &request) {
+        let status = StatusCode::from_u16(interaction.status).unwrap_or(StatusCode::OK);
+        return (status, Json(interaction.response.clone())).into_response();
+    }
+
+    match canned(&url) {
+        Some(v) => Json(v).into_response(),
+        None => (
+            StatusCode::NOT_FOUND,
+            Json(json!({"error": {"message": format!("no mock for {}", scrub_url(&url)), "type": "not_found"}})),
+        )
+            .into_response(),
+    }
+}
+
+/// 各APIの最小限の応答
+fn canned(url: &str) -> Option<Value> {
+    let text = "mock response";
+    if url.contains("api.anthropic.com/v1/messages") {
+        Some(json!({
+            "id": "msg_mock",
+            "type": "message",
+            "role": "assistant",
+            "model": "mock",
+            "content": [{"type": "text", "text": text}],
+            "stop_reason": "end_turn",
+            "usage": {"input_tokens": 1, "output_tokens": 2}
+        }))
+    } else if url.contains("api.openai.com/v1/chat/completions") {
+        Some(json!({
+            "id": "chatcmpl-mock",
+            "object": "chat.completion",
+            "model": "mock",
+            "choices": [{"index": 0, "message": {"role": "assistant", "content": text}, "finish_reason": "stop"}],
+            "usage": {"prompt_tokens": 1, "completion_tokens": 2, "total_tokens": 3}
+        }))
+    } else if url.contains("api.openai.com/v1/embeddings") {
+        Some(json!({"data": [{"embedding": [0.0, 1.0]}]}))
+    } else if url.contains("generativelanguage.googleapis.com") && url.contains(":generateContent")
+    {
+        Some(json!({
+            "candidates": [{"content": {"role": "model", "parts": [{"text": text}]}, "finishReason": "STOP"}],
+            "usageMetadata": {"promptTokenCount": 1, "candidatesTokenCount": 2, "totalTokenCount": 3}
+        }))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scrub_url_hides_key() {
+        assert_eq!(
+            scrub_url("https://example.com/v1beta/models/m:generateContent?key=abc&alt=json"),
+            "https://example.com/v1beta/models/m:generateContent?key=REDACTED&alt=json"
+        );
+        assert_eq!(
+            scrub_url("https://example.com/v1"),
+            "https://example.com/v1"
+        );
+        assert_eq!(
+            to_mock_url("http://127.0.0.1:9000/", "https://api.openai.com/v1/models"),
+            "http://127.0.0.1:9000/api.openai.com/v1/models"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_request_error_hides_key_in_url() {
+        // 接続できないポートへ送り、reqwest のエラーに Gemini の ?key= が出ないこと
+        let url = "http://127.0.0.1:1/v1beta/models/m:generateContent?key=gemini-test-secret";
+        let e = scope(Mode::Live, post_json(url, &[], &json!({})))
+            .await
+            .unwrap_err();
+        assert!(e.starts_with("Request error"), "{}", e);
+        assert!(!e.contains("gemini-test-secret"), "{}", e);
+    }
+
+    #[test]
+    fn test_cassette_find_matches_body() {
+        let cassette = Cassette {
+            note: None,
+            interactions: vec![
+                Interaction {
+                    method: "POST".to_string(),
+                    url: "https://a/v1?key=REDACTED".to_string(),
+                    request: json!({"q": 1}),
+                    status: 200,
+                    response: json!("one"),
+                },
+                Interaction {
+                    method: "POST".to_string(),
+                    url: "https://a/v1?key=REDACTED".to_string(),
+                    request: json!({"q": 2}),
+                    status: 200,
+                    response: json!("two"),
+                },
+                Interaction {
+                    method: "POST".to_string(),
+                    url: "https://a/form".to_string(),
+                    request: Value::Null,
+                    status: 200,
+                    response: json!("form"),
+                },
+            ],
+        };
+        let found = cassette.find("POST", "https://a/v1?key=secret", &json!({"q": 2}));
+        assert_eq!(found.unwrap().response, json!("two"));
+        assert!(cassette
+            .find("POST", "https://a/v1", &json!({"q": 3}))
+            .is_none());
+        assert!(cassette
+            .find("GET", "https://a/v1?key=x", &json!({"q": 1}))
+            .is_none());
+        assert_eq!(
+            cassette
+                .find("POST", "https://a/form", &Value::Null)
+                .unwrap()
+                .response,
+            json!("form")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_record_then_replay() {
+        let dir =
+            std::env::temp_dir().join(format!("talkwithrust-cassette-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = dir.join("record.json");
+
+        // mock_server を相手に記録し、送信せずに再生する
+        let (port, shutdown) = mock_server(0, MockConfig::default()).await.unwrap();
+        let base = format!("http://127.0.0.1:{}", port);
+        let url = format!("{}/api.anthropic.com/v1/messages", base);
+        let body = json!({"model": "m", "messages": []});
+
+        let recorded = scope(Mode::Record(path.clone()), post_json(&url, &[], &body))
+            .await
+            .unwrap();
+        let _ = shutdown.send(());
+
+        let replayed = scope(Mode::Replay(path.clone()), post_json(&url, &[], &body))
+            .await
+            .unwrap();
+        assert_eq!(recorded, replayed);
+        assert_eq!(replayed["content"][0]["text"], "mock response");
+
+        let missing = scope(
+            Mode::Replay(path),
+            post_json(&url, &[], &json!({"other": true})),
+        )
+        .await;
+        assert!(missing.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_mock_server_simulates_rate_limit_and_errors() {
+        let config = MockConfig {
+            rate_limit_every: Some(2),
+            delay_ms: 10,
+            ..Default::default()
+        };
+        let (port, shutdown) = mock_server(0, config).await.unwrap();
+        let mode = Mode::Mock(format!("http://127.0.0.1:{}", port));
+        let url = "https://api.openai.com/v1/chat/completions";
+
+        let first = scope(mode.clone(), post_json(url, &[], &json!({})))
+            .await
+            .unwrap();
+        assert_eq!(first["choices"][0]["message"]["content"], "mock response");
+        let second = scope(mode.clone(), post_json(url, &[], &json!({})))
+            .await
+            .unwrap();
+        assert_eq!(second["error"]["type"], "rate_limit_error");
+        let _ = shutdown.send(());
+
+        let config = MockConfig {
+            fail_status: Some(503),
+            ..Default::default()
+        };
+        let (port, shutdown) = mock_server(0, config).await.unwrap();
+        let mode = Mode::Mock(format!("http://127.0.0.1:{}", port));
+        let failed = scope(mode, post_json(url, &[], &json!({}))).await.unwrap();
+        assert!(failed["error"]["message"].as_str().unwrap().contains("503"));
+        let _ = shutdown.send(());
+    }
+}
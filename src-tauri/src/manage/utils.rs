@@ -1,55 +1,27 @@
-use dotenv::dotenv;
+use base64::Engine;
+use directories::UserDirs;
+use serde::Serialize;
 use serde_json::Value;
-use std::env;
-use std::error::Error;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::result::Result;
 
 use markdown;
 
+use crate::manage::{message::Meta, settings};
 use crate::sub;
 
-pub fn model_high_and_low(key: &str) -> (String, String) {
-    match std::env::var(key) {
-        Ok(val) => {
-            let models: Vec<&str> = val.split(',').collect();
-            if models.len() == 2 {
-                (models[0].to_string(), models[1].to_string())
-            } else {
-                ("".to_string(), "".to_string())
-            }
-        }
-        Err(_) => ("".to_string(), "".to_string()),
-    }
-}
-
-#[derive(Debug)]
-pub struct Keys {
-    pub anthropic_key: String,
-    pub openai_token: String,
-    pub google_key: String,
-    #[allow(unused)]
-    pub voice_id: Option<i16>,
-}
+pub const APPNAME: &str = "Talk with RustGPT";
 
-/// 必要な環境変数を取得する
-/// VOICEID は省略可能。未設定または不正値の場合は None
-pub async fn get_env() -> Result<Keys, Box<dyn Error>> {
-    dotenv().ok();
-    let anthropic_key = env::var("ANTHROPIC_API_KEY")?;
-    let openai_token = env::var("CHATGPTTOKEN")?;
-    let google_key = env::var("GOOGLE_GEMINI_API_KEY")?;
-    let voice_id = env::var("VOICEID").ok().and_then(|v| v.parse().ok());
-
-    Ok(Keys {
-        anthropic_key,
-        openai_token,
-        google_key,
-        voice_id,
-    })
+/// メモや生成画像を保存するアプリデータディレクトリ
+/// Documents/.appdata/Talk with RustGPT
+pub fn app_data_dir() -> Result<PathBuf, String> {
+    let user_dir = UserDirs::new().ok_or("user directories not found")?;
+    let document_dir = user_dir
+        .document_dir()
+        .ok_or("document directory not found")?;
+    Ok(document_dir.join(".appdata").join(APPNAME))
 }
 
-#[allow(unused)]
 pub fn get_file_type_by_extension(file_path: &str) -> Option<&str> {
     let path = Path::new(file_path);
     match path.extension()?.to_str()? {
@@ -67,6 +39,18 @@ pub fn get_file_type_by_extension(file_path: &str) -> Option<&str> {
     }
 }
 
+/// data URL (data:image/png;base64,....) を MIME タイプとバイト列に分解する
+pub fn decode_data_url(src: &str) -> Result<(String, Vec<u8>), String> {
+    let rest = src.strip_prefix("data:").ok_or("not a data URL")?;
+    let (mime, data) = rest
+        .split_once(";base64,")
+        .ok_or("data URL is not base64 encoded")?;
+    let bytes = base64::engine::general_purpose::STANDARD
+        .decode(data)
+        .map_err(|e| format!("base64 decode error: {}", e))?;
+    Ok((mime.to_string(), bytes))
+}
+
 pub fn get_content_for_chatgpt(v: &Value) -> Result<(String, u64), String> {
     let choices = v["choices"].as_array().ok_or("choices not found")?;
     if choices.is_empty() {
@@ -102,6 +86,26 @@ pub fn get_content_for_chatgpt_dell3(v: &Value) -> Result<(String, String), Stri
     Ok((prompt, url))
 }
 
+/// 画像生成APIの data[] から (revised_prompt, b64_json) を取り出す
+/// response_format: b64_json で要求した場合に使う
+pub fn get_b64_images_for_chatgpt(v: &Value) -> Result<Vec<(String, String)>, String> {
+    let data = v["data"].as_array().ok_or("data not found")?;
+    if data.is_empty() {
+        return Err("data is empty".to_string());
+    }
+
+    data.iter()
+        .map(|d| {
+            let prompt = d["revised_prompt"].as_str().unwrap_or_default().to_string();
+            let b64 = d["b64_json"]
+                .as_str()
+                .ok_or("b64_json not found or not a string")?
+                .to_string();
+            Ok((prompt, b64))
+        })
+        .collect()
+}
+
 pub fn get_content_for_claude(v: &Value) -> Result<(String, u64), String> {
     let content = v["content"].as_array().ok_or("content not found")?;
     if content.is_empty() {
@@ -117,23 +121,124 @@ pub fn get_content_for_claude(v: &Value) -> Result<(String, u64), String> {
 }
 
 pub fn get_content_for_gemini(v: &Value) -> Result<(String, u64), String> {
-    let text = v
+    let (text, _, tokens) = get_parts_for_gemini(v)?;
+    Ok((text, tokens))
+}
+
+/// (MIME, base64) の画像データ
+pub type InlineImage = (String, String);
+
+/// Gemini のレスポンスからテキストと画像を取り出す
+/// テキストと画像が混在する場合、テキストは連結し、画像は (MIME, base64) で返す
+pub fn get_parts_for_gemini(v: &Value) -> Result<(String, Vec<InlineImage>, u64), String> {
+    let parts = v
         .get("candidates")
         .and_then(|c| c.get(0))
         .and_then(|c| c.get("content"))
         .and_then(|c| c.get("parts"))
-        .and_then(|p| p.get(0))
-        .and_then(|p| p.get("text"))
-        .ok_or(format!("part.text not found, error: {:?}", v))?
-        .as_str()
-        .expect("part.text is not a string")
-        .to_string();
+        .and_then(|p| p.as_array())
+        .ok_or(format!("parts not found, error: {:?}", v))?;
+
+    let mut texts = Vec::new();
+    let mut images = Vec::new();
+    for part in parts {
+        if let Some(text) = part.get("text").and_then(|t| t.as_str()) {
+            texts.push(text.to_string());
+        }
+        // REST のレスポンスは inlineData、リクエスト側は inline_data
+        let inline = part.get("inlineData").or(part.get("inline_data"));
+        if let Some(inline) = inline {
+            let mime = inline
+                .get("mimeType")
+                .or(inline.get("mime_type"))
+                .and_then(|m| m.as_str())
+                .unwrap_or("image/png");
+            if let Some(data) = inline.get("data").and_then(|d| d.as_str()) {
+                images.push((mime.to_string(), data.to_string()));
+            }
+        }
+    }
+
+    if texts.is_empty() && images.is_empty() {
+        return Err(format!("part.text not found, error: {:?}", v));
+    }
+
     let tokens = v["usageMetadata"]["totalTokenCount"].as_u64().unwrap_or(0);
-    Ok((text, tokens))
+    Ok((texts.join(""), images, tokens))
+}
+
+/// Imagen (predict) のレスポンスから画像を (MIME, base64) で取り出す
+pub fn get_images_for_imagen(v: &Value) -> Result<Vec<InlineImage>, String> {
+    let predictions = v["predictions"]
+        .as_array()
+        .ok_or(format!("predictions not found, error: {:?}", v))?;
+
+    let images = predictions
+        .iter()
+        .filter_map(|p| {
+            let data = p["bytesBase64Encoded"].as_str()?;
+            let mime = p["mimeType"].as_str().unwrap_or("image/png");
+            Some((mime.to_string(), data.to_string()))
+        })
+        .collect::<Vec<InlineImage>>();
+
+    if images.is_empty() {
+        return Err("predictions is empty".to_string());
+    }
+    Ok(images)
+}
+
+/// 各APIのレスポンスから (入力トークン, 出力トークン) を取り出す
+pub fn get_usage_for_chatgpt(v: &Value) -> (u64, u64) {
+    (
+        v["usage"]["prompt_tokens"].as_u64().unwrap_or(0),
+        v["usage"]["completion_tokens"].as_u64().unwrap_or(0),
+    )
+}
+
+pub fn get_usage_for_claude(v: &Value) -> (u64, u64) {
+    (
+        v["usage"]["input_tokens"].as_u64().unwrap_or(0),
+        v["usage"]["output_tokens"].as_u64().unwrap_or(0),
+    )
+}
+
+pub fn get_usage_for_gemini(v: &Value) -> (u64, u64) {
+    (
+        v["usageMetadata"]["promptTokenCount"].as_u64().unwrap_or(0),
+        v["usageMetadata"]["candidatesTokenCount"]
+            .as_u64()
+            .unwrap_or(0),
+    )
+}
+
+/// 終了理由 (end_turn, max_tokens など)
+pub fn get_finish_for_claude(v: &Value) -> Option<String> {
+    v["stop_reason"].as_str().map(String::from)
+}
+
+/// 終了理由 (stop, length, content_filter など)
+pub fn get_finish_for_chatgpt(v: &Value) -> Option<String> {
+    v["choices"][0]["finish_reason"].as_str().map(String::from)
+}
+
+/// 終了理由 (STOP, MAX_TOKENS, SAFETY など)
+pub fn get_finish_for_gemini(v: &Value) -> Option<String> {
+    v["candidates"][0]["finishReason"]
+        .as_str()
+        .map(String::from)
+}
+
+/// レスポンスのID。Claude と ChatGPT は id、Gemini は responseId
+pub fn get_request_id(v: &Value) -> Option<String> {
+    v["id"]
+        .as_str()
+        .or(v["responseId"].as_str())
+        .map(String::from)
 }
 
 /// AIが出力したマークダウン用テキストをHTML出力する
-/// ammonia でXSS対策のサニタイズを行う
+/// コードブロックは sub::highlight で色付けし、ammonia でXSS対策のサニタイズを行う
 pub fn convert_markdown_to_html(text: &str) -> Result<String, String> {
     let mut mathed_op = markdown::ParseOptions::gfm();
     mathed_op.constructs.math_flow = true;
@@ -150,58 +255,231 @@ pub fn convert_markdown_to_html(text: &str) -> Result<String, String> {
     )
     .map_err(|e| format!("markdown::to_html_with_options error: {}", e))?;
 
-    Ok(ammonia::clean(&html))
+    // 数式と図を先に変換し、残ったコードブロックを色付けする
+    let html = sub::math::render_math(&html);
+    let html = sub::diagram::render_diagrams(&html);
+    let html = sub::highlight::highlight_code_blocks(&html, &Default::default());
+
+    Ok(sanitize(&html))
+}
+
+/// MathML で残すタグ
+const MATHML_TAGS: &[&str] = &[
+    "math",
+    "semantics",
+    "annotation",
+    "mrow",
+    "mi",
+    "mn",
+    "mo",
+    "ms",
+    "mtext",
+    "mspace",
+    "msup",
+    "msub",
+    "msubsup",
+    "mfrac",
+    "msqrt",
+    "mroot",
+    "mover",
+    "munder",
+    "munderover",
+    "mtable",
+    "mtr",
+    "mtd",
+    "mstyle",
+    "mpadded",
+    "mphantom",
+    "menclose",
+];
+const MATHML_ATTRIBUTES: &[&str] = &[
+    "display",
+    "mathvariant",
+    "stretchy",
+    "fence",
+    "separator",
+    "accent",
+    "accentunder",
+    "lspace",
+    "rspace",
+    "linethickness",
+    "columnalign",
+    "rowalign",
+    "notation",
+    "width",
+    "height",
+    "depth",
+    "encoding",
+    "xmlns",
+];
+
+/// SVG で残すタグ。script, style, foreignObject, use, image は入れない
+const SVG_TAGS: &[&str] = &[
+    "svg", "g", "defs", "marker", "clipPath", "polygon", "polyline", "rect", "circle", "ellipse",
+    "path", "line", "text", "tspan", "textPath",
+];
+const SVG_ATTRIBUTES: &[&str] = &[
+    "id",
+    "viewBox",
+    "width",
+    "height",
+    "x",
+    "y",
+    "dx",
+    "dy",
+    "x1",
+    "x2",
+    "y1",
+    "y2",
+    "cx",
+    "cy",
+    "r",
+    "rx",
+    "ry",
+    "d",
+    "points",
+    "fill",
+    "fill-opacity",
+    "stroke",
+    "stroke-width",
+    "stroke-dasharray",
+    "stroke-opacity",
+    "transform",
+    "markerWidth",
+    "markerHeight",
+    "markerUnits",
+    "refX",
+    "refY",
+    "orient",
+    "marker-start",
+    "marker-end",
+    "clip-path",
+    "text-anchor",
+    "dominant-baseline",
+    "font-size",
+    "font-family",
+    "startOffset",
+    "href",
+    "xmlns",
+];
+
+/// ammonia の既定のポリシーに、色付け用の hl- で始まるクラスと、MathML と SVG を加える
+/// SVG の id と参照 (#... と url(#...)) は、アプリが描いた図のもの (diagram::id_prefix) だけを許す
+/// 回答に書かれた SVG の id がアプリの図の参照を奪わないようにするため
+pub fn sanitize(html: &str) -> String {
+    let mut builder = ammonia::Builder::default();
+    builder
+        .add_tags(MATHML_TAGS)
+        .add_tags(SVG_TAGS)
+        .add_tag_attributes("pre", &["class"])
+        .add_tag_attributes("code", &["class"])
+        .add_tag_attributes("span", &["class"]);
+    for tag in MATHML_TAGS {
+        builder.add_tag_attributes(tag, MATHML_ATTRIBUTES);
+    }
+    for tag in SVG_TAGS {
+        builder.add_tag_attributes(tag, SVG_ATTRIBUTES);
+    }
+    builder
+        .attribute_filter(|element, attribute, value| {
+            if SVG_TAGS.contains(&element) {
+                let drawn = |id: &str| id.starts_with(sub::diagram::id_prefix());
+                return match attribute {
+                    "id" => drawn(value).then(|| value.into()),
+                    "href" => value
+                        .strip_prefix('#')
+                        .is_some_and(drawn)
+                        .then(|| value.into()),
+                    _ => svg_refs_are_drawn(value, drawn).then(|| value.into()),
+                };
+            }
+            if attribute != "class" {
+                return Some(value.into());
+            }
+            let classes = value
+                .split_whitespace()
+                .filter(|c| c.starts_with(sub::highlight::CLASS_PREFIX))
+                .filter(|c| {
+                    c.chars()
+                        .all(|ch| ch.is_alphanumeric() || "-_+#.".contains(ch))
+                })
+                .collect::<Vec<&str>>();
+            if classes.is_empty() {
+                None
+            } else {
+                Some(classes.join(" ").into())
+            }
+        })
+        .clean(html)
+        .to_string()
+}
+
+/// url(...) がすべてアプリの図の中を指しているか
+/// URL( や u r l( のような書き方と、\75 rl( のような CSS のエスケープ (SVG の値には不要) も通さない
+fn svg_refs_are_drawn(value: &str, drawn: impl Fn(&str) -> bool) -> bool {
+    if value.contains('\\') {
+        return false;
+    }
+    let value = value
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>()
+        .to_lowercase();
+    value
+        .match_indices("url(")
+        .all(|(i, _)| value[i + 4..].strip_prefix('#').is_some_and(&drawn))
+}
+
+/// チャットのコマンドの返り値
+/// meta の各項目は html, markdown と同じ階層に展開する
+#[derive(Debug, Clone, Serialize)]
+pub struct ChatResponse {
+    pub html: String,
+    pub markdown: String,
+    #[serde(flatten)]
+    pub meta: Meta,
 }
 
 /// invokeへの返り値を作成する
-pub fn create_response(
-    markdown_content: &str,
-    set_model: &str,
-    token_count: u64,
-    start: chrono::DateTime<chrono::Local>,
-) -> String {
-    let end = chrono::Local::now();
-    format!(
-        "{}\n\nModel: {}, Total token: {}, Elaps: {}s",
-        markdown_content,
-        set_model,
-        token_count,
-        end.signed_duration_since(start).num_seconds(),
-    )
+pub fn create_response(html: &str, markdown: &str, meta: Meta) -> ChatResponse {
+    ChatResponse {
+        html: html.to_string(),
+        markdown: markdown.to_string(),
+        meta,
+    }
 }
 
-pub fn say(msg: String) -> bool {
+/// ペルソナの声で発言する。voice が None なら say と同じ
+pub fn say_as(msg: String, voice: Option<i16>) -> bool {
     // VoiceIDの指定を読み込み
-    let mut is_voice: bool;
-    let voice_id: i16 = match env::var("VOICEID") {
-        Ok(val) => {
-            is_voice = true;
+    // ペルソナの声が無ければ、設定か VOICEID で指定する
+    let voice_id = match voice.or(settings::current().voice_id) {
+        Some(val) => {
             println!("VOICEID: {}", val);
-            val.parse().unwrap()
+            val
         }
-        Err(e) => {
-            println!("couldn't interpret VOICEID: {}", e);
-            is_voice = false;
-            1
+        None => {
+            println!("VOICEID is not set");
+            return false;
         }
     };
 
     // メッセージを発言
     // 棒読みちゃんが起動していない場合は無視します
-    if is_voice {
-        match sub::voice::say(voice_id, msg.as_str()) {
-            Ok(_) => is_voice = true,
-            Err(e) => {
-                println!(
-                    "棒読みちゃんが起動していないか、エラーが発生しました: {}",
-                    e
-                );
-                is_voice = false;
-            }
-        };
+    match sub::voice::say(voice_id, msg.as_str()) {
+        Ok(_) => true,
+        Err(e) => {
+            println!(
+                "棒読みちゃんが起動していないか、エラーが発生しました: {}",
+                e
+            );
+            false
+        }
     }
+}
 
-    is_voice
+pub fn say(msg: String) -> bool {
+    say_as(msg, None)
 }
 
 #[cfg(test)]
@@ -223,48 +501,69 @@ mod tests {
         assert!(!html.contains("onerror"), "onerror attribute should be removed: {}", html);
     }
 
-    #[tokio::test]
-    async fn test_get_env() {
-        let current = env::current_dir().expect("Failed to get current directory");
-        let filepath = current.join(".env");
-        println!("filepath: {:?}", filepath);
-        let _ = dotenv::from_path(filepath.as_path());
-
-        let keys = match get_env().await {
-            Ok(keys) => keys,
-            Err(e) => panic!("Failed to get env: {}", e),
-        };
-        assert!(
-            !keys.anthropic_key.is_empty(),
-            "anthropic_key is empty, {}",
-            keys.anthropic_key
+    #[test]
+    fn test_sanitize_keeps_only_highlight_classes() {
+        let html = sanitize(
+            r#"<span class="evil hl-keyword" onclick="x()">a</span><div class="hl-x">b</div><code class="language-rs">c</code>"#,
         );
-        assert!(
-            !keys.openai_token.is_empty(),
-            "openai_token is empty, {}",
-            keys.openai_token
+        assert_eq!(
+            html,
+            r#"<span class="hl-keyword">a</span><div>b</div><code>c</code>"#
         );
-        // VOICEID は省略可能なので None も有効
-        if let Some(id) = keys.voice_id {
-            assert!(id > 0, "voice_id is wrong, {}", id);
-        }
+
+        let html = convert_markdown_to_html("```rust\nfn main() {}\n```").unwrap();
+        assert!(html.contains("<pre class=\"hl-code\">"), "{}", html);
+        assert!(html.contains("hl-storage"), "{}", html);
     }
 
-    #[tokio::test]
-    async fn test_get_env_succeeds_without_voiceid() {
-        // VOICEID を未設定の状態で get_env() がエラーにならないことを確認
-        std::env::remove_var("VOICEID");
-        std::env::set_var("ANTHROPIC_API_KEY", "dummy_anthropic");
-        std::env::set_var("CHATGPTTOKEN", "dummy_openai");
-        std::env::set_var("GOOGLE_GEMINI_API_KEY", "dummy_google");
-
-        let result = get_env().await;
-        assert!(result.is_ok(), "VOICEID 未設定時に get_env() が失敗した: {:?}", result.err());
-
-        // 後片付け
-        std::env::remove_var("ANTHROPIC_API_KEY");
-        std::env::remove_var("CHATGPTTOKEN");
-        std::env::remove_var("GOOGLE_GEMINI_API_KEY");
+    #[test]
+    fn test_sanitize_keeps_mathml_and_svg() {
+        let html = convert_markdown_to_html(
+            "$x^2$\n\n```dot\ndigraph G { a -> b; }\n```\n\n```mermaid\ngraph TD; A-->B;\n```",
+        )
+        .unwrap();
+        assert!(html.contains("<math"), "{}", html);
+        assert!(html.contains("<msup>"), "{}", html);
+        assert!(html.contains("<svg"), "{}", html);
+        assert!(html.contains("marker-end=\"url(#dot"), "{}", html);
+        assert!(html.contains("graph TD;"), "{}", html);
+
+        let html = sanitize(
+            r##"<svg onload="x()"><script>x()</script><foreignObject><p>a</p></foreignObject><path d="M0" style="fill:red" marker-end="url(https://evil/#a)" /><textPath href="https://evil">t</textPath></svg><a href="https://example.com">b</a>"##,
+        );
+        assert!(!html.contains("onload"), "{}", html);
+        assert!(!html.contains("script"), "{}", html);
+        assert!(!html.contains("foreignObject"), "{}", html);
+        assert!(!html.contains("style"), "{}", html);
+        assert!(!html.contains("evil"), "{}", html);
+        assert!(html.contains("<path d=\"M0\">"), "{}", html);
+        assert!(html.contains("href=\"https://example.com\""), "{}", html);
+
+        for fill in [
+            "URL(https://evil/a)",
+            "uRl( https://evil/a)",
+            "u r l(https://evil/a)",
+            "\\75 rl(https://evil/a)",
+            "url(#x) url(https://evil/a)",
+        ] {
+            let html = sanitize(&format!(r#"<svg><rect fill="{}" /></svg>"#, fill));
+            assert!(!html.contains("evil"), "{}", html);
+        }
+
+        // 回答に書かれた id は、アプリの図の id と重ならないよう外す
+        let drawn = format!("{}0-endarrow", sub::diagram::id_prefix());
+        let html = sanitize(&format!(
+            r##"<svg><marker id="{0}"></marker><marker id="endarrow"></marker><path marker-end="url(#{0})" fill="url(#endarrow)" /></svg>"##,
+            drawn
+        ));
+        assert!(html.contains(&format!("id=\"{}\"", drawn)), "{}", html);
+        assert!(!html.contains("id=\"endarrow\""), "{}", html);
+        assert!(
+            html.contains(&format!("marker-end=\"url(#{})\"", drawn)),
+            "{}",
+            html
+        );
+        assert!(!html.contains("url(#endarrow)"), "{}", html);
     }
 
     #[test]
@@ -297,6 +596,103 @@ mod tests {
         assert_eq!(tokens, 30);
     }
 
+    #[test]
+    fn test_get_usage_splits_input_and_output() {
+        let v: Value = serde_json::from_str(
+            r#"{"usage": {"prompt_tokens": 9, "completion_tokens": 12, "total_tokens": 21}}"#,
+        )
+        .unwrap();
+        assert_eq!(get_usage_for_chatgpt(&v), (9, 12));
+
+        let v: Value =
+            serde_json::from_str(r#"{"usage": {"input_tokens": 10, "output_tokens": 5}}"#).unwrap();
+        assert_eq!(get_usage_for_claude(&v), (10, 5));
+
+        let v: Value = serde_json::from_str(
+            r#"{"usageMetadata": {"promptTokenCount": 7, "candidatesTokenCount": 3}}"#,
+        )
+        .unwrap();
+        assert_eq!(get_usage_for_gemini(&v), (7, 3));
+    }
+
+    #[test]
+    fn test_get_finish_and_request_id() {
+        let v: Value =
+            serde_json::from_str(r#"{"id": "msg_1", "stop_reason": "max_tokens"}"#).unwrap();
+        assert_eq!(get_finish_for_claude(&v).as_deref(), Some("max_tokens"));
+        assert_eq!(get_request_id(&v).as_deref(), Some("msg_1"));
+
+        let v: Value = serde_json::from_str(
+            r#"{"id": "chatcmpl-1", "choices": [{"finish_reason": "length"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(get_finish_for_chatgpt(&v).as_deref(), Some("length"));
+
+        let v: Value = serde_json::from_str(
+            r#"{"responseId": "r1", "candidates": [{"finishReason": "STOP"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(get_finish_for_gemini(&v).as_deref(), Some("STOP"));
+        assert_eq!(get_request_id(&v).as_deref(), Some("r1"));
+        assert_eq!(get_finish_for_claude(&v), None);
+    }
+
+    #[test]
+    fn test_create_response_flattens_meta() {
+        let meta = Meta {
+            provider: "gemini".to_string(),
+            model: "gemini-2.0-flash".to_string(),
+            output_tokens: 4,
+            ..Default::default()
+        };
+        let value = serde_json::to_value(create_response("<p>a</p>", "a", meta)).unwrap();
+        assert_eq!(value["html"], "<p>a</p>");
+        assert_eq!(value["markdown"], "a");
+        assert_eq!(value["provider"], "gemini");
+        assert_eq!(value["output_tokens"], 4);
+        assert!(value["warnings"].as_array().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_get_parts_for_gemini_mixed_text_and_image() {
+        let v: Value = serde_json::from_str(
+            r#"{
+            "candidates": [{"content": {"parts": [
+                {"text": "Here is "},
+                {"inlineData": {"mimeType": "image/png", "data": "Y2F0"}},
+                {"text": "your cat."}
+            ]}}],
+            "usageMetadata": {"totalTokenCount": 42}
+        }"#,
+        )
+        .unwrap();
+        let (text, images, tokens) = get_parts_for_gemini(&v).unwrap();
+        assert_eq!(text, "Here is your cat.");
+        assert_eq!(images, vec![("image/png".to_string(), "Y2F0".to_string())]);
+        assert_eq!(tokens, 42);
+
+        // 画像のみのレスポンスでも content を取得できる
+        let v: Value = serde_json::from_str(
+            r#"{"candidates": [{"content": {"parts": [{"inlineData": {"mimeType": "image/jpeg", "data": "ZG9n"}}]}}]}"#,
+        )
+        .unwrap();
+        let (text, _) = get_content_for_gemini(&v).unwrap();
+        assert!(text.is_empty());
+    }
+
+    #[test]
+    fn test_get_images_for_imagen() {
+        let v: Value = serde_json::from_str(
+            r#"{"predictions": [{"bytesBase64Encoded": "Y2F0", "mimeType": "image/png"}]}"#,
+        )
+        .unwrap();
+        let images = get_images_for_imagen(&v).unwrap();
+        assert_eq!(images[0].1, "Y2F0");
+
+        let v: Value = serde_json::from_str(r#"{"predictions": []}"#).unwrap();
+        assert!(get_images_for_imagen(&v).is_err());
+    }
+
     #[test]
     fn test_get_content_for_chatgpt() {
         let v: Value = match serde_json::from_str(
@@ -336,6 +732,32 @@ mod tests {
         assert_eq!(tokens, 21);
     }
 
+    #[test]
+    fn test_decode_data_url() {
+        let (mime, bytes) = decode_data_url("data:image/jpeg;base64,Y2F0").unwrap();
+        assert_eq!(mime, "image/jpeg");
+        assert_eq!(bytes, b"cat");
+
+        assert!(decode_data_url("https://example.com/a.png").is_err());
+        assert!(decode_data_url("data:image/png,raw").is_err());
+    }
+
+    #[test]
+    fn test_get_b64_images_for_chatgpt() {
+        let v: Value = serde_json::from_str(
+            r#"{"created":1727198587,"data":[{"revised_prompt":"a cute cat","b64_json":"iVBORw0KGgo="}]}"#,
+        )
+        .unwrap();
+        let images = get_b64_images_for_chatgpt(&v).unwrap();
+        assert_eq!(images.len(), 1);
+        assert_eq!(images[0].0, "a cute cat");
+        assert_eq!(images[0].1, "iVBORw0KGgo=");
+
+        let v: Value =
+            serde_json::from_str(r#"{"data":[{"url":"https://example.com/a.png"}]}"#).unwrap();
+        assert!(get_b64_images_for_chatgpt(&v).is_err());
+    }
+
     #[test]
     fn test_get_content_for_chatgpt_dell3() {
         let v: Value = match serde_json::from_str(
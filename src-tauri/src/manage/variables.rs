@@ -0,0 +1,209 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::create_dir_all;
+use std::path::PathBuf;
+use std::result::Result;
+
+use crate::manage::{provider::Provider, utils};
+use crate::sub::template::{self, Rendered};
+
+const FILE: &str = "variables.toml";
+
+/// 利用者が定義したテンプレート変数
+/// Documents/.appdata/Talk with RustGPT/variables.toml に name = "value" で保存する
+pub struct Variables {
+    path: PathBuf,
+    pub values: BTreeMap<String, String>,
+}
+
+impl Variables {
+    pub fn open() -> Result<Self, String> {
+        Self::at(utils::app_data_dir()?.join(FILE))
+    }
+
+    pub fn at(path: PathBuf) -> Result<Self, String> {
+        let values = if path.exists() {
+            let data = std::fs::read_to_string(&path)
+                .map_err(|e| format!("failed to read variables: {}", e))?;
+            toml::from_str(&data).map_err(|e| format!("failed to parse variables: {}", e))?
+        } else {
+            BTreeMap::new()
+        };
+        Ok(Self { path, values })
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        if let Some(dir) = self.path.parent() {
+            create_dir_all(dir).map_err(|e| format!("failed to create dir: {}", e))?;
+        }
+        let data = toml::to_string(&self.values)
+            .map_err(|e| format!("failed to serialize variables: {}", e))?;
+        std::fs::write(&self.path, data).map_err(|e| format!("failed to write variables: {}", e))
+    }
+
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        if !template::is_name(name) {
+            return Err(format!("invalid variable name: {}", name));
+        }
+        self.values.insert(name.to_string(), value.to_string());
+        Ok(())
+    }
+}
+
+/// OS のロケール (ja-JP など)
+/// LC_ALL, LC_MESSAGES, LANG の順に見て、無ければ ja-JP
+pub fn locale() -> String {
+    ["LC_ALL", "LC_MESSAGES", "LANG"]
+        .iter()
+        .filter_map(|name| std::env::var(name).ok())
+        .map(|value| {
+            value
+                .split(['.', '@'])
+                .next()
+                .unwrap_or_default()
+                .to_string()
+        })
+        .find(|value| !value.is_empty() && value != "C" && value != "POSIX")
+        .map(|value| value.replace('_', "-"))
+        .unwrap_or("ja-JP".to_string())
+}
+
+/// 組み込みの変数
+/// assistant, user は利用者の定義で上書きできる
+pub fn builtins(provider: Option<Provider>, model: &str) -> HashMap<String, String> {
+    let now = chrono::Local::now();
+    let mut vars = HashMap::from([
+        ("assistant".to_string(), "あなた".to_string()),
+        ("user".to_string(), "ユーザー".to_string()),
+        ("date".to_string(), now.format("%Y-%m-%d").to_string()),
+        ("time".to_string(), now.format("%H:%M").to_string()),
+        (
+            "datetime".to_string(),
+            now.format("%Y-%m-%d %H:%M").to_string(),
+        ),
+        ("locale".to_string(), locale()),
+        ("os".to_string(), std::env::consts::OS.to_string()),
+    ]);
+    if let Some(provider) = provider {
+        vars.insert("provider".to_string(), provider.name().to_string());
+    }
+    if !model.is_empty() {
+        vars.insert("model".to_string(), model.to_string());
+    }
+    vars
+}
+
+/// 送信時に使う変数。組み込みの変数に利用者の定義を重ねる
+/// 定義ファイルが読めない場合は組み込みの変数だけを使う
+pub fn context(provider: Option<Provider>, model: &str) -> HashMap<String, String> {
+    let mut vars = builtins(provider, model);
+    match Variables::open() {
+        Ok(user) => vars.extend(user.values),
+        Err(e) => log::warn!("{}", e),
+    }
+    vars
+}
+
+/// provider, model の変数で展開する
+pub fn render(text: &str, provider: Provider, model: &str) -> Rendered {
+    template::render(text, &context(Some(provider), model))
+}
+
+/// システムプロンプトを provider, model の変数で展開する
+/// 値のない変数があれば送信前にエラーにする
+pub fn render_system(text: &str, provider: Provider, model: &str) -> Result<String, String> {
+    let rendered = render(text, provider, model);
+    check(&[&rendered])?;
+    Ok(rendered.text)
+}
+
+/// 展開できなかった変数があればエラーにする
+pub fn check(rendered: &[&Rendered]) -> Result<(), String> {
+    let mut names: Vec<&str> = Vec::new();
+    for name in rendered.iter().flat_map(|r| r.unresolved.iter()) {
+        if !names.contains(&name.as_str()) {
+            names.push(name);
+        }
+    }
+    if names.is_empty() {
+        return Ok(());
+    }
+    Err(format!(
+        "unresolved template variables in the system prompt: {} (define them with set_variable or write {{name|default}})",
+        names
+            .iter()
+            .map(|n| format!("{{{}}}", n))
+            .collect::<Vec<String>>()
+            .join(", ")
+    ))
+}
+
+#[tauri::command]
+pub fn list_variables() -> Result<BTreeMap<String, String>, String> {
+    Ok(Variables::open()?.values)
+}
+
+#[tauri::command]
+pub fn set_variable(name: &str, value: &str) -> Result<String, String> {
+    let mut variables = Variables::open()?;
+    variables.set(name, value)?;
+    variables.save()?;
+    Ok("success".to_string())
+}
+
+#[tauri::command]
+pub fn delete_variable(name: &str) -> Result<String, String> {
+    let mut variables = Variables::open()?;
+    variables
+        .values
+        .remove(name)
+        .ok_or(format!("variable not found: {}", name))?;
+    variables.save()?;
+    Ok("success".to_string())
+}
+
+/// 送信前の確認用。展開後の本文と、値のない変数を返す
+#[tauri::command]
+pub fn preview_template(
+    text: &str,
+    provider: Option<Provider>,
+    model: Option<String>,
+) -> Result<Rendered, String> {
+    let vars = context(provider, model.as_deref().unwrap_or_default());
+    Ok(template::render(text, &vars))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtins_and_user_variables() {
+        let path = std::env::temp_dir().join(format!(
+            "talkwithrust-variables-{}.toml",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+
+        let mut variables = Variables::at(path.clone()).unwrap();
+        variables.set("user", "太郎").unwrap();
+        variables.set("project", "talk with rust").unwrap();
+        assert!(variables.set("bad name", "x").is_err());
+        variables.save().unwrap();
+
+        let mut vars = builtins(Some(Provider::Gemini), "gemini-2.0-flash");
+        vars.extend(Variables::at(path.clone()).unwrap().values);
+        let rendered = template::render("{user}/{assistant}/{provider}/{model}/{project}", &vars);
+        assert_eq!(
+            rendered.text,
+            "太郎/あなた/gemini/gemini-2.0-flash/talk with rust"
+        );
+        assert!(template::render("{date}", &vars).text.starts_with("20"));
+
+        let vars = builtins(None, "");
+        let rendered = template::render("{model}", &vars);
+        let e = check(&[&rendered, &rendered]).unwrap_err();
+        assert!(e.contains("variables in the system prompt: {model} ("));
+        assert!(check(&[&template::render("{user}", &builtins(None, ""))]).is_ok());
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,94 @@
+use crate::sub::highlight;
+use layout::backends::svg::SVGWriter;
+use layout::gv::{DotParser, GraphBuilder};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+
+/// 同じページに複数の図があっても id が重ならないようにする
+static COUNTER: AtomicUsize = AtomicUsize::new(0);
+static PREFIX: OnceLock<String> = OnceLock::new();
+
+/// アプリが描いた図の id の接頭辞。起動ごとの乱数を含むため、回答に書かれた SVG からは当てられない
+/// sanitize はこれで始まる id と参照だけを残す
+pub fn id_prefix() -> &'static str {
+    PREFIX.get_or_init(|| {
+        let mut bytes = [0u8; 6];
+        let _ = SystemRandom::new().fill(&mut bytes);
+        let hex = bytes
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+        format!("dot-{}-", hex)
+    })
+}
+
+/// ```dot のコードブロックを SVG に変換する
+/// mermaid など Rust で描画できない図や、描画に失敗した図はコードブロックのまま残す
+pub fn render_diagrams(html: &str) -> String {
+    highlight::replace_code_blocks(html, |lang, code| match lang {
+        "dot" | "graphviz" => dot_to_svg(code).ok(),
+        _ => None,
+    })
+}
+
+/// dot 言語の図を SVG にする
+pub fn dot_to_svg(src: &str) -> Result<String, String> {
+    let graph = DotParser::new(src)
+        .process()
+        .map_err(|e| format!("dot parse error: {}", e))?;
+
+    // layout-rs は不正な図で panic することがあるので、ここで止める
+    let svg = std::panic::catch_unwind(|| {
+        let mut builder = GraphBuilder::new();
+        builder.visit_graph(&graph);
+        let mut visual = builder.get();
+        let mut writer = SVGWriter::new();
+        visual.do_it(false, false, false, &mut writer);
+        writer.finalize()
+    })
+    .map_err(|_| "failed to layout dot graph".to_string())?;
+
+    let prefix = format!(
+        "{}{}-",
+        id_prefix(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    );
+    let svg = match svg.find("<svg") {
+        Some(start) => &svg[start..],
+        None => return Err("layout-rs returned no svg".to_string()),
+    };
+    Ok(prefix_ids(svg, &prefix))
+}
+
+/// id と、それを参照する url(#...) と href="#..." に接頭辞をつける
+fn prefix_ids(svg: &str, prefix: &str) -> String {
+    svg.replace("id=\"", &format!("id=\"{}", prefix))
+        .replace("url(#", &format!("url(#{}", prefix))
+        .replace("href=\"#", &format!("href=\"#{}", prefix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_dot_to_svg() {
+        let html = "<pre><code class=\"language-dot\">digraph G { a -&gt; b; }\n</code></pre>";
+        let out = render_diagrams(html);
+        assert!(out.starts_with("<svg"));
+        assert!(!out.contains("<?xml"));
+        assert!(!out.contains("id=\"endarrow\""));
+        assert!(!out.contains("url(#endarrow)"));
+        assert!(out.contains(&format!("url(#{}", id_prefix())));
+    }
+
+    #[test]
+    fn test_mermaid_and_broken_dot_stay_code_blocks() {
+        let mermaid = "<pre><code class=\"language-mermaid\">graph TD; A--&gt;B;\n</code></pre>";
+        assert_eq!(render_diagrams(mermaid), mermaid);
+
+        let broken = "<pre><code class=\"language-dot\">digraph {\n</code></pre>";
+        assert_eq!(render_diagrams(broken), broken);
+    }
+}
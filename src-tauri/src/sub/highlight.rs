@@ -0,0 +1,199 @@
+use std::sync::LazyLock;
+use syntect::highlighting::ThemeSet;
+use syntect::html::{css_for_theme_with_class_style, ClassStyle, ClassedHTMLGenerator};
+use syntect::parsing::SyntaxSet;
+use syntect::util::LinesWithEndings;
+
+/// 生成するクラス名の接頭辞。サニタイズではこの接頭辞のクラスだけを残す
+pub const CLASS_PREFIX: &str = "hl-";
+const CLASS_STYLE: ClassStyle = ClassStyle::SpacedPrefixed {
+    prefix: CLASS_PREFIX,
+};
+
+static SYNTAXES: LazyLock<SyntaxSet> = LazyLock::new(SyntaxSet::load_defaults_newlines);
+static THEMES: LazyLock<ThemeSet> = LazyLock::new(ThemeSet::load_defaults);
+
+/// コードブロックの色付けの設定
+/// 環境変数 HIGHLIGHT_THEME_LIGHT, HIGHLIGHT_THEME_DARK, HIGHLIGHT_LINE_NUMBERS で変更できる
+#[derive(Debug, Clone, PartialEq)]
+pub struct HighlightOptions {
+    pub light_theme: String,
+    pub dark_theme: String,
+    pub line_numbers: bool,
+}
+
+impl Default for HighlightOptions {
+    fn default() -> Self {
+        let env = |name: &str, default: &str| {
+            std::env::var(name)
+                .ok()
+                .filter(|v| !v.is_empty())
+                .unwrap_or(default.to_string())
+        };
+        Self {
+            light_theme: env("HIGHLIGHT_THEME_LIGHT", "InspiredGitHub"),
+            dark_theme: env("HIGHLIGHT_THEME_DARK", "base16-ocean.dark"),
+            line_numbers: matches!(
+                env("HIGHLIGHT_LINE_NUMBERS", "").as_str(),
+                "1" | "true" | "on"
+            ),
+        }
+    }
+}
+
+/// 使用できるテーマ名
+pub fn themes() -> Vec<String> {
+    THEMES.themes.keys().cloned().collect()
+}
+
+/// 色付け用のCSS
+/// ダークテーマは prefers-color-scheme: dark の時だけ適用する
+pub fn css(options: &HighlightOptions) -> Result<String, String> {
+    let theme_css = |name: &str| {
+        let theme = THEMES.themes.get(name).ok_or(format!(
+            "unknown theme: {}, available: {}",
+            name,
+            themes().join(", ")
+        ))?;
+        css_for_theme_with_class_style(theme, CLASS_STYLE)
+            .map_err(|e| format!("failed to create css: {}", e))
+    };
+
+    Ok(format!(
+        "{}\n@media (prefers-color-scheme: dark) {{\n{}\n}}\n.{p}gutter {{ float: left; margin-right: 1em; text-align: right; opacity: 0.5; user-select: none; }}\n",
+        theme_css(&options.light_theme)?,
+        theme_css(&options.dark_theme)?,
+        p = CLASS_PREFIX,
+    ))
+}
+
+/// markdown が出力した <pre><code class="language-x"> を色付けする
+/// 言語が不明なブロックはそのまま残す
+pub fn highlight_code_blocks(html: &str, options: &HighlightOptions) -> String {
+    replace_code_blocks(html, |lang, code| highlight(lang, code, options))
+}
+
+/// <pre><code class="language-x"> のブロックを f(言語, エスケープを戻したコード) で置き換える
+/// f が None を返したブロックはそのまま残す
+pub fn replace_code_blocks<F>(html: &str, f: F) -> String
+where
+    F: Fn(&str, &str) -> Option<String>,
+{
+    const OPEN: &str = "<pre><code";
+    const CLOSE: &str = "</code></pre>";
+
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find(OPEN) {
+        out.push_str(&rest[..start]);
+        let block = &rest[start..];
+
+        let Some(tag_end) = block[OPEN.len()..].find('>').map(|i| i + OPEN.len()) else {
+            break;
+        };
+        let Some(end) = block.find(CLOSE) else {
+            break;
+        };
+        let tag = &block[..tag_end];
+        let code = &block[tag_end + 1..end];
+
+        match language(tag).and_then(|lang| f(lang, &unescape(code))) {
+            Some(highlighted) => out.push_str(&highlighted),
+            None => out.push_str(&block[..end + CLOSE.len()]),
+        }
+        rest = &block[end + CLOSE.len()..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// class="language-rust" から rust を取り出す
+pub fn language(tag: &str) -> Option<&str> {
+    let class = tag.split("class=\"").nth(1)?.split('"').next()?;
+    class
+        .split_whitespace()
+        .find_map(|c| c.strip_prefix("language-"))
+}
+
+pub fn unescape(code: &str) -> String {
+    code.replace("&quot;", "\"")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+/// 1ブロックを色付けする。対応する構文がなければ None
+pub fn highlight(lang: &str, code: &str, options: &HighlightOptions) -> Option<String> {
+    let syntax = SYNTAXES
+        .find_syntax_by_token(lang)
+        .or_else(|| SYNTAXES.find_syntax_by_extension(lang))?;
+
+    let mut generator = ClassedHTMLGenerator::new_with_class_style(syntax, &SYNTAXES, CLASS_STYLE);
+    for line in LinesWithEndings::from(code) {
+        generator
+            .parse_html_for_line_which_includes_newline(line)
+            .ok()?;
+    }
+    let body = generator.finalize();
+
+    let gutter = if options.line_numbers {
+        let lines = LinesWithEndings::from(code).count();
+        let numbers = (1..=lines)
+            .map(|n| n.to_string())
+            .collect::<Vec<String>>()
+            .join("\n");
+        format!("<span class=\"{}gutter\">{}</span>", CLASS_PREFIX, numbers)
+    } else {
+        String::new()
+    };
+
+    Some(format!(
+        "<pre class=\"{p}code\">{}<code class=\"{p}{}\">{}</code></pre>",
+        gutter,
+        lang,
+        body,
+        p = CLASS_PREFIX
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(line_numbers: bool) -> HighlightOptions {
+        HighlightOptions {
+            light_theme: "InspiredGitHub".to_string(),
+            dark_theme: "base16-ocean.dark".to_string(),
+            line_numbers,
+        }
+    }
+
+    #[test]
+    fn test_highlight_code_blocks_uses_prefixed_classes() {
+        let html = "<p>a</p>\n<pre><code class=\"language-rust\">fn main() { let s = &quot;&lt;b&gt;&quot;; }\n</code></pre>\n<pre><code class=\"language-nosuchlang\">x\n</code></pre>";
+        let out = highlight_code_blocks(html, &options(false));
+        assert!(out.starts_with("<p>a</p>\n<pre class=\"hl-code\">"));
+        assert!(out.contains("<span class=\"hl-source hl-rust\">"));
+        assert!(out.contains("hl-keyword"));
+        // 文字列はエスケープし直される
+        assert!(out.contains("&lt;b&gt;"));
+        assert!(!out.contains("<b>"));
+        // 不明な言語はそのまま
+        assert!(out.contains("<pre><code class=\"language-nosuchlang\">x\n</code></pre>"));
+        assert!(!out.contains("hl-gutter"));
+    }
+
+    #[test]
+    fn test_line_numbers_and_css() {
+        let out = highlight("py", "a = 1\nb = 2\n", &options(true)).unwrap();
+        assert!(out.contains("<span class=\"hl-gutter\">1\n2</span>"));
+
+        let css = css(&options(true)).unwrap();
+        assert!(css.contains(".hl-code"));
+        assert!(css.contains("prefers-color-scheme: dark"));
+
+        let mut unknown = options(false);
+        unknown.dark_theme = "nope".to_string();
+        assert!(super::css(&unknown).is_err());
+    }
+}
@@ -0,0 +1,62 @@
+use crate::sub::highlight;
+use latex2mathml::{latex_to_mathml, DisplayStyle};
+
+const INLINE_OPEN: &str = "<code class=\"language-math math-inline\">";
+const INLINE_CLOSE: &str = "</code>";
+
+/// markdown が出力した数式 ($...$, $$...$$) を MathML に変換する
+/// 変換できない数式は TeX のまま残す
+pub fn render_math(html: &str) -> String {
+    let html = highlight::replace_code_blocks(html, |lang, code| {
+        if lang != "math" {
+            return None;
+        }
+        latex_to_mathml(code.trim(), DisplayStyle::Block).ok()
+    });
+    render_inline(&html)
+}
+
+/// <code class="language-math math-inline"> を置き換える
+fn render_inline(html: &str) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find(INLINE_OPEN) {
+        out.push_str(&rest[..start]);
+        let block = &rest[start..];
+        let Some(end) = block.find(INLINE_CLOSE) else {
+            break;
+        };
+        let code = highlight::unescape(&block[INLINE_OPEN.len()..end]);
+
+        match latex_to_mathml(code.trim(), DisplayStyle::Inline) {
+            Ok(mathml) => out.push_str(&mathml),
+            Err(_) => out.push_str(&block[..end + INLINE_CLOSE.len()]),
+        }
+        rest = &block[end + INLINE_CLOSE.len()..];
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_math_inline_and_block() {
+        let html = "<p>a <code class=\"language-math math-inline\">x^2</code> b</p>\n<pre><code class=\"language-math math-display\">E = mc^2\n</code></pre>\n<pre><code class=\"language-rust\">x\n</code></pre>";
+        let out = render_math(html);
+        assert!(out.contains("<math"));
+        assert!(out.contains("display=\"inline\""));
+        assert!(out.contains("display=\"block\""));
+        assert!(!out.contains("math-inline"));
+        // 数式以外のコードブロックはそのまま
+        assert!(out.contains("<pre><code class=\"language-rust\">x\n</code></pre>"));
+    }
+
+    #[test]
+    fn test_invalid_math_is_kept() {
+        let html = "<code class=\"language-math math-inline\">\\frac{1</code>";
+        assert_eq!(render_math(html), html);
+    }
+}
@@ -1,2 +1,7 @@
+pub mod diagram;
+pub mod highlight;
+pub mod math;
 pub mod prompts;
+pub mod template;
+pub mod tokenize;
 pub mod voice;
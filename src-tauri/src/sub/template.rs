@@ -0,0 +1,99 @@
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// 展開した結果
+/// unresolved は値のなかった変数名。本文には {name} のまま残す
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Rendered {
+    pub text: String,
+    pub unresolved: Vec<String>,
+}
+
+/// {name} と {name|既定値} を展開する
+/// 変数名にならない { } (コードや JSON など) はそのまま残す
+pub fn render(template: &str, vars: &HashMap<String, String>) -> Rendered {
+    let mut text = String::with_capacity(template.len());
+    let mut unresolved: Vec<String> = Vec::new();
+
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        text.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+
+        let Some((name, default, len)) = placeholder(after) else {
+            text.push('{');
+            rest = after;
+            continue;
+        };
+        match vars.get(name).map(|v| v.as_str()).or(default) {
+            Some(value) => text.push_str(value),
+            None => {
+                text.push_str(&rest[start..start + 1 + len]);
+                if !unresolved.iter().any(|u| u == name) {
+                    unresolved.push(name.to_string());
+                }
+            }
+        }
+        rest = &after[len..];
+    }
+    text.push_str(rest);
+
+    Rendered { text, unresolved }
+}
+
+/// 変数名に使える文字
+pub fn is_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_alphabetic() || c == '_' => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_alphanumeric() || c == '_' || c == '.' || c == '-')
+}
+
+/// "{" の直後から "name}" か "name|default}" を読み、(name, default, "}" までの長さ) を返す
+fn placeholder(text: &str) -> Option<(&str, Option<&str>, usize)> {
+    let end = text.find(['}', '{', '\n'])?;
+    if !text[end..].starts_with('}') {
+        return None;
+    }
+    let inner = &text[..end];
+    let (name, default) = match inner.split_once('|') {
+        Some((name, default)) => (name, Some(default)),
+        None => (inner, None),
+    };
+    if !is_name(name) {
+        return None;
+    }
+    Some((name, default, end + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_variables_defaults_and_unresolved() {
+        let vars = HashMap::from([
+            ("assistant".to_string(), "あなた".to_string()),
+            ("user".to_string(), "太郎".to_string()),
+        ]);
+        let rendered = render(
+            "{assistant}は{user}と話す。{project|talk} {missing} {missing}",
+            &vars,
+        );
+        assert_eq!(
+            rendered.text,
+            "あなたは太郎と話す。talk {missing} {missing}"
+        );
+        assert_eq!(rendered.unresolved, vec!["missing"]);
+    }
+
+    #[test]
+    fn test_render_leaves_code_braces() {
+        let code = "fn main() { let x = {1}; }\n{\"a\": 1} {user";
+        let rendered = render(code, &HashMap::new());
+        assert_eq!(rendered.text, code);
+        assert!(rendered.unresolved.is_empty());
+    }
+}
@@ -0,0 +1,87 @@
+/// 検索用にテキストを語へ分割する
+/// 英数字は小文字化した単語、ひらがな・カタカナ・漢字の連続は2文字ずつのバイグラムにする
+/// 形態素解析を使わずに日本語の部分一致を拾うための簡易な分割
+pub fn tokenize(text: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut word = String::new();
+    let mut cjk: Vec<char> = Vec::new();
+
+    for c in text.chars().map(normalize) {
+        if is_cjk(c) {
+            flush_word(&mut word, &mut tokens);
+            cjk.push(c);
+        } else if c.is_alphanumeric() || c == '_' {
+            flush_cjk(&mut cjk, &mut tokens);
+            word.extend(c.to_lowercase());
+        } else {
+            flush_word(&mut word, &mut tokens);
+            flush_cjk(&mut cjk, &mut tokens);
+        }
+    }
+    flush_word(&mut word, &mut tokens);
+    flush_cjk(&mut cjk, &mut tokens);
+
+    tokens
+}
+
+fn flush_word(word: &mut String, tokens: &mut Vec<String>) {
+    if !word.is_empty() {
+        tokens.push(std::mem::take(word));
+    }
+}
+
+fn flush_cjk(run: &mut Vec<char>, tokens: &mut Vec<String>) {
+    match run.len() {
+        0 => {}
+        1 => tokens.push(run[0].to_string()),
+        _ => {
+            for pair in run.windows(2) {
+                tokens.push(pair.iter().collect());
+            }
+        }
+    }
+    run.clear();
+}
+
+/// 全角英数字を半角にする
+fn normalize(c: char) -> char {
+    match c {
+        '\u{FF01}'..='\u{FF5E}' => char::from_u32(c as u32 - 0xFEE0).unwrap_or(c),
+        _ => c,
+    }
+}
+
+fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{3005}'                   // 々
+        | '\u{3040}'..='\u{309F}'    // ひらがな
+        | '\u{30A0}'..='\u{30FF}'    // カタカナ
+        | '\u{3400}'..='\u{4DBF}'    // CJK拡張A
+        | '\u{4E00}'..='\u{9FFF}'    // CJK統合漢字
+        | '\u{F900}'..='\u{FAFF}'    // CJK互換漢字
+        | '\u{FF66}'..='\u{FF9F}'    // 半角カタカナ
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokenize_mixed_text() {
+        let tokens = tokenize("Rustで東京の天気を取得する API");
+        assert_eq!(
+            tokens,
+            vec![
+                "rust", "で東", "東京", "京の", "の天", "天気", "気を", "を取", "取得", "得す",
+                "する", "api"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_fullwidth_and_single_kanji() {
+        assert_eq!(tokenize("ＡＢＣ１２３ 猫"), vec!["abc123", "猫"]);
+        assert!(tokenize("  ,.!? ").is_empty());
+    }
+}
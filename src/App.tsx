@@ -54,6 +54,57 @@ interface ResponseImage {
   url: string;
 }
 
+// claude_request, chatgpt_request, gemini_request の返り値
+interface ChatResponse {
+  html: string;
+  markdown: string;
+  provider: string;
+  model: string;
+  input_tokens: number;
+  output_tokens: number;
+  latency_ms: number;
+  finish_reason: string | null;
+  request_id: string | null;
+  warnings: string[];
+}
+
+// get_settings の戻り値 (必要な項目だけ)
+interface SettingsView {
+  path: string;
+  error: string | null;
+}
+
+// list_models の返り値。性能が不明なものは null
+interface ModelInfo {
+  id: string;
+  provider: string;
+  name: string;
+  context_window: number | null;
+  max_output: number | null;
+  vision: boolean | null;
+  tools: boolean | null;
+}
+
+// モデル選択の表示: 名前 (コンテキスト長, 画像, ツール)
+const modelLabel = (m: ModelInfo) => {
+  const tags = [
+    m.context_window ? `${Math.round(m.context_window / 1000)}k` : null,
+    m.vision ? "vision" : null,
+    m.tools ? "tools" : null,
+  ].filter((t) => t !== null);
+  return tags.length > 0 ? `${m.name} (${tags.join(", ")})` : m.name;
+};
+
+const PROVIDERS = ["claude", "chatgpt", "gemini"];
+
+// 回答の下に表示するモデル名、トークン数、経過時間と注意
+const responseFooter = (res: ChatResponse) => {
+  const escape = (s: string) => s.replace(/&/g, "&amp;").replace(/</g, "&lt;").replace(/>/g, "&gt;");
+  const info = `Model: ${escape(res.model)}, Tokens: ${res.input_tokens} + ${res.output_tokens}, Elaps: ${(res.latency_ms / 1000).toFixed(1)}s`;
+  const warnings = res.warnings.map((w) => `<p>⚠ ${escape(w)}</p>`).join("");
+  return `<p>${info}</p>${warnings}`;
+};
+
 export const App = () => {
   const [messageApi, contextHolder] = message.useMessage();
   const [form] = Form.useForm();
@@ -99,16 +150,27 @@ export const App = () => {
   const [model, setModel] = useState<number>(0);
   // set gemini
   const [AI, setAI] = useState<number>(2);
+  // 空なら高性能/経済モードのモデルを使う
+  const [models, setModels] = useState<ModelInfo[]>([]);
+  const [selectedModel, setSelectedModel] = useState<string>("");
   const [status, setStatus] = useState(StatusModelHigh);
 
   const inputRef = useRef<HTMLInputElement>(null);
 
-  // 起動時に、環境変数: CHATGPTTOKEN、ANTHROPIC_API_KEYどちらもなければ、setResultにエラーメッセージを表示する
+  // 起動時に、どのAIのキーもなければ、setResultにエラーメッセージを表示する
   const init_check = async () => {
     const isEnvAvailable = await invoke("is_there_env");
     if (isEnvAvailable !== true) {
-      setResult(`[ALERT]ご利用できません: 各AIサービスを利用するための環境変数: CHATGPTTOKENまたは ANTHROPIC_API_KEYを設定してください。`);
+      setResult(`[ALERT]ご利用できません: 各AIサービスを利用するための環境変数: ANTHROPIC_API_KEY、CHATGPTTOKEN、GOOGLE_GEMINI_API_KEYのいずれかを設定してください。`);
     }
+    // 設定ファイルを読めなければ、既定値で動いていることを知らせる
+    invoke<SettingsView>("get_settings")
+      .then((view) => {
+        if (view.error) {
+          setResult((prev) => `${prev}[ALERT]設定ファイルを読み込めないため既定値を使います: ${view.error} (${view.path})`);
+        }
+      })
+      .catch((err: any) => console.error(`get_settings > ${err}`));
   };
 
 
@@ -117,6 +179,17 @@ export const App = () => {
     init_check();
   }, []);
 
+  // AIを切り替えたら、そのAIのモデル一覧を取得する
+  useEffect(() => {
+    setSelectedModel("");
+    invoke<ModelInfo[]>("list_models", { provider: PROVIDERS[AI] })
+      .then((list) => setModels(list))
+      .catch((err: any) => {
+        console.error(`list_models > ${err}`);
+        setModels([]);
+      });
+  }, [AI]);
+
   // useEffect 変数監視セクション
   useEffect(() => { // Resultが更新され、Queryが刷新されたら、入力フォームにフォーカス
     // 入力フォームにフォーカス
@@ -270,11 +343,11 @@ export const App = () => {
     console.log(`invoke: ${to_invoke}`);
 
 
-    invoke(to_invoke, { b: model, msg: request_message, src: src })
-      .then((res: any) => { // Add type annotation to 'res'
+    invoke<ChatResponse>(to_invoke, { b: model, model: selectedModel || null, msg: request_message, src: src })
+      .then((res) => {
         console.debug(res);
 
-        setResult(`${res}`);
+        setResult(`${res.html}${responseFooter(res)}`);
       })
       .catch((err: any) => {
         console.error(`gemini_request > ${err}`);
@@ -506,6 +579,19 @@ export const App = () => {
             form.setFieldValue("msg", value);
           }}
         />
+        {/* モデル選択。未選択なら高性能/経済モードに従う */}
+        <Select
+          value={selectedModel}
+          style={{ width: 280, flexShrink: 0 }}
+          title="モデル"
+          showSearch
+          optionFilterProp="label"
+          options={[
+            { label: model === 1 ? "Performance (default)" : "Economical (default)", value: "" },
+            ...models.map((m) => ({ label: modelLabel(m), value: m.id })),
+          ]}
+          onChange={(value) => setSelectedModel(value)}
+        />
         {/* 画像最大サイズ設定 */}
         <Select
           value={imageMaxSize}